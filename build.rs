@@ -1,7 +1,7 @@
 use anyhow::*;
 use fs_extra::copy_items;
 use fs_extra::dir::CopyOptions;
-use std::{env, path};
+use std::env;
 
 fn main() -> Result<()> {
     println!("cargo:rerun-if-changes=models/*");
@@ -10,8 +10,7 @@ fn main() -> Result<()> {
     let mut copy_options = CopyOptions::new();
     copy_options.overwrite = true;
 
-    let mut paths_to_copy = Vec::new();
-    paths_to_copy.push("models/");
+    let paths_to_copy = vec!["models/"];
 
     copy_items(&paths_to_copy, out_dir, &copy_options)?;
 
@@ -0,0 +1,259 @@
+//! Periodic snapshots of the session as a project, so a crash costs at most one interval of sculpting.
+//! On native the snapshot is written by a background thread, to a temporary file that is then renamed
+//! over the previous one, so a crash mid-write never leaves a broken recovery file behind.
+//! The web has no threads or files, so there it goes to local storage right away.
+//! Saving explicitly or closing the app cleanly discards the snapshot, so only a crash leaves one behind.
+
+use crate::project::{self, Project};
+
+// Seconds between snapshots while the model keeps changing
+pub const INTERVAL: f64 = 60.0;
+
+// What the background thread is asked to do, only the latest request matters
+#[cfg(not(target_arch = "wasm32"))]
+enum Job {
+    Write(Project),
+    Discard,
+}
+
+pub struct Autosave {
+    interval: f64,
+    last_save: f64,
+    // Something was sculpted since the last snapshot
+    changed: bool,
+    #[cfg(not(target_arch = "wasm32"))]
+    worker: Option<(std::sync::mpsc::Sender<Job>, std::thread::JoinHandle<()>)>,
+}
+
+impl Autosave {
+    pub fn new(interval: f64) -> Self {
+        Self {
+            interval,
+            last_save: now(),
+            changed: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            worker: spawn_worker(),
+        }
+    }
+
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    /// Whether there are changes that have waited out the interval.
+    pub fn is_due(&self) -> bool {
+        self.changed && now() - self.last_save >= self.interval
+    }
+
+    pub fn save(&mut self, project: Project) {
+        self.changed = false;
+        self.last_save = now();
+
+        // Comes back when there is no thread to write it
+        #[cfg(not(target_arch = "wasm32"))]
+        let Some(Job::Write(project)) = self.send(Job::Write(project)) else {
+            return;
+        };
+
+        if let Err(e) = write_recovery(&project) {
+            log::warn!("Autosave failed: {:#}", e);
+        }
+    }
+
+    /// Removes the snapshot, for when the session was saved or closed and there is nothing to recover.
+    pub fn discard(&mut self) {
+        self.changed = false;
+        self.last_save = now();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.send(Job::Discard).is_none() {
+            return;
+        }
+
+        if let Err(e) = remove_recovery() {
+            log::warn!("Failed to remove the autosave: {:#}", e);
+        }
+    }
+
+    // Hands the job to the thread, or back to the caller when there's no thread to do it
+    #[cfg(not(target_arch = "wasm32"))]
+    fn send(&mut self, job: Job) -> Option<Job> {
+        let (sender, _) = self.worker.as_ref()?;
+        match sender.send(job) {
+            Ok(()) => None,
+            // The thread is gone, so jobs are done here from now on
+            Err(std::sync::mpsc::SendError(job)) => {
+                self.worker = None;
+                Some(job)
+            }
+        }
+    }
+}
+
+// Lets a snapshot still being written finish before the app goes away
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.worker.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_worker() -> Option<(std::sync::mpsc::Sender<Job>, std::thread::JoinHandle<()>)> {
+    use anyhow::Context;
+
+    let (sender, receiver) = std::sync::mpsc::channel::<Job>();
+    let handle = std::thread::Builder::new()
+        .name("autosave".to_string())
+        .spawn(move || {
+            while let Ok(mut job) = receiver.recv() {
+                // Only the newest job matters if several piled up while writing
+                while let Ok(newer) = receiver.try_recv() {
+                    job = newer;
+                }
+                let done = match &job {
+                    Job::Write(project) => write_recovery(project).context("Autosave failed"),
+                    Job::Discard => remove_recovery().context("Failed to remove the autosave"),
+                };
+                if let Err(e) = done {
+                    log::warn!("{:#}", e);
+                }
+            }
+        })
+        .map_err(|e| log::warn!("Autosaving on the main thread, no thread for it: {}", e))
+        .ok()?;
+    Some((sender, handle))
+}
+
+fn now() -> f64 {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            js_sys::Date::now() / 1000.0
+        } else {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs_f64())
+                .unwrap_or(0.0)
+        }
+    }
+}
+
+// Kept in the home folder rather than the temp folder, which some systems empty on reboot
+#[cfg(not(target_arch = "wasm32"))]
+pub fn recovery_path() -> std::path::PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    let dir = match home {
+        Some(home) => std::path::PathBuf::from(home).join(".web_sculpt"),
+        None => std::env::temp_dir().join("web_sculpt"),
+    };
+    dir.join(format!("recovery.{}", project::EXTENSION))
+}
+
+#[cfg(target_arch = "wasm32")]
+const STORAGE_KEY: &str = "web_sculpt_recovery";
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> anyhow::Result<web_sys::Storage> {
+    use anyhow::Context;
+    web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .context("local storage is unavailable")
+}
+
+fn write_recovery(project: &Project) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let mut bytes = Vec::new();
+    project::write(&mut bytes, project)?;
+
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            use base64::Engine;
+            // Local storage only holds text
+            let text = base64::engine::general_purpose::STANDARD.encode(&bytes);
+            local_storage()?
+                .set_item(STORAGE_KEY, &text)
+                .map_err(|e| anyhow::anyhow!("{:?}", e))
+                .context("Failed to store the autosave, it may be over the storage quota")
+        } else {
+            use std::io::Write;
+
+            let path = recovery_path();
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+            let temporary = path.with_extension("tmp");
+            let mut file = std::fs::File::create(&temporary)
+                .with_context(|| format!("Failed to create {}", temporary.display()))?;
+            file.write_all(&bytes)
+                .and_then(|()| file.sync_all())
+                .with_context(|| format!("Failed to write {}", temporary.display()))?;
+            std::fs::rename(&temporary, &path)
+                .with_context(|| format!("Failed to move the autosave to {}", path.display()))
+        }
+    }
+}
+
+fn remove_recovery() -> anyhow::Result<()> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            local_storage()?
+                .remove_item(STORAGE_KEY)
+                .map_err(|e| anyhow::anyhow!("{:?}", e))
+        } else {
+            use anyhow::Context;
+
+            let path = recovery_path();
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// Where the last autosave can be found, if there is one.
+pub fn recovery_location() -> Option<String> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            local_storage().ok()?.get_item(STORAGE_KEY).ok().flatten()?;
+            Some(format!("local storage ({})", STORAGE_KEY))
+        } else {
+            let path = recovery_path();
+            path.exists().then(|| path.display().to_string())
+        }
+    }
+}
+
+/// The last autosaved session, None if nothing was ever autosaved.
+pub fn read_recovery() -> anyhow::Result<Option<Project>> {
+    use anyhow::Context;
+
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            use base64::Engine;
+            let text = match local_storage()?.get_item(STORAGE_KEY).ok().flatten() {
+                Some(text) => text,
+                None => return Ok(None),
+            };
+            let bytes = base64::engine::general_purpose::STANDARD.decode(text)
+                .context("Autosave in local storage is damaged")?;
+            let location = STORAGE_KEY.to_string();
+        } else {
+            let path = recovery_path();
+            if !path.exists() {
+                return Ok(None);
+            }
+            let bytes = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            let location = path.display().to_string();
+        }
+    }
+
+    let project = project::read(&bytes).with_context(|| format!("Failed to read the autosave in {}", location))?;
+    Ok(Some(project))
+}
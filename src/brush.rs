@@ -1,13 +1,57 @@
-use cgmath::Point3;
-use wgpu::{include_wgsl, util::DeviceExt, ShaderStages};
-use crate::{camera::{Camera, CameraController, CameraUniform}, instance::{self, InstanceRaw}, model::{DrawModel, Model}, resources, texture, vertex::{ModelVertex, Vertex, INDICES, VERTICES}};
+use cgmath::{InnerSpace, Point3, Vector3};
+use wgpu::{include_wgsl, util::DeviceExt};
+
+use crate::{camera::Camera, falloff::{Falloff, CURVE_SAMPLES}, picking, stroke::StrokeSettings};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum BrushKind {
+    #[default]
+    Draw,
+    Clay,
+    Inflate,
+    Smooth,
+    Flatten,
+    Pinch,
+    Crease,
+    Grab,
+}
+
+impl BrushKind {
+    // In the order the number keys pick them
+    pub const ALL: [BrushKind; 8] = [
+        BrushKind::Draw,
+        BrushKind::Clay,
+        BrushKind::Inflate,
+        BrushKind::Smooth,
+        BrushKind::Flatten,
+        BrushKind::Pinch,
+        BrushKind::Crease,
+        BrushKind::Grab,
+    ];
+}
+
+/// The part of a brush the user tunes, without anything on the GPU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrushSettings {
+    pub kind: BrushKind,
+    pub radius: f32,
+    pub strength: f32,
+    pub falloff: Falloff,
+    pub stroke_settings: StrokeSettings,
+}
 
 pub struct Brush {
+    pub kind: BrushKind,
+    // World space point and surface normal under the cursor
     pub position: cgmath::Point3<f32>,
+    pub normal: cgmath::Vector3<f32>,
+    // In world units
     pub radius: f32,
+    pub strength: f32,
+    pub falloff: Falloff,
+    pub stroke_settings: StrokeSettings,
 
     pub render_pipeline: wgpu::RenderPipeline,
-    pub bind_group_layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
 
     pub buffer: wgpu::Buffer,
@@ -15,11 +59,16 @@ pub struct Brush {
     pub uniform: BrushUniform
 }
 
+// The cursor overlay is drawn in screen space, so position and radius are in pixels here.
+// The falloff is sent pre-sampled so the preview matches the CPU curve exactly.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct BrushUniform {
     pub position: [f32; 3],
-    pub radius: f32
+    pub radius: f32,
+    pub falloff: [[f32; 4]; CURVE_SAMPLES / 4],
+    pub strength: f32,
+    _padding: [f32; 3],
 }
 
 impl Brush {
@@ -112,10 +161,14 @@ impl Brush {
 
         Self {
             render_pipeline,
+            kind: BrushKind::default(),
             position: Point3::new(0.0, 0.0, 0.0),
-            radius: 5.0,
+            normal: Vector3::unit_y(),
+            radius: 0.25,
+            strength: brush_uniform.strength,
+            falloff: Falloff::default(),
+            stroke_settings: StrokeSettings::default(),
 
-            bind_group_layout,
             bind_group,
 
             buffer,
@@ -124,9 +177,9 @@ impl Brush {
         }
     }
 
-    pub fn update_position(&mut self, new_position: Point3<f32>) {
+    pub fn update_position(&mut self, new_position: Point3<f32>, new_normal: Vector3<f32>) {
         self.position = new_position;
-        self.uniform.update_position(new_position);
+        self.normal = new_normal;
     }
 
     pub fn update_radius(&mut self, new_radius: f32) {
@@ -134,16 +187,69 @@ impl Brush {
             return;
         }
         self.radius = new_radius;
-        self.uniform.update_radius(new_radius);
+    }
+
+    pub fn update_strength(&mut self, new_strength: f32) {
+        self.strength = new_strength.clamp(0.0, 1.0);
+        self.uniform.strength = self.strength;
+    }
+
+    pub fn update_falloff(&mut self, new_falloff: Falloff) {
+        self.uniform.update_falloff(&new_falloff);
+        self.falloff = new_falloff;
+    }
+
+    pub fn settings(&self) -> BrushSettings {
+        BrushSettings {
+            kind: self.kind,
+            radius: self.radius,
+            strength: self.strength,
+            falloff: self.falloff.clone(),
+            stroke_settings: self.stroke_settings,
+        }
+    }
+
+    pub fn apply_settings(&mut self, settings: &BrushSettings) {
+        self.kind = settings.kind;
+        self.update_radius(settings.radius);
+        self.update_strength(settings.strength);
+        self.update_falloff(settings.falloff.clone());
+        self.stroke_settings = settings.stroke_settings;
+    }
+
+    // Projects the world space radius at the brush position to get the size of the cursor circle
+    pub fn update_overlay(&mut self, camera: &Camera, cursor: [f32; 2], size: [f32; 2]) {
+        let right = (camera.target - camera.eye).cross(camera.up).normalize();
+        let center = picking::project_to_screen(camera, self.position, size);
+        let edge = picking::project_to_screen(camera, self.position + right * self.radius, size);
+
+        if let (Some(center), Some(edge)) = (center, edge) {
+            let dx = edge[0] - center[0];
+            let dy = edge[1] - center[1];
+            self.uniform.update_radius((dx * dx + dy * dy).sqrt());
+        }
+        self.uniform.update_position(Point3::new(cursor[0], cursor[1], 0.0));
     }
 
 }
 
 impl BrushUniform {
     pub fn new() -> Self {
-        Self {
+        let mut uniform = Self {
             position: [0.0, 0.0, 0.0],
-            radius: 5.0
+            radius: 5.0,
+            falloff: [[0.0; 4]; CURVE_SAMPLES / 4],
+            strength: 0.5,
+            _padding: [0.0; 3],
+        };
+        uniform.update_falloff(&Falloff::default());
+        uniform
+    }
+
+    pub fn update_falloff(&mut self, falloff: &Falloff) {
+        let samples = falloff.sample();
+        for (i, chunk) in samples.chunks(4).enumerate() {
+            self.falloff[i].copy_from_slice(chunk);
         }
     }
 
@@ -154,4 +260,9 @@ impl BrushUniform {
     pub fn update_radius(&mut self, new_radius: f32) {
         self.radius = new_radius;
     }
-}
\ No newline at end of file
+}
+impl Default for BrushUniform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
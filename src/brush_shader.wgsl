@@ -26,19 +26,39 @@ fn vs_main(
 
 struct BrushUniform {
     position: vec3<f32>,
-    radius: f32
+    radius: f32,
+    // 16 samples of the falloff curve from the center to the rim
+    falloff: array<vec4<f32>, 4>,
+    strength: f32,
 };
 @group(0) @binding(0)
 var<uniform> brushUniform: BrushUniform;
 
+fn falloff_sample(i: u32) -> f32 {
+    return brushUniform.falloff[i / 4u][i % 4u];
+}
+
+fn falloff(t: f32) -> f32 {
+    let x = clamp(t, 0.0, 1.0) * 15.0;
+    let i = u32(floor(x));
+    let j = min(i + 1u, 15u);
+    return mix(falloff_sample(i), falloff_sample(j), x - f32(i));
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let frag_pos = in.clip_position.xy;
     let brush_pos = brushUniform.position.xy;
     let distance = length(frag_pos - brush_pos);
-    if (distance <= brushUniform.radius) {
-        return vec4<f32>(0.0, 1.0, 0.0, 1.0);
+    if (distance > brushUniform.radius) {
+        return vec4<f32>(0.0);
     }
-    return vec4<f32>(0.0);
-}
 
+    // Solid outline at the rim, the falloff gradient scaled by strength inside
+    var alpha = 0.4 * falloff(distance / brushUniform.radius) * brushUniform.strength;
+    if (distance >= brushUniform.radius - 1.5) {
+        alpha = 1.0;
+    }
+    // Premultiplied for PREMULTIPLIED_ALPHA_BLENDING
+    return vec4<f32>(0.0, alpha, 0.0, alpha);
+}
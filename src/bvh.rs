@@ -0,0 +1,495 @@
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3};
+
+use crate::{picking::{self, Ray}, sculpt_mesh::SculptMesh};
+
+const MAX_LEAF_FACES: usize = 4;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Self {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn grow(&mut self, p: Point3<f32>) {
+        self.min = Point3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Point3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut result = *self;
+        result.grow(other.min);
+        result.grow(other.max);
+        result
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        Point3::new(
+            (self.min.x + self.max.x) * 0.5,
+            (self.min.y + self.max.y) * 0.5,
+            (self.min.z + self.max.z) * 0.5,
+        )
+    }
+
+    pub fn extent(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    // Bounds of the transformed box, which may be looser than the transformed contents
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        let mut result = Aabb::empty();
+        if self.is_empty() {
+            return result;
+        }
+        for corner in 0..8 {
+            let p = Point3::new(
+                if corner & 1 == 0 { self.min.x } else { self.max.x },
+                if corner & 2 == 0 { self.min.y } else { self.max.y },
+                if corner & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            result.grow(Point3::from_homogeneous(matrix * p.to_homogeneous()));
+        }
+        result
+    }
+
+    // Squared distance from `p` to the box, zero when inside
+    pub fn distance2(&self, p: Point3<f32>) -> f32 {
+        let dx = (self.min.x - p.x).max(0.0).max(p.x - self.max.x);
+        let dy = (self.min.y - p.y).max(0.0).max(p.y - self.max.y);
+        let dz = (self.min.z - p.z).max(0.0).max(p.z - self.max.z);
+        dx * dx + dy * dy + dz * dz
+    }
+
+    // Slab test, returns the entry distance along the ray
+    pub fn intersect_ray(&self, ray: &Ray, max_t: f32) -> Option<f32> {
+        let mut t_min = 0.0f32;
+        let mut t_max = max_t;
+        for axis in 0..3 {
+            let inv = 1.0 / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inv;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Nearest {
+    pub face: usize,
+    pub point: Point3<f32>,
+    pub barycentric: [f32; 3],
+    pub distance: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    bounds: Aabb,
+    parent: Option<usize>,
+    // Children for internal nodes, a range into `Bvh::faces` for leaves
+    left: usize,
+    right: usize,
+    first: usize,
+    count: usize,
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// Bounding volume hierarchy over the triangles of a `SculptMesh`.
+/// The tree only stores face indices, so every query takes the mesh it was built from.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    faces: Vec<usize>,
+    face_leaf: Vec<usize>,
+}
+
+fn face_bounds(mesh: &SculptMesh, face: usize) -> Aabb {
+    let mut bounds = Aabb::empty();
+    for v in mesh.face(face) {
+        bounds.grow(mesh.positions[v]);
+    }
+    bounds
+}
+
+impl Bvh {
+    pub fn build(mesh: &SculptMesh) -> Self {
+        let num_faces = mesh.num_faces();
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(num_faces.max(1) * 2 / MAX_LEAF_FACES + 1),
+            faces: (0..num_faces).collect(),
+            face_leaf: vec![0; num_faces],
+        };
+
+        let centroids = (0..num_faces)
+            .map(|f| face_bounds(mesh, f).center())
+            .collect::<Vec<_>>();
+
+        bvh.nodes.push(Node {
+            bounds: Aabb::empty(),
+            parent: None,
+            left: 0,
+            right: 0,
+            first: 0,
+            count: num_faces,
+        });
+        if num_faces > 0 {
+            bvh.split(mesh, &centroids, 0);
+        }
+        bvh
+    }
+
+    fn split(&mut self, mesh: &SculptMesh, centroids: &[Point3<f32>], node: usize) {
+        let Node { first, count, .. } = self.nodes[node];
+
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &face in &self.faces[first..first + count] {
+            bounds = bounds.union(&face_bounds(mesh, face));
+            centroid_bounds.grow(centroids[face]);
+        }
+        self.nodes[node].bounds = bounds;
+
+        if count <= MAX_LEAF_FACES {
+            for &face in &self.faces[first..first + count] {
+                self.face_leaf[face] = node;
+            }
+            return;
+        }
+
+        let extent = centroid_bounds.extent();
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = count / 2;
+        self.faces[first..first + count].select_nth_unstable_by(mid, |&a, &b| {
+            centroids[a][axis].total_cmp(&centroids[b][axis])
+        });
+
+        let left = self.nodes.len();
+        self.nodes.push(Node { bounds: Aabb::empty(), parent: Some(node), left: 0, right: 0, first, count: mid });
+        let right = self.nodes.len();
+        self.nodes.push(Node { bounds: Aabb::empty(), parent: Some(node), left: 0, right: 0, first: first + mid, count: count - mid });
+
+        self.nodes[node].left = left;
+        self.nodes[node].right = right;
+        self.nodes[node].count = 0;
+
+        self.split(mesh, centroids, left);
+        self.split(mesh, centroids, right);
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    // Closest hit as (face, t, barycentric)
+    pub fn ray_cast(&self, mesh: &SculptMesh, ray: &Ray) -> Option<(usize, f32, [f32; 3])> {
+        let mut closest: Option<(usize, f32, [f32; 3])> = None;
+        let mut stack = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let max_t = closest.map_or(f32::INFINITY, |(_, t, _)| t);
+            if node.bounds.is_empty() || node.bounds.intersect_ray(ray, max_t).is_none() {
+                continue;
+            }
+
+            if !node.is_leaf() {
+                stack.push(node.left);
+                stack.push(node.right);
+                continue;
+            }
+
+            for &face in &self.faces[node.first..node.first + node.count] {
+                let [a, b, c] = mesh.face(face);
+                if let Some((t, u, v)) = picking::intersect_triangle(ray, mesh.positions[a], mesh.positions[b], mesh.positions[c]) {
+                    if closest.is_none_or(|(_, closest_t, _)| t < closest_t) {
+                        closest = Some((face, t, [1.0 - u - v, u, v]));
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    // All vertices belonging to a face that overlaps the sphere and lying inside it, sorted
+    pub fn vertices_in_sphere(&self, mesh: &SculptMesh, center: Point3<f32>, radius: f32) -> Vec<usize> {
+        let radius2 = radius * radius;
+        let mut vertices = Vec::new();
+        let mut stack = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.bounds.is_empty() || node.bounds.distance2(center) > radius2 {
+                continue;
+            }
+
+            if !node.is_leaf() {
+                stack.push(node.left);
+                stack.push(node.right);
+                continue;
+            }
+
+            for &face in &self.faces[node.first..node.first + node.count] {
+                for v in mesh.face(face) {
+                    if (mesh.positions[v] - center).magnitude2() <= radius2 {
+                        vertices.push(v);
+                    }
+                }
+            }
+        }
+
+        vertices.sort_unstable();
+        vertices.dedup();
+        vertices
+    }
+
+    pub fn nearest_point(&self, mesh: &SculptMesh, p: Point3<f32>) -> Option<Nearest> {
+        let mut best: Option<Nearest> = None;
+        let mut best_distance2 = f32::INFINITY;
+        let mut stack = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.bounds.is_empty() || node.bounds.distance2(p) > best_distance2 {
+                continue;
+            }
+
+            if !node.is_leaf() {
+                // Visit the closer child first so the bound tightens sooner
+                let left = self.nodes[node.left].bounds.distance2(p);
+                let right = self.nodes[node.right].bounds.distance2(p);
+                if left < right {
+                    stack.push(node.right);
+                    stack.push(node.left);
+                } else {
+                    stack.push(node.left);
+                    stack.push(node.right);
+                }
+                continue;
+            }
+
+            for &face in &self.faces[node.first..node.first + node.count] {
+                let [a, b, c] = mesh.face(face);
+                let (point, barycentric) = closest_point_on_triangle(p, mesh.positions[a], mesh.positions[b], mesh.positions[c]);
+                let distance2 = (point - p).magnitude2();
+                if distance2 < best_distance2 {
+                    best_distance2 = distance2;
+                    best = Some(Nearest { face, point, barycentric, distance: distance2.sqrt() });
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Recomputes the bounds of the leaves holding `faces` and their ancestors,
+    /// which is enough after a dab moves vertices without changing topology.
+    pub fn refit(&mut self, mesh: &SculptMesh, faces: impl IntoIterator<Item = usize>) {
+        let mut dirty = vec![false; self.nodes.len()];
+        for face in faces {
+            let mut node = Some(self.face_leaf[face]);
+            while let Some(index) = node {
+                if dirty[index] {
+                    break;
+                }
+                dirty[index] = true;
+                node = self.nodes[index].parent;
+            }
+        }
+
+        // Children are always pushed after their parent, so walking backwards visits them first
+        for index in (0..self.nodes.len()).rev() {
+            if !dirty[index] {
+                continue;
+            }
+            let node = self.nodes[index];
+            self.nodes[index].bounds = if node.is_leaf() {
+                self.faces[node.first..node.first + node.count]
+                    .iter()
+                    .fold(Aabb::empty(), |bounds, &face| bounds.union(&face_bounds(mesh, face)))
+            } else {
+                self.nodes[node.left].bounds.union(&self.nodes[node.right].bounds)
+            };
+        }
+    }
+
+    pub fn refit_vertices(&mut self, mesh: &SculptMesh, vertices: &[usize]) {
+        let faces = vertices.iter().flat_map(|&v| mesh.vertex_faces[v].iter().copied());
+        self.refit(mesh, faces.collect::<Vec<_>>());
+    }
+}
+
+// From Real-Time Collision Detection (Ericson), 5.1.5
+pub fn closest_point_on_triangle(p: Point3<f32>, a: Point3<f32>, b: Point3<f32>, c: Point3<f32>) -> (Point3<f32>, [f32; 3]) {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, [1.0, 0.0, 0.0]);
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, [0.0, 1.0, 0.0]);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return (a + ab * v, [1.0 - v, v, 0.0]);
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, [0.0, 0.0, 1.0]);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return (a + ac * w, [1.0 - w, 0.0, w]);
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (b + (c - b) * w, [0.0, 1.0 - w, w]);
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    let v = vb * denom;
+    let w = vc * denom;
+    (a + ab * v + ac * w, [1.0 - v - w, v, w])
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::EuclideanSpace;
+
+    use super::*;
+    use crate::resources;
+
+    // Deterministic points spread over a box, so failures reproduce
+    fn points(count: usize, extent: f32) -> Vec<Point3<f32>> {
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f32 / u32::MAX as f32 * 2.0 - 1.0) * extent
+        };
+        (0..count).map(|_| Point3::new(next(), next(), next())).collect()
+    }
+
+    fn brute_ray_cast(mesh: &SculptMesh, ray: &Ray) -> Option<f32> {
+        (0..mesh.num_faces())
+            .filter_map(|face| {
+                let [a, b, c] = mesh.face(face).map(|v| mesh.positions[v]);
+                picking::intersect_triangle(ray, a, b, c).map(|(t, _, _)| t)
+            })
+            .min_by(f32::total_cmp)
+    }
+
+    fn brute_sphere(mesh: &SculptMesh, center: Point3<f32>, radius: f32) -> Vec<usize> {
+        let mut vertices = mesh.indices.iter()
+            .map(|&v| v as usize)
+            .filter(|&v| (mesh.positions[v] - center).magnitude() <= radius)
+            .collect::<Vec<_>>();
+        vertices.sort_unstable();
+        vertices.dedup();
+        vertices
+    }
+
+    fn brute_nearest(mesh: &SculptMesh, p: Point3<f32>) -> f32 {
+        (0..mesh.num_faces())
+            .map(|face| {
+                let [a, b, c] = mesh.face(face).map(|v| mesh.positions[v]);
+                (closest_point_on_triangle(p, a, b, c).0 - p).magnitude()
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn assert_matches_brute_force(bvh: &Bvh, mesh: &SculptMesh) {
+        let targets = points(64, 1.0);
+        for (origin, target) in points(64, 3.0).into_iter().zip(targets) {
+            let ray = Ray { origin, direction: (target - origin).normalize() };
+            let hit = bvh.ray_cast(mesh, &ray).map(|(_, t, _)| t);
+            match (hit, brute_ray_cast(mesh, &ray)) {
+                (Some(t), Some(expected)) => assert!((t - expected).abs() < 1e-4, "ray hit at {} instead of {}", t, expected),
+                (hit, expected) => assert_eq!(hit, expected),
+            }
+        }
+
+        for center in points(32, 1.2) {
+            for radius in [0.1, 0.4, 1.0] {
+                assert_eq!(bvh.vertices_in_sphere(mesh, center, radius), brute_sphere(mesh, center, radius));
+            }
+        }
+
+        for p in points(64, 2.0) {
+            let nearest = bvh.nearest_point(mesh, p).unwrap();
+            assert!((nearest.distance - brute_nearest(mesh, p)).abs() < 1e-5);
+            assert!(((nearest.point - p).magnitude() - nearest.distance).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn queries_match_brute_force() {
+        let mesh = resources::test_mesh("cube.obj");
+        let bvh = Bvh::build(&mesh);
+        assert_matches_brute_force(&bvh, &mesh);
+    }
+
+    #[test]
+    fn queries_match_brute_force_after_refit() {
+        let mut mesh = resources::test_mesh("cube.obj");
+        let mut bvh = Bvh::build(&mesh);
+
+        // Pull a corner of the cube well outside the bounds it was built with
+        let moved = bvh.vertices_in_sphere(&mesh, Point3::new(1.0, 1.0, 1.0), 0.5);
+        assert!(!moved.is_empty());
+        for &v in &moved {
+            let p = mesh.positions[v];
+            mesh.positions[v] = p + p.to_vec() * 0.8;
+        }
+        bvh.refit_vertices(&mesh, &moved);
+
+        assert!(bvh.bounds().max.x > 1.5);
+        assert_matches_brute_force(&bvh, &mesh);
+    }
+}
@@ -1,13 +1,25 @@
-use winit::{event::{ElementState, KeyEvent, WindowEvent}, keyboard::{KeyCode, PhysicalKey}};
+use cgmath::{InnerSpace, Matrix3, Quaternion, Rad, Rotation, Rotation3, Vector3};
+use winit::{event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent}, keyboard::{KeyCode, ModifiersState, PhysicalKey}};
 
+use crate::bvh::Aabb;
+
+// Maps OpenGL's -1..1 depth to wgpu's 0..1. `Matrix4::new` takes columns, so the 0.5 offset goes in the last one,
+// the other way round it leaks depth into w and unprojecting the far plane ends up behind the camera
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
- 
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    Orthographic,
+}
+
 pub struct Camera {
     pub eye: cgmath::Point3<f32>,
     pub target: cgmath::Point3<f32>,
@@ -15,15 +27,109 @@ pub struct Camera {
     pub aspect: f32,
     pub fovy: f32,
     pub znear: f32,
-    pub zfar: f32
+    pub zfar: f32,
+    pub projection: Projection,
 }
 
 impl Camera {
     pub fn build_vp_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+        let proj = match self.projection {
+            Projection::Perspective => cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar),
+            Projection::Orthographic => {
+                let half_height = self.ortho_scale();
+                let half_width = half_height * self.aspect;
+                cgmath::ortho(-half_width, half_width, -half_height, half_height, self.znear, self.zfar)
+            },
+        };
+
+        OPENGL_TO_WGPU_MATRIX * proj * view
+    }
+
+    // Half the height of the orthographic view, chosen so the target plane is framed
+    // exactly like the perspective projection frames it from the current distance
+    pub fn ortho_scale(&self) -> f32 {
+        let distance = (self.eye - self.target).magnitude();
+        distance * (cgmath::Rad::from(cgmath::Deg(self.fovy)).0 * 0.5).tan()
+    }
+
+    /// How much of the surface at `point` one pixel covers, for a viewport `viewport_height` pixels tall.
+    pub fn pixel_size(&self, point: cgmath::Point3<f32>, viewport_height: f32) -> f32 {
+        let height = match self.projection {
+            Projection::Perspective => {
+                let distance = (point - self.eye).magnitude();
+                distance * 2.0 * (cgmath::Rad::from(cgmath::Deg(self.fovy)).0 * 0.5).tan()
+            },
+            Projection::Orthographic => self.ortho_scale() * 2.0,
+        };
+        height / viewport_height.max(1.0)
+    }
+
+    /// Points the camera at the center of `bounds` from the current direction,
+    /// backing off until the bounding sphere fits the vertical field of view.
+    pub fn frame(&mut self, bounds: &Aabb) {
+        if bounds.is_empty() {
+            return;
+        }
+        let center = bounds.center();
+        let radius = (bounds.extent().magnitude() * 0.5).max(MIN_DISTANCE);
+        let half_fov = cgmath::Rad::from(cgmath::Deg(self.fovy)).0 * 0.5;
+        let distance = radius / half_fov.sin();
+
+        let direction = (self.eye - self.target).normalize();
+        self.target = center;
+        self.eye = center + direction * distance;
+    }
+
+    // Recenters the orbit on `point` without changing where the camera looks from
+    pub fn focus(&mut self, point: cgmath::Point3<f32>) {
+        let direction = (self.eye - self.target).normalize();
+        let distance = (self.eye - point).magnitude().max(MIN_DISTANCE);
+        self.target = point;
+        self.eye = point + direction * distance;
+    }
+
+    // Tightest clip planes that still contain `bounds`, keeping enough depth precision to sculpt
+    pub fn fit_clip_planes(&mut self, bounds: &Aabb) {
+        if bounds.is_empty() {
+            return;
+        }
+        let radius = bounds.extent().magnitude() * 0.5;
+        let distance = (self.eye - bounds.center()).magnitude();
+
+        self.zfar = (distance + radius) * 1.1;
+        self.znear = (distance - radius).max(self.zfar * 1e-4);
+    }
 
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+    pub fn toggle_projection(&mut self) {
+        self.projection = match self.projection {
+            Projection::Perspective => Projection::Orthographic,
+            Projection::Orthographic => Projection::Perspective,
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StandardView {
+    Front,
+    Back,
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl StandardView {
+    // Direction from the target to the eye, and the up vector for that view
+    pub fn orientation(&self) -> (Vector3<f32>, Vector3<f32>) {
+        match self {
+            StandardView::Front => (Vector3::unit_z(), Vector3::unit_y()),
+            StandardView::Back => (-Vector3::unit_z(), Vector3::unit_y()),
+            StandardView::Right => (Vector3::unit_x(), Vector3::unit_y()),
+            StandardView::Left => (-Vector3::unit_x(), Vector3::unit_y()),
+            StandardView::Top => (Vector3::unit_y(), -Vector3::unit_z()),
+            StandardView::Bottom => (-Vector3::unit_y(), Vector3::unit_z()),
+        }
     }
 }
 
@@ -31,6 +137,8 @@ impl Camera {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
+    view_position: [f32; 4],
+    view: [[f32; 4]; 4],
     view_proj: [[f32; 4]; 4],
 }
 
@@ -38,37 +146,190 @@ impl CameraUniform {
     pub fn new() -> Self {
         use cgmath::SquareMatrix;
         Self {
+            view_position: [0.0; 4],
+            view: cgmath::Matrix4::identity().into(),
             view_proj: cgmath::Matrix4::identity().into(),
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera) {
+        self.view_position = camera.eye.to_homogeneous().into();
+        self.view = cgmath::Matrix4::look_at_rh(camera.eye, camera.target, camera.up).into();
         self.view_proj = camera.build_vp_matrix().into();
     }
 }
 
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OrbitMode {
+    // Yaw around world up and pitch clamped short of the poles, the usual sculpting tumble
+    #[default]
+    Turntable,
+    // Free rotation around the view axes, the camera may roll
+    Trackball,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Drag {
+    Orbit,
+    Pan,
+}
+
+// Interpolates the camera's orientation around the target from one view to another
+#[derive(Debug, Copy, Clone)]
+struct Transition {
+    from: Quaternion<f32>,
+    to: Quaternion<f32>,
+    progress: f32,
+}
+
+// Fraction of a view transition done per frame
+const TRANSITION_STEP: f32 = 1.0 / 15.0;
+
+// Keeps turntable pitch just short of straight up or down so look_at never degenerates
+const PITCH_LIMIT: f32 = 89.0 * std::f32::consts::PI / 180.0;
+const MIN_DISTANCE: f32 = 0.01;
+
 pub struct CameraController {
     pub speed: f32,
+    pub mode: OrbitMode,
+    // Radians per pixel dragged
+    pub rotate_sensitivity: f32,
+    // Fraction of the orbit distance per pixel dragged
+    pub pan_sensitivity: f32,
+    // Distance scale per wheel notch
+    pub zoom_sensitivity: f32,
+
     pub left: bool,
     pub right: bool,
     pub forward: bool,
     pub backward: bool,
+
+    modifiers: ModifiersState,
+    drag: Option<Drag>,
+    drag_button: Option<MouseButton>,
+    cursor: Option<[f32; 2]>,
+    rotate: [f32; 2],
+    pan: [f32; 2],
+    zoom: f32,
+    pending_view: Option<StandardView>,
+    pending_projection_toggle: bool,
+    transition: Option<Transition>,
 }
 
 impl CameraController {
     pub fn new(speed: f32) -> Self {
         Self {
             speed,
+            mode: OrbitMode::default(),
+            rotate_sensitivity: 0.01,
+            pan_sensitivity: 0.0015,
+            zoom_sensitivity: 0.1,
             left: false,
             right: false,
             forward: false,
-            backward: false
+            backward: false,
+            modifiers: ModifiersState::empty(),
+            drag: None,
+            drag_button: None,
+            cursor: None,
+            rotate: [0.0, 0.0],
+            pan: [0.0, 0.0],
+            zoom: 0.0,
+            pending_view: None,
+            pending_projection_toggle: false,
+            transition: None,
         }
     }
 
+    pub fn set_view(&mut self, view: StandardView) {
+        self.pending_view = Some(view);
+    }
+
+    pub fn toggle_projection(&mut self) {
+        self.pending_projection_toggle = !self.pending_projection_toggle;
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            OrbitMode::Turntable => OrbitMode::Trackball,
+            OrbitMode::Trackball => OrbitMode::Turntable,
+        };
+    }
+
+    /// Middle or right drag (or Alt + left drag) tumbles, holding Shift pans instead.
+    /// The wheel dollies unless Ctrl is held, which is left for resizing the brush.
+    /// Numpad 1, 3 and 7 snap to front, right and top (Ctrl for the opposite side), 5 toggles orthographic.
     pub fn handle_event(&mut self, event: &WindowEvent) -> bool {
         match event {
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+                false
+            },
+            WindowEvent::MouseInput { state, button, .. } => {
+                let navigates = match button {
+                    MouseButton::Middle | MouseButton::Right => true,
+                    MouseButton::Left => self.modifiers.alt_key(),
+                    _ => false,
+                };
+
+                match state {
+                    ElementState::Pressed if navigates && self.drag.is_none() => {
+                        self.drag = Some(if self.modifiers.shift_key() { Drag::Pan } else { Drag::Orbit });
+                        self.drag_button = Some(*button);
+                        true
+                    },
+                    ElementState::Released if self.drag_button == Some(*button) => {
+                        self.drag = None;
+                        self.drag_button = None;
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                let cursor = [position.x as f32, position.y as f32];
+                let last = self.cursor.replace(cursor);
+
+                match (self.drag, last) {
+                    (Some(drag), Some(last)) => {
+                        let delta = [cursor[0] - last[0], cursor[1] - last[1]];
+                        let accumulated = match drag {
+                            Drag::Orbit => &mut self.rotate,
+                            Drag::Pan => &mut self.pan,
+                        };
+                        accumulated[0] += delta[0];
+                        accumulated[1] += delta[1];
+                        true
+                    },
+                    (Some(_), None) => true,
+                    _ => false,
+                }
+            },
+            WindowEvent::MouseWheel { delta, .. } if !self.modifiers.control_key() => {
+                self.zoom += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 50.0,
+                };
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(keycode @ (KeyCode::Numpad1 | KeyCode::Numpad3 | KeyCode::Numpad7 | KeyCode::Numpad5)),
+                    ..
+                },
+                ..
+            } => {
+                let opposite = self.modifiers.control_key();
+                match keycode {
+                    KeyCode::Numpad1 => self.set_view(if opposite { StandardView::Back } else { StandardView::Front }),
+                    KeyCode::Numpad3 => self.set_view(if opposite { StandardView::Left } else { StandardView::Right }),
+                    KeyCode::Numpad7 => self.set_view(if opposite { StandardView::Bottom } else { StandardView::Top }),
+                    _ => self.toggle_projection(),
+                }
+                true
+            },
             WindowEvent::KeyboardInput {
                 event: KeyEvent {
                     state,
@@ -76,7 +337,7 @@ impl CameraController {
                     ..
                 },
                 ..
-             } => {
+             } if !self.modifiers.control_key() => {
                 let is_pressed = *state == ElementState::Pressed;
 
                 match keycode {
@@ -84,7 +345,8 @@ impl CameraController {
                     KeyCode::ArrowRight | KeyCode::KeyD => self.right = is_pressed,
                     KeyCode::ArrowDown | KeyCode::KeyS => self.backward = is_pressed,
                     KeyCode::ArrowUp | KeyCode::KeyW => self.forward = is_pressed,
-                    _ => {}
+                    KeyCode::KeyT if is_pressed => self.toggle_mode(),
+                    _ => return false
                 }
 
                 true
@@ -92,31 +354,127 @@ impl CameraController {
              _ => false
         }
     }
-    
-    pub fn update_camera(&self, camera: &mut Camera) {
-        use cgmath::InnerSpace;
-        let forward = camera.target - camera.eye;
-        let forward_norm = forward.normalize();
 
-        if self.forward {
-            camera.eye += forward_norm * self.speed;
+    pub fn update_camera(&mut self, camera: &mut Camera) {
+        if std::mem::take(&mut self.pending_projection_toggle) {
+            camera.toggle_projection();
         }
-        if self.backward {
-            camera.eye -= forward_norm * self.speed;
+        if let Some(view) = self.pending_view.take() {
+            let (direction, up) = view.orientation();
+            self.transition = Some(Transition {
+                from: orientation(camera),
+                to: basis_rotation(direction, up),
+                progress: 0.0,
+            });
         }
 
-        let right = forward_norm.cross(camera.up);
+        // Any manual navigation cancels a transition that is still playing
+        if self.rotate != [0.0, 0.0] || self.pan != [0.0, 0.0] {
+            self.transition = None;
+        }
+        if let Some(transition) = &mut self.transition {
+            transition.progress = (transition.progress + TRANSITION_STEP).min(1.0);
+            let t = transition.progress;
+            let eased = t * t * (3.0 - 2.0 * t);
+            apply_orientation(camera, transition.from.slerp(transition.to, eased));
+            if transition.progress >= 1.0 {
+                self.transition = None;
+            }
+        }
 
-        let forward = camera.target - camera.eye;
-        let forward_mag = forward.magnitude();
+        let key_yaw = match (self.left, self.right) {
+            (true, false) => -1.0,
+            (false, true) => 1.0,
+            _ => 0.0,
+        };
+        let key_zoom = match (self.forward, self.backward) {
+            (true, false) => 1.0,
+            (false, true) => -1.0,
+            _ => 0.0,
+        };
 
-        if self.right {
-            camera.eye = camera.target - (forward + right * self.speed).normalize() * forward_mag;
+        let yaw = -self.rotate[0] * self.rotate_sensitivity - key_yaw * self.speed * 0.2;
+        let pitch = -self.rotate[1] * self.rotate_sensitivity;
+        if yaw != 0.0 || pitch != 0.0 {
+            match self.mode {
+                OrbitMode::Turntable => turntable(camera, yaw, pitch),
+                OrbitMode::Trackball => trackball(camera, yaw, pitch),
+            }
         }
-        if self.left {
-            camera.eye = camera.target - (forward - right * self.speed).normalize() * forward_mag;
+
+        if self.pan != [0.0, 0.0] {
+            pan(camera, self.pan[0] * self.pan_sensitivity, self.pan[1] * self.pan_sensitivity);
         }
 
+        let zoom = self.zoom + key_zoom * self.speed;
+        if zoom != 0.0 {
+            dolly(camera, (1.0 - self.zoom_sensitivity).powf(zoom));
+        }
 
+        self.rotate = [0.0, 0.0];
+        self.pan = [0.0, 0.0];
+        self.zoom = 0.0;
     }
-}
\ No newline at end of file
+}
+
+// Rotation taking the camera's local axes (x right, y up, z back towards the eye) into world space
+fn basis_rotation(back: Vector3<f32>, up: Vector3<f32>) -> Quaternion<f32> {
+    let back = back.normalize();
+    let right = up.cross(back).normalize();
+    let up = back.cross(right);
+    Quaternion::from(Matrix3::from_cols(right, up, back))
+}
+
+fn orientation(camera: &Camera) -> Quaternion<f32> {
+    basis_rotation(camera.eye - camera.target, camera.up)
+}
+
+fn apply_orientation(camera: &mut Camera, rotation: Quaternion<f32>) {
+    let distance = (camera.eye - camera.target).magnitude();
+    camera.eye = camera.target + rotation.rotate_vector(Vector3::unit_z()) * distance;
+    camera.up = rotation.rotate_vector(Vector3::unit_y());
+}
+
+fn turntable(camera: &mut Camera, yaw: f32, pitch: f32) {
+    let offset = camera.eye - camera.target;
+    let distance = offset.magnitude();
+    let current_yaw = offset.x.atan2(offset.z);
+    let current_pitch = (offset.y / distance).clamp(-1.0, 1.0).asin();
+
+    let yaw = current_yaw + yaw;
+    let pitch = (current_pitch + pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+
+    camera.eye = camera.target + Vector3::new(
+        pitch.cos() * yaw.sin(),
+        pitch.sin(),
+        pitch.cos() * yaw.cos(),
+    ) * distance;
+    camera.up = Vector3::unit_y();
+}
+
+fn trackball(camera: &mut Camera, yaw: f32, pitch: f32) {
+    let offset = camera.eye - camera.target;
+    let right = offset.cross(camera.up).normalize();
+    let up = right.cross(offset).normalize();
+
+    let rotation = Quaternion::from_axis_angle(up, Rad(yaw)) * Quaternion::from_axis_angle(right, Rad(-pitch));
+    camera.eye = camera.target + rotation.rotate_vector(offset);
+    camera.up = rotation.rotate_vector(up);
+}
+
+fn pan(camera: &mut Camera, dx: f32, dy: f32) {
+    let forward = camera.target - camera.eye;
+    let distance = forward.magnitude();
+    let right = forward.cross(camera.up).normalize();
+    let up = right.cross(forward).normalize();
+
+    let offset = (-right * dx + up * dy) * distance;
+    camera.eye += offset;
+    camera.target += offset;
+}
+
+fn dolly(camera: &mut Camera, scale: f32) {
+    let offset = camera.eye - camera.target;
+    let distance = (offset.magnitude() * scale).max(MIN_DISTANCE);
+    camera.eye = camera.target + offset.normalize() * distance;
+}
@@ -0,0 +1,90 @@
+use anyhow::{bail, Context};
+
+use crate::history::DEFAULT_MEMORY_BUDGET;
+
+pub const USAGE: &str = "usage: web_sculpt [--restore] [--quads] [--undo-memory MB] [path/to/model.obj]\n  --restore         reopen the session autosaved before the app last closed or crashed\n  --quads           keep the quads of OBJ files as the cage for Catmull-Clark subdivision\n  --undo-memory MB  how much memory undo history may use before the oldest edits are dropped";
+
+const MEGABYTE: usize = 1024 * 1024;
+
+// Shipped in models/ and loaded when no model is given
+pub const DEFAULT_MODEL: &str = "cube.obj";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub model: String,
+    // Open the autosaved session instead, falling back to the model when there is none
+    pub restore: bool,
+    // Load OBJ polygons as they are instead of triangulating them
+    pub keep_quads: bool,
+    // Bytes of undo history to keep
+    pub undo_memory: usize,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self { model: DEFAULT_MODEL.to_string(), restore: false, keep_quads: false, undo_memory: DEFAULT_MEMORY_BUDGET }
+    }
+}
+
+impl Args {
+    /// Parses the arguments after the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let mut model = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--restore" {
+                parsed.restore = true;
+                continue;
+            }
+            if arg == "--quads" {
+                parsed.keep_quads = true;
+                continue;
+            }
+            if arg == "--undo-memory" {
+                parsed.undo_memory = parse_megabytes(args.next().as_deref())?;
+                continue;
+            }
+            if arg.starts_with('-') {
+                bail!("unknown option {}\n{}", arg, USAGE);
+            }
+            if model.replace(arg).is_some() {
+                bail!("only one model can be opened\n{}", USAGE);
+            }
+        }
+        if let Some(model) = model {
+            parsed.model = model;
+        }
+        Ok(parsed)
+    }
+}
+
+/// A memory size given in megabytes, like the value of `--undo-memory`.
+pub fn parse_megabytes(value: Option<&str>) -> anyhow::Result<usize> {
+    let value = match value {
+        Some(value) => value,
+        None => bail!("--undo-memory needs a size in megabytes\n{}", USAGE),
+    };
+    let megabytes = value.parse::<usize>()
+        .with_context(|| format!("--undo-memory takes a whole number of megabytes, not {}", value))?;
+    Ok(megabytes.saturating_mul(MEGABYTE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn undo_memory_is_given_in_megabytes() {
+        assert_eq!(parse(&[]).unwrap().undo_memory, DEFAULT_MEMORY_BUDGET);
+        let args = parse(&["--undo-memory", "64", "model.obj"]).unwrap();
+        assert_eq!(args.undo_memory, 64 * MEGABYTE);
+        assert_eq!(args.model, "model.obj");
+        assert!(parse(&["--undo-memory"]).is_err());
+        assert!(parse(&["--undo-memory", "lots"]).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3, Zero};
+
+use crate::{brush::BrushKind, falloff::Falloff, sculpt_mesh::SculptMesh};
+
+// How far a full strength dab moves a vertex, as a fraction of the brush radius
+const DISPLACEMENT_SCALE: f32 = 0.1;
+
+/// A single application of a brush, everything in the mesh's local space.
+#[derive(Debug, Copy, Clone)]
+pub struct Dab<'a> {
+    pub kind: BrushKind,
+    pub center: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub radius: f32,
+    pub strength: f32,
+    pub falloff: &'a Falloff,
+    pub invert: bool,
+    // Only used by grab, how far the cursor moved since the last dab
+    pub grab_delta: Vector3<f32>,
+}
+
+impl Dab<'_> {
+    fn sign(&self) -> f32 {
+        if self.invert { -1.0 } else { 1.0 }
+    }
+
+    pub fn weight(&self, position: Point3<f32>) -> f32 {
+        let t = (position - self.center).magnitude() / self.radius;
+        self.falloff.eval(t) * self.strength
+    }
+}
+
+pub fn apply_dab(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    match dab.kind {
+        BrushKind::Draw => draw(mesh, vertices, dab),
+        BrushKind::Clay => clay(mesh, vertices, dab),
+        BrushKind::Inflate => inflate(mesh, vertices, dab),
+        BrushKind::Smooth => smooth(mesh, vertices, dab),
+        BrushKind::Flatten => flatten(mesh, vertices, dab),
+        BrushKind::Pinch => pinch(mesh, vertices, dab),
+        BrushKind::Crease => crease(mesh, vertices, dab),
+        BrushKind::Grab => grab(mesh, vertices, dab),
+    }
+}
+
+// Weighted average of the normals and positions under the brush, the plane most brushes work against
+pub fn area_plane(mesh: &SculptMesh, vertices: &[usize], dab: &Dab) -> (Point3<f32>, Vector3<f32>) {
+    let mut center = Vector3::zero();
+    let mut normal = Vector3::zero();
+    let mut total = 0.0;
+
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]);
+        center += mesh.positions[v].to_vec() * w;
+        normal += mesh.normals[v] * w;
+        total += w;
+    }
+
+    if total <= 0.0 {
+        return (dab.center, dab.normal);
+    }
+    let normal = if normal.magnitude2() > 1e-12 { normal.normalize() } else { dab.normal };
+    (Point3::from_vec(center / total), normal)
+}
+
+fn displace(mesh: &mut SculptMesh, v: usize, offset: Vector3<f32>) {
+    mesh.positions[v] += offset;
+    mesh.mark_dirty(v);
+}
+
+pub fn draw(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let (_, normal) = area_plane(mesh, vertices, dab);
+    let step = dab.sign() * dab.radius * DISPLACEMENT_SCALE;
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]);
+        displace(mesh, v, normal * (w * step));
+    }
+}
+
+// Raises everything below a plane floating just above the surface up to it
+pub fn clay(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let (center, normal) = area_plane(mesh, vertices, dab);
+    let normal = normal * dab.sign();
+    let plane = center + normal * (dab.radius * DISPLACEMENT_SCALE * dab.strength);
+
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]);
+        let d = (mesh.positions[v] - plane).dot(normal);
+        if d < 0.0 {
+            displace(mesh, v, normal * (-d * w.min(1.0)));
+        }
+    }
+}
+
+pub fn inflate(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let step = dab.sign() * dab.radius * DISPLACEMENT_SCALE;
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]);
+        let normal = mesh.normals[v];
+        if normal.magnitude2() > 1e-12 {
+            displace(mesh, v, normal.normalize() * (w * step));
+        }
+    }
+}
+
+// Laplacian smoothing towards the one-ring average, computed from the positions before the dab
+pub fn smooth(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let offsets = vertices.iter().map(|&v| {
+        let neighbors = &mesh.vertex_neighbors[v];
+        if neighbors.is_empty() {
+            return Vector3::zero();
+        }
+        let sum = neighbors.iter().fold(Vector3::zero(), |sum, &n| sum + mesh.positions[n].to_vec());
+        let average = Point3::from_vec(sum / neighbors.len() as f32);
+        (average - mesh.positions[v]) * dab.weight(mesh.positions[v]).min(1.0)
+    }).collect::<Vec<_>>();
+
+    for (&v, offset) in vertices.iter().zip(offsets) {
+        displace(mesh, v, offset);
+    }
+}
+
+pub fn flatten(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let (center, normal) = area_plane(mesh, vertices, dab);
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]).min(1.0);
+        let d = (mesh.positions[v] - center).dot(normal);
+        displace(mesh, v, normal * (-d * w));
+    }
+}
+
+// Pulls vertices towards the brush center within the surface plane, inverted it pushes them apart
+pub fn pinch(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let (_, normal) = area_plane(mesh, vertices, dab);
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]).min(1.0);
+        let to_center = dab.center - mesh.positions[v];
+        let tangent = to_center - normal * to_center.dot(normal);
+        displace(mesh, v, tangent * (w * dab.sign() * 0.5));
+    }
+}
+
+// A pinch that also cuts into the surface, leaving a sharp groove (or ridge when inverted)
+pub fn crease(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    let (_, normal) = area_plane(mesh, vertices, dab);
+    let step = -dab.sign() * dab.radius * DISPLACEMENT_SCALE;
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]).min(1.0);
+        let to_center = dab.center - mesh.positions[v];
+        let tangent = to_center - normal * to_center.dot(normal);
+        displace(mesh, v, normal * (w * step) + tangent * (w * 0.5));
+    }
+}
+
+pub fn grab(mesh: &mut SculptMesh, vertices: &[usize], dab: &Dab) {
+    for &v in vertices {
+        let w = dab.weight(mesh.positions[v]);
+        displace(mesh, v, dab.grab_delta * w);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f32 = 0.5;
+    const SPACING: f32 = 0.1;
+    const HALF: usize = 10;
+
+    // A flat grid facing +y, from -1 to 1 on x and z
+    fn grid() -> SculptMesh {
+        let side = HALF * 2 + 1;
+        let mut positions = Vec::new();
+        for z in 0..side {
+            for x in 0..side {
+                positions.push(Point3::new((x as f32 - HALF as f32) * SPACING, 0.0, (z as f32 - HALF as f32) * SPACING));
+            }
+        }
+        let mut indices = Vec::new();
+        for z in 0..side - 1 {
+            for x in 0..side - 1 {
+                let i = (z * side + x) as u32;
+                let below = i + side as u32;
+                indices.extend_from_slice(&[i, below, i + 1, i + 1, below, below + 1]);
+            }
+        }
+        let count = positions.len();
+        SculptMesh::new(positions, vec![Vector3::unit_y(); count], vec![[0.0, 0.0]; count], indices)
+    }
+
+    // The vertex `steps` grid cells along +x from the center
+    fn along_x(steps: usize) -> usize {
+        let side = HALF * 2 + 1;
+        HALF * side + HALF + steps
+    }
+
+    fn dab(kind: BrushKind, falloff: &Falloff) -> Dab<'_> {
+        Dab {
+            kind,
+            center: Point3::new(0.0, 0.0, 0.0),
+            normal: Vector3::unit_y(),
+            radius: RADIUS,
+            strength: 1.0,
+            falloff,
+            invert: false,
+            grab_delta: Vector3::new(0.0, 0.0, 0.2),
+        }
+    }
+
+    fn apply(mesh: &mut SculptMesh, dab: &Dab) -> Vec<Vector3<f32>> {
+        let before = mesh.positions.clone();
+        let vertices = (0..mesh.num_vertices()).collect::<Vec<_>>();
+        apply_dab(mesh, &vertices, dab);
+        mesh.positions.iter().zip(before).map(|(after, before)| after - before).collect()
+    }
+
+    const KINDS: [BrushKind; 8] = [
+        BrushKind::Draw,
+        BrushKind::Clay,
+        BrushKind::Inflate,
+        BrushKind::Smooth,
+        BrushKind::Flatten,
+        BrushKind::Pinch,
+        BrushKind::Crease,
+        BrushKind::Grab,
+    ];
+
+    #[test]
+    fn vertices_outside_the_radius_stay_put() {
+        let falloff = Falloff::default();
+        for kind in KINDS {
+            for invert in [false, true] {
+                let mut mesh = grid();
+                // A bump inside the radius, so smooth and flatten have something to do
+                mesh.positions[along_x(1)].y = 0.1;
+                let before = mesh.clone();
+                let moved = apply(&mut mesh, &Dab { invert, ..dab(kind, &falloff) });
+                for (v, offset) in moved.iter().enumerate() {
+                    if (before.positions[v] - Point3::new(0.0, 0.0, 0.0)).magnitude() >= RADIUS {
+                        assert_eq!(*offset, Vector3::zero(), "{:?} moved vertex {} outside the radius", kind, v);
+                    }
+                }
+                assert!(moved.iter().any(|offset| *offset != Vector3::zero()), "{:?} moved nothing", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn draw_follows_the_normal_and_falls_off() {
+        let falloff = Falloff::default();
+        let moved = apply(&mut grid(), &dab(BrushKind::Draw, &falloff));
+        assert!((moved[along_x(0)].y - RADIUS * DISPLACEMENT_SCALE).abs() < 1e-6);
+        for steps in 0..5 {
+            let (inner, outer) = (moved[along_x(steps)], moved[along_x(steps + 1)]);
+            assert!(inner.y > outer.y, "no falloff between {} and {} cells out", steps, steps + 1);
+            assert!(inner.x.abs() < 1e-6 && inner.z.abs() < 1e-6);
+        }
+        // The rim itself gets nothing
+        assert_eq!(moved[along_x(5)], Vector3::zero());
+
+        let inverted = apply(&mut grid(), &Dab { invert: true, ..dab(BrushKind::Draw, &falloff) });
+        assert!((inverted[along_x(0)].y + RADIUS * DISPLACEMENT_SCALE).abs() < 1e-6);
+    }
+
+    #[test]
+    fn clay_raises_the_surface_up_to_a_plane() {
+        let falloff = Falloff::default();
+        let mut mesh = grid();
+        apply(&mut mesh, &dab(BrushKind::Clay, &falloff));
+        let plane = RADIUS * DISPLACEMENT_SCALE;
+        assert!(mesh.positions[along_x(0)].y > 0.0);
+        assert!(mesh.positions.iter().all(|p| p.y <= plane + 1e-6));
+        assert!(mesh.positions[along_x(0)].y > mesh.positions[along_x(3)].y);
+    }
+
+    #[test]
+    fn inflate_moves_along_each_vertex_normal() {
+        let falloff = Falloff::default();
+        let mut mesh = grid();
+        let tilted = Vector3::new(1.0, 1.0, 0.0).normalize();
+        mesh.normals[along_x(2)] = tilted;
+        let moved = apply(&mut mesh, &dab(BrushKind::Inflate, &falloff));
+        assert!(moved[along_x(2)].magnitude() > 0.0);
+        assert!((moved[along_x(2)].normalize() - tilted).magnitude() < 1e-5);
+        assert!(moved[along_x(1)].x.abs() < 1e-6 && moved[along_x(1)].y > 0.0);
+    }
+
+    #[test]
+    fn smooth_pulls_a_spike_towards_its_neighbors() {
+        let falloff = Falloff::default();
+        let mut mesh = grid();
+        mesh.positions[along_x(0)].y = 0.3;
+        apply(&mut mesh, &dab(BrushKind::Smooth, &falloff));
+        let spike = mesh.positions[along_x(0)].y;
+        assert!((0.0..0.3).contains(&spike));
+        // The neighbors are pulled up towards the spike
+        assert!(mesh.positions[along_x(1)].y > 0.0);
+    }
+
+    #[test]
+    fn flatten_evens_out_bumps() {
+        let falloff = Falloff::default();
+        let mut mesh = grid();
+        for (steps, height) in [(0, 0.1), (2, -0.05), (3, 0.08)] {
+            mesh.positions[along_x(steps)].y = height;
+        }
+        let spread = |mesh: &SculptMesh| {
+            let heights = (0..4).map(|steps| mesh.positions[along_x(steps)].y).collect::<Vec<_>>();
+            heights.iter().cloned().fold(f32::MIN, f32::max) - heights.iter().cloned().fold(f32::MAX, f32::min)
+        };
+        let before = spread(&mesh);
+        apply(&mut mesh, &dab(BrushKind::Flatten, &falloff));
+        assert!(spread(&mesh) < before);
+    }
+
+    #[test]
+    fn pinch_and_crease_pull_towards_the_center() {
+        let falloff = Falloff::default();
+        let pinched = apply(&mut grid(), &dab(BrushKind::Pinch, &falloff));
+        assert!(pinched[along_x(2)].x < 0.0);
+        assert!(pinched[along_x(2)].y.abs() < 1e-6);
+        let spread = apply(&mut grid(), &Dab { invert: true, ..dab(BrushKind::Pinch, &falloff) });
+        assert!(spread[along_x(2)].x > 0.0);
+
+        // Crease also cuts in, or raises a ridge when inverted
+        let creased = apply(&mut grid(), &dab(BrushKind::Crease, &falloff));
+        assert!(creased[along_x(2)].x < 0.0 && creased[along_x(2)].y < 0.0);
+        let ridge = apply(&mut grid(), &Dab { invert: true, ..dab(BrushKind::Crease, &falloff) });
+        assert!(ridge[along_x(2)].y > 0.0);
+    }
+
+    #[test]
+    fn grab_drags_by_the_cursor_delta() {
+        let falloff = Falloff::default();
+        let moved = apply(&mut grid(), &dab(BrushKind::Grab, &falloff));
+        assert!((moved[along_x(0)] - Vector3::new(0.0, 0.0, 0.2)).magnitude() < 1e-6);
+        let partial = moved[along_x(3)];
+        assert!((0.0..0.2).contains(&partial.z) && partial.z > 0.0 && partial.x == 0.0 && partial.y == 0.0);
+    }
+}
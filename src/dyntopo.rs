@@ -0,0 +1,635 @@
+use std::collections::{HashMap, HashSet};
+
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3, Zero};
+
+use crate::{history::TopologyRecorder, sculpt_mesh::SculptMesh};
+
+// Edges shorter than this fraction of the detail size are collapsed.
+// Below half, so the two halves of a freshly split edge never qualify.
+const COLLAPSE_RATIO: f32 = 0.4;
+// Each pass halves the long edges, a few bring even a coarse mesh down to the detail size
+const MAX_PASSES: usize = 4;
+// Keeps a tiny detail size from exploding the mesh in a single dab
+const MAX_SPLITS_PER_DAB: usize = 4096;
+
+pub const DEFAULT_BRUSH_DETAIL: f32 = 0.1;
+pub const DEFAULT_SCREEN_DETAIL: f32 = 8.0;
+
+/// What the detail size is measured against.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DetailSize {
+    // Fraction of the brush radius, so detail follows the brush size
+    Brush(f32),
+    // Pixels on screen, so detail follows how far the camera is zoomed in
+    Screen(f32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DyntopoSettings {
+    pub enabled: bool,
+    pub detail: DetailSize,
+}
+
+impl Default for DyntopoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            detail: DetailSize::Brush(DEFAULT_BRUSH_DETAIL),
+        }
+    }
+}
+
+impl DyntopoSettings {
+    /// The longest edge allowed under the brush, `pixel_size` being how much surface one pixel covers at the brush.
+    pub fn edge_length(&self, brush_radius: f32, pixel_size: f32) -> f32 {
+        match self.detail {
+            DetailSize::Brush(fraction) => brush_radius * fraction,
+            DetailSize::Screen(pixels) => pixels * pixel_size,
+        }
+    }
+
+    pub fn toggle_detail(&mut self) {
+        self.detail = match self.detail {
+            DetailSize::Brush(_) => DetailSize::Screen(DEFAULT_SCREEN_DETAIL),
+            DetailSize::Screen(_) => DetailSize::Brush(DEFAULT_BRUSH_DETAIL),
+        };
+    }
+}
+
+/// Splits edges longer than `edge_length` and collapses much shorter ones around `center`,
+/// starting from the `vertices` inside the brush. Everything overwritten goes through `recorder` first.
+/// Returns whether the topology changed, leaving the caller's BVH and GPU buffers stale if so.
+pub fn remesh(
+    mesh: &mut SculptMesh,
+    vertices: &[usize],
+    center: Point3<f32>,
+    radius: f32,
+    edge_length: f32,
+    recorder: &mut TopologyRecorder,
+) -> bool {
+    if edge_length <= 0.0 {
+        return false;
+    }
+    let radius2 = radius * radius;
+    let split2 = edge_length * edge_length;
+    let collapse2 = split2 * COLLAPSE_RATIO * COLLAPSE_RATIO;
+    let mut region = vertices.iter().copied().collect::<HashSet<_>>();
+    let mut changed = false;
+
+    let mut splits = 0;
+    for _ in 0..MAX_PASSES {
+        let mut edges = region_edges(mesh, &region, center, radius2, |length2| length2 > split2);
+        if edges.is_empty() || splits >= MAX_SPLITS_PER_DAB {
+            break;
+        }
+        // Longest first, so the split points spread evenly instead of piling up on one edge
+        edges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, a, b) in edges {
+            if splits >= MAX_SPLITS_PER_DAB {
+                break;
+            }
+            if edge_length2(mesh, a, b).is_some_and(|length2| length2 > split2) {
+                if let Some(m) = split_edge(mesh, a, b, recorder) {
+                    region.insert(m);
+                    splits += 1;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut edges = region_edges(mesh, &region, center, radius2, |length2| length2 < collapse2);
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0));
+    for (_, a, b) in edges {
+        // Earlier collapses renumber vertices, so the edge may be gone or a different one by now
+        if !edge_length2(mesh, a, b).is_some_and(|length2| length2 < collapse2) {
+            continue;
+        }
+        if let Some(moved) = collapse_edge(mesh, a, b, recorder) {
+            region.remove(&b);
+            if let Some(last) = moved {
+                if region.remove(&last) {
+                    region.insert(b);
+                }
+            }
+            changed = true;
+        }
+    }
+
+    if changed {
+        let region = region.into_iter().filter(|&v| v < mesh.num_vertices()).collect::<Vec<_>>();
+        recorder.record_vertices(mesh, &with_neighbors(mesh, &region));
+        mesh.recompute_normals_region(&region);
+    }
+    changed
+}
+
+/// What welding the seams under a brush did.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SeamWeld {
+    pub changed: bool,
+    // Copies with different uvs were merged, so the texture smears across the seam there
+    pub uvs_lost: bool,
+}
+
+/// Merges vertices at exactly the same position, like the duplicates OBJ keeps along uv seams and hard edges,
+/// among `vertices` and their neighbors. Splitting an edge on one side of a seam would leave a crack, so this
+/// runs on the region before remeshing it, and only there so the texture keeps its seams everywhere else.
+/// Vertices are renumbered when anything is merged.
+pub fn weld_seams(mesh: &mut SculptMesh, vertices: &[usize], recorder: &mut TopologyRecorder) -> SeamWeld {
+    // Adding zero turns -0.0 into 0.0 so both land on the same key
+    let key = |p: Point3<f32>| [(p.x + 0.0).to_bits(), (p.y + 0.0).to_bits(), (p.z + 0.0).to_bits()];
+    let vertices = vertices.iter().copied().filter(|&v| v < mesh.num_vertices()).collect::<Vec<_>>();
+    let mut first = HashMap::new();
+    let mut duplicates = Vec::new();
+    // In increasing order, so every duplicate is merged into a lower index
+    for v in with_neighbors(mesh, &vertices) {
+        let original = *first.entry(key(mesh.positions[v])).or_insert(v);
+        if original != v {
+            duplicates.push((v, original));
+        }
+    }
+    if duplicates.is_empty() {
+        return SeamWeld::default();
+    }
+    let uvs_lost = duplicates.iter().any(|&(v, original)| mesh.uvs[v] != mesh.uvs[original]);
+
+    let mut kept = Vec::new();
+    for &(v, original) in &duplicates {
+        for f in mesh.vertex_faces[v].clone() {
+            let corners = mesh.face(f).map(|c| if c == v { original } else { c });
+            set_face(mesh, f, corners, recorder);
+        }
+        kept.push(original);
+    }
+
+    // Faces whose corners ran into each other are gone, highest first so removals don't move pending ones
+    let mut degenerate = kept.iter()
+        .flat_map(|&v| mesh.vertex_faces[v].clone())
+        .filter(|&f| {
+            let [a, b, c] = mesh.face(f);
+            a == b || b == c || c == a
+        })
+        .collect::<Vec<_>>();
+    degenerate.sort_unstable();
+    degenerate.dedup();
+    for &f in degenerate.iter().rev() {
+        remove_face(mesh, f, recorder);
+    }
+
+    // Duplicates are always the higher index, so removing from the top down
+    // never moves one that is still waiting to be removed
+    let mut touched = kept.iter().flat_map(|&v| mesh.vertex_faces[v].clone()).flat_map(|f| mesh.face(f)).collect::<Vec<_>>();
+    let mut removed = duplicates.iter().map(|&(v, _)| v).collect::<Vec<_>>();
+    removed.sort_unstable();
+    for &v in removed.iter().rev() {
+        let moved = remove_vertex(mesh, v, recorder);
+        for t in &mut touched {
+            if Some(*t) == moved {
+                *t = v;
+            }
+        }
+    }
+
+    touched.sort_unstable();
+    touched.dedup();
+    touched.retain(|&v| v < mesh.num_vertices());
+    for &v in &touched {
+        refresh_neighbors(mesh, v);
+    }
+    recorder.record_vertices(mesh, &with_neighbors(mesh, &touched));
+    mesh.recompute_normals_region(&touched);
+    SeamWeld { changed: true, uvs_lost }
+}
+
+/// Whether every edge borders one or two consistently wound faces, no face repeats a corner,
+/// and the faces around every vertex form a single fan, the invariants remeshing has to keep.
+pub fn is_manifold(mesh: &SculptMesh) -> bool {
+    let mut directed = HashMap::new();
+    for f in 0..mesh.num_faces() {
+        let [a, b, c] = mesh.face(f);
+        if a == b || b == c || c == a {
+            return false;
+        }
+        for edge in [(a, b), (b, c), (c, a)] {
+            // The same directed edge twice means flipped winding or more than two faces
+            if directed.insert(edge, f).is_some() {
+                return false;
+            }
+        }
+    }
+
+    (0..mesh.num_vertices()).all(|v| {
+        let faces = &mesh.vertex_faces[v];
+        if faces.is_empty() {
+            return true;
+        }
+        // Walk around the fan from one face and check every face gets reached
+        let next = |f: usize| {
+            let corners = mesh.face(f);
+            let i = corners.iter().position(|&c| c == v).unwrap_or(0);
+            corners[(i + 2) % 3]
+        };
+        let mut reached = HashSet::new();
+        let mut stack = vec![faces[0]];
+        while let Some(f) = stack.pop() {
+            if !reached.insert(f) {
+                continue;
+            }
+            let corners = mesh.face(f);
+            let i = corners.iter().position(|&c| c == v).unwrap_or(0);
+            for other in [corners[(i + 1) % 3], next(f)] {
+                for &g in faces {
+                    if !reached.contains(&g) && mesh.face(g).contains(&other) {
+                        stack.push(g);
+                    }
+                }
+            }
+        }
+        reached.len() == faces.len()
+    })
+}
+
+fn edge_length2(mesh: &SculptMesh, a: usize, b: usize) -> Option<f32> {
+    if a >= mesh.num_vertices() || b >= mesh.num_vertices() || !mesh.vertex_neighbors[a].contains(&b) {
+        return None;
+    }
+    Some((mesh.positions[a] - mesh.positions[b]).magnitude2())
+}
+
+// Edges of the faces around the region whose midpoint is inside the brush and whose length passes `keep`
+fn region_edges(
+    mesh: &SculptMesh,
+    region: &HashSet<usize>,
+    center: Point3<f32>,
+    radius2: f32,
+    keep: impl Fn(f32) -> bool,
+) -> Vec<(f32, usize, usize)> {
+    let mut seen = HashSet::new();
+    let mut edges = Vec::new();
+    for &v in region {
+        if v >= mesh.num_vertices() {
+            continue;
+        }
+        for &n in &mesh.vertex_neighbors[v] {
+            let edge = (v.min(n), v.max(n));
+            if !seen.insert(edge) {
+                continue;
+            }
+            let (a, b) = (mesh.positions[edge.0], mesh.positions[edge.1]);
+            if (a.midpoint(b) - center).magnitude2() > radius2 {
+                continue;
+            }
+            let length2 = (a - b).magnitude2();
+            if keep(length2) {
+                edges.push((length2, edge.0, edge.1));
+            }
+        }
+    }
+    edges
+}
+
+fn with_neighbors(mesh: &SculptMesh, vertices: &[usize]) -> Vec<usize> {
+    let mut all = vertices.to_vec();
+    for &v in vertices {
+        all.extend_from_slice(&mesh.vertex_neighbors[v]);
+    }
+    all.sort_unstable();
+    all.dedup();
+    all
+}
+
+fn edge_faces(mesh: &SculptMesh, a: usize, b: usize) -> Vec<usize> {
+    mesh.vertex_faces[a].iter().copied().filter(|&f| mesh.face(f).contains(&b)).collect()
+}
+
+fn opposite(mesh: &SculptMesh, face: usize, a: usize, b: usize) -> usize {
+    mesh.face(face).into_iter().find(|&v| v != a && v != b).unwrap_or(a)
+}
+
+fn is_boundary(mesh: &SculptMesh, v: usize) -> bool {
+    mesh.vertex_neighbors[v].iter().any(|&n| edge_faces(mesh, v, n).len() != 2)
+}
+
+fn refresh_neighbors(mesh: &mut SculptMesh, v: usize) {
+    let mut neighbors = Vec::new();
+    for &f in &mesh.vertex_faces[v] {
+        for c in mesh.face(f) {
+            if c != v && !neighbors.contains(&c) {
+                neighbors.push(c);
+            }
+        }
+    }
+    mesh.vertex_neighbors[v] = neighbors;
+}
+
+fn face_normal(corners: [Point3<f32>; 3]) -> Vector3<f32> {
+    (corners[1] - corners[0]).cross(corners[2] - corners[0])
+}
+
+// Blends two vertices into `target`, which may be one of them
+fn blend_vertices(mesh: &mut SculptMesh, target: usize, a: usize, b: usize) {
+    let normal = mesh.normals[a] + mesh.normals[b];
+    let (uv_a, uv_b) = (mesh.uvs[a], mesh.uvs[b]);
+    mesh.positions[target] = mesh.positions[a].midpoint(mesh.positions[b]);
+    if normal.magnitude2() > 1e-20 {
+        mesh.normals[target] = normal.normalize();
+    }
+    mesh.uvs[target] = [(uv_a[0] + uv_b[0]) * 0.5, (uv_a[1] + uv_b[1]) * 0.5];
+    if mesh.has_colors() {
+        let (ca, cb) = (mesh.colors[a], mesh.colors[b]);
+        mesh.colors[target] = [0, 1, 2, 3].map(|i| (ca[i] + cb[i]) * 0.5);
+    }
+    mesh.mark_dirty(target);
+}
+
+fn push_vertex(mesh: &mut SculptMesh, recorder: &mut TopologyRecorder) -> usize {
+    let v = mesh.num_vertices();
+    recorder.record_vertices(mesh, &[v]);
+    mesh.positions.push(Point3::origin());
+    mesh.normals.push(Vector3::zero());
+    mesh.uvs.push([0.0, 0.0]);
+    if mesh.has_colors() {
+        mesh.colors.push([1.0; 4]);
+    }
+    mesh.tangents.push(Vector3::zero());
+    mesh.bitangents.push(Vector3::zero());
+    mesh.vertex_faces.push(Vec::new());
+    mesh.vertex_neighbors.push(Vec::new());
+    mesh.mark_dirty(v);
+    v
+}
+
+fn push_face(mesh: &mut SculptMesh, corners: [usize; 3], recorder: &mut TopologyRecorder) -> usize {
+    let f = mesh.num_faces();
+    recorder.record_faces(mesh, &[f]);
+    mesh.indices.extend(corners.map(|c| c as u32));
+    for c in corners {
+        mesh.vertex_faces[c].push(f);
+    }
+    f
+}
+
+fn set_face(mesh: &mut SculptMesh, f: usize, corners: [usize; 3], recorder: &mut TopologyRecorder) {
+    recorder.record_faces(mesh, &[f]);
+    for old in mesh.face(f) {
+        if !corners.contains(&old) {
+            mesh.vertex_faces[old].retain(|&g| g != f);
+        }
+    }
+    for (i, c) in corners.into_iter().enumerate() {
+        mesh.indices[f * 3 + i] = c as u32;
+        if !mesh.vertex_faces[c].contains(&f) {
+            mesh.vertex_faces[c].push(f);
+        }
+    }
+}
+
+// Moves the last face into the removed one's slot, so indices stay packed
+fn remove_face(mesh: &mut SculptMesh, f: usize, recorder: &mut TopologyRecorder) {
+    let last = mesh.num_faces() - 1;
+    recorder.record_faces(mesh, &[f, last]);
+    for v in mesh.face(f) {
+        mesh.vertex_faces[v].retain(|&g| g != f);
+    }
+    if f != last {
+        for v in mesh.face(last) {
+            for g in &mut mesh.vertex_faces[v] {
+                if *g == last {
+                    *g = f;
+                }
+            }
+        }
+        mesh.indices.copy_within(last * 3..last * 3 + 3, f * 3);
+    }
+    mesh.indices.truncate(last * 3);
+}
+
+// Removes a vertex no face uses anymore by moving the last vertex into its slot.
+// Returns the index the moved vertex had, None if the removed one was the last.
+fn remove_vertex(mesh: &mut SculptMesh, v: usize, recorder: &mut TopologyRecorder) -> Option<usize> {
+    let last = mesh.num_vertices() - 1;
+    recorder.record_vertices(mesh, &[v, last]);
+    for n in std::mem::take(&mut mesh.vertex_neighbors[v]) {
+        mesh.vertex_neighbors[n].retain(|&o| o != v);
+    }
+
+    let moved = if v != last {
+        let faces = mesh.vertex_faces[last].clone();
+        recorder.record_faces(mesh, &faces);
+        for f in faces {
+            for i in &mut mesh.indices[f * 3..f * 3 + 3] {
+                if *i as usize == last {
+                    *i = v as u32;
+                }
+            }
+        }
+        for n in mesh.vertex_neighbors[last].clone() {
+            for o in &mut mesh.vertex_neighbors[n] {
+                if *o == last {
+                    *o = v;
+                }
+            }
+        }
+        mesh.positions[v] = mesh.positions[last];
+        mesh.normals[v] = mesh.normals[last];
+        mesh.uvs[v] = mesh.uvs[last];
+        if mesh.has_colors() {
+            mesh.colors[v] = mesh.colors[last];
+        }
+        mesh.tangents[v] = mesh.tangents[last];
+        mesh.bitangents[v] = mesh.bitangents[last];
+        mesh.vertex_faces.swap(v, last);
+        mesh.vertex_neighbors.swap(v, last);
+        mesh.mark_dirty(v);
+        Some(last)
+    } else {
+        None
+    };
+
+    mesh.positions.truncate(last);
+    mesh.normals.truncate(last);
+    mesh.uvs.truncate(last);
+    if mesh.has_colors() {
+        mesh.colors.truncate(last);
+    }
+    mesh.tangents.truncate(last);
+    mesh.bitangents.truncate(last);
+    mesh.vertex_faces.truncate(last);
+    mesh.vertex_neighbors.truncate(last);
+    moved
+}
+
+/// Splits the edge between `a` and `b` at its midpoint, turning each face on it into two.
+/// Returns the new vertex, None where the edge has more than two faces and can't be split cleanly.
+pub fn split_edge(mesh: &mut SculptMesh, a: usize, b: usize, recorder: &mut TopologyRecorder) -> Option<usize> {
+    let faces = edge_faces(mesh, a, b);
+    if faces.is_empty() || faces.len() > 2 {
+        return None;
+    }
+
+    let m = push_vertex(mesh, recorder);
+    blend_vertices(mesh, m, a, b);
+
+    let mut opposites = Vec::new();
+    for f in faces {
+        let corners = mesh.face(f);
+        let i = corners.iter().position(|&c| c == a).unwrap_or(0);
+        // Keep the winding, whichever way round the edge runs in this face
+        let (from, to) = if corners[(i + 1) % 3] == b { (a, b) } else { (b, a) };
+        let c = opposite(mesh, f, a, b);
+        set_face(mesh, f, [from, m, c], recorder);
+        push_face(mesh, [m, to, c], recorder);
+        opposites.push(c);
+    }
+
+    for v in [a, b, m].into_iter().chain(opposites) {
+        refresh_neighbors(mesh, v);
+    }
+    Some(m)
+}
+
+/// Collapses the edge between `a` and `b` into its midpoint, keeping `a` and removing `b`.
+/// Refused where it would make the mesh non-manifold, flip a face or eat into a boundary.
+/// On success returns the index the vertex moved into `b`'s slot had, as `remove_vertex` does.
+pub fn collapse_edge(mesh: &mut SculptMesh, a: usize, b: usize, recorder: &mut TopologyRecorder) -> Option<Option<usize>> {
+    if a == b {
+        return None;
+    }
+    let faces = edge_faces(mesh, a, b);
+    if faces.is_empty() || faces.len() > 2 || is_boundary(mesh, a) || is_boundary(mesh, b) {
+        return None;
+    }
+
+    // Link condition: the only vertices both ends share are the tips of the faces on the edge,
+    // anything else would pinch the surface into a non-manifold edge
+    let opposites = faces.iter().map(|&f| opposite(mesh, f, a, b)).collect::<Vec<_>>();
+    let shared = mesh.vertex_neighbors[a].iter().filter(|n| mesh.vertex_neighbors[b].contains(n)).count();
+    if shared != opposites.len() || opposites.iter().any(|&c| mesh.vertex_neighbors[c].len() <= 3) {
+        return None;
+    }
+
+    let midpoint = mesh.positions[a].midpoint(mesh.positions[b]);
+    let around = mesh.vertex_faces[a].iter().chain(&mesh.vertex_faces[b]).copied().filter(|f| !faces.contains(f)).collect::<Vec<_>>();
+    for &f in &around {
+        let corners = mesh.face(f);
+        let before = face_normal(corners.map(|c| mesh.positions[c]));
+        let after = face_normal(corners.map(|c| if c == a || c == b { midpoint } else { mesh.positions[c] }));
+        if after.magnitude2() <= before.magnitude2() * 1e-6 || before.dot(after) <= 0.0 {
+            return None;
+        }
+    }
+
+    let neighbors = mesh.vertex_neighbors[a].iter().chain(&mesh.vertex_neighbors[b]).copied()
+        .filter(|&n| n != a && n != b)
+        .collect::<Vec<_>>();
+    recorder.record_vertices(mesh, &[a, b]);
+    blend_vertices(mesh, a, a, b);
+    for f in mesh.vertex_faces[b].clone() {
+        if !faces.contains(&f) {
+            let corners = mesh.face(f).map(|c| if c == b { a } else { c });
+            set_face(mesh, f, corners, recorder);
+        }
+    }
+    let mut faces = faces;
+    faces.sort_unstable();
+    for &f in faces.iter().rev() {
+        remove_face(mesh, f, recorder);
+    }
+
+    mesh.vertex_neighbors[b].clear();
+    for v in neighbors.into_iter().chain([a]) {
+        refresh_neighbors(mesh, v);
+    }
+    Some(remove_vertex(mesh, b, recorder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources;
+
+    fn euler_characteristic(mesh: &SculptMesh) -> i64 {
+        let used = (0..mesh.num_vertices()).filter(|&v| !mesh.vertex_faces[v].is_empty()).count();
+        let edges = (0..mesh.num_vertices())
+            .flat_map(|v| mesh.vertex_neighbors[v].iter().filter(move |&&n| n > v))
+            .count();
+        used as i64 - edges as i64 + mesh.num_faces() as i64
+    }
+
+    fn welded_cube(recorder: Option<&mut TopologyRecorder>) -> SculptMesh {
+        let mut mesh = resources::test_mesh("cube.obj");
+        let all = (0..mesh.num_vertices()).collect::<Vec<_>>();
+        let mut own = TopologyRecorder::new(0, &mesh);
+        let weld = weld_seams(&mut mesh, &all, recorder.unwrap_or(&mut own));
+        assert!(weld.changed);
+        mesh
+    }
+
+    // Remeshes around a few of the cube's corners, like dabs of a stroke
+    fn remesh_rounds(mesh: &mut SculptMesh, edge_length: f32, recorder: &mut TopologyRecorder) -> bool {
+        let mut changed = false;
+        for center in [Point3::new(1.0, 0.9, 0.9), Point3::new(-0.9, -1.0, 0.9), Point3::new(0.9, 0.9, -1.0)] {
+            let radius = 0.6;
+            let vertices = (0..mesh.num_vertices())
+                .filter(|&v| (mesh.positions[v] - center).magnitude() <= radius)
+                .collect::<Vec<_>>();
+            changed |= remesh(mesh, &vertices, center, radius, edge_length, recorder);
+            assert!(is_manifold(mesh));
+            assert_eq!(euler_characteristic(mesh), 2);
+        }
+        changed
+    }
+
+    #[test]
+    fn welded_cube_is_a_closed_manifold() {
+        let raw = resources::test_mesh("cube.obj");
+        let mesh = welded_cube(None);
+        assert!(mesh.num_vertices() < raw.num_vertices());
+        assert!(is_manifold(&mesh));
+        assert_eq!(euler_characteristic(&mesh), 2);
+    }
+
+    #[test]
+    fn welding_only_touches_the_region() {
+        let mut mesh = resources::test_mesh("cube.obj");
+        let bottom = (0..mesh.num_vertices()).filter(|&v| mesh.positions[v].y < -0.95).collect::<Vec<_>>();
+        // The bottom's neighbors reach up the sides, but not as far as the top's bevel
+        let top = |mesh: &SculptMesh| (0..mesh.num_vertices()).filter(|&v| mesh.positions[v].y > 0.95).count();
+        let copies = top(&mesh);
+        let mut recorder = TopologyRecorder::new(0, &mesh);
+        assert!(weld_seams(&mut mesh, &bottom, &mut recorder).changed);
+        assert_eq!(top(&mesh), copies);
+        assert!(top(&welded_cube(None)) < copies);
+    }
+
+    #[test]
+    fn splitting_and_collapsing_keep_the_cube_closed() {
+        let mut mesh = welded_cube(None);
+        let mut recorder = TopologyRecorder::new(0, &mesh);
+        let faces = mesh.num_faces();
+        assert!(remesh_rounds(&mut mesh, 0.01, &mut recorder));
+        assert!(mesh.num_faces() > faces);
+        let split = mesh.num_faces();
+        assert!(remesh_rounds(&mut mesh, 1.0, &mut recorder));
+        assert!(mesh.num_faces() < split);
+    }
+
+    #[test]
+    fn topology_edits_undo_and_redo_exactly() {
+        let original = resources::test_mesh("cube.obj");
+        let mut recorder = TopologyRecorder::new(0, &original);
+        let mut welded = welded_cube(Some(&mut recorder));
+        remesh_rounds(&mut welded, 0.01, &mut recorder);
+        remesh_rounds(&mut welded, 1.0, &mut recorder);
+        let after = welded.clone();
+        let mut edit = recorder.finish(&welded).unwrap();
+
+        edit.undo(&mut welded);
+        assert_eq!(welded.positions, original.positions);
+        assert_eq!(welded.indices, original.indices);
+        edit.redo(&mut welded);
+        assert_eq!(welded.positions, after.positions);
+        assert_eq!(welded.indices, after.indices);
+    }
+}
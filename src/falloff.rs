@@ -0,0 +1,159 @@
+// Number of samples the cursor overlay gets, the shader interpolates between them
+pub const CURVE_SAMPLES: usize = 16;
+
+/// How a brush's influence fades from its center (t = 0) to its rim (t = 1).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Falloff {
+    #[default]
+    Smooth,
+    Sphere,
+    Root,
+    Sharp,
+    Linear,
+    Constant,
+    // (t, influence) control points, interpolated linearly and sorted by t
+    Custom(Vec<[f32; 2]>),
+}
+
+impl Falloff {
+    // The built in curves, softest first, in the order the falloff key cycles through them
+    pub const PRESETS: [Falloff; 6] = [
+        Falloff::Smooth,
+        Falloff::Sphere,
+        Falloff::Root,
+        Falloff::Linear,
+        Falloff::Sharp,
+        Falloff::Constant,
+    ];
+
+    pub fn custom(mut points: Vec<[f32; 2]>) -> Self {
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        Falloff::Custom(points)
+    }
+
+    pub fn eval(&self, t: f32) -> f32 {
+        if !(0.0..1.0).contains(&t) {
+            return if t < 0.0 { self.eval(0.0) } else { 0.0 };
+        }
+
+        let s = 1.0 - t;
+        match self {
+            Falloff::Smooth => s * s * (3.0 - 2.0 * s),
+            Falloff::Sphere => (1.0 - t * t).sqrt(),
+            Falloff::Root => s.sqrt(),
+            Falloff::Sharp => s * s,
+            Falloff::Linear => s,
+            Falloff::Constant => 1.0,
+            Falloff::Custom(points) => eval_curve(points, t),
+        }
+    }
+
+    /// The preset after this one, a custom curve moves on to the first preset.
+    pub fn next_preset(&self) -> Falloff {
+        let index = Self::PRESETS.iter().position(|preset| preset == self).map_or(0, |i| (i + 1) % Self::PRESETS.len());
+        Self::PRESETS[index].clone()
+    }
+
+    pub fn sample(&self) -> [f32; CURVE_SAMPLES] {
+        let mut samples = [0.0; CURVE_SAMPLES];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            // Stop just short of the rim so hard brushes keep their edge in the preview
+            let t = i as f32 / (CURVE_SAMPLES - 1) as f32;
+            *sample = self.eval(t.min(0.9999));
+        }
+        samples
+    }
+}
+
+fn eval_curve(points: &[[f32; 2]], t: f32) -> f32 {
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 0.0,
+    };
+    if t <= first[0] {
+        return first[1].clamp(0.0, 1.0);
+    }
+    if t >= last[0] {
+        return last[1].clamp(0.0, 1.0);
+    }
+
+    for pair in points.windows(2) {
+        let [a, b] = [pair[0], pair[1]];
+        if t <= b[0] {
+            let span = b[0] - a[0];
+            let f = if span > 0.0 { (t - a[0]) / span } else { 1.0 };
+            return (a[1] + (b[1] - a[1]) * f).clamp(0.0, 1.0);
+        }
+    }
+    last[1].clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-5, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn presets_at_the_center_middle_and_rim() {
+        let expected = [
+            (Falloff::Smooth, 0.5),
+            (Falloff::Sphere, 0.75f32.sqrt()),
+            (Falloff::Root, 0.5f32.sqrt()),
+            (Falloff::Sharp, 0.25),
+            (Falloff::Linear, 0.5),
+            (Falloff::Constant, 1.0),
+        ];
+        for (falloff, middle) in expected {
+            assert_near(falloff.eval(0.0), 1.0);
+            assert_near(falloff.eval(0.5), middle);
+            // Nothing reaches past the rim, however hard the brush
+            assert_near(falloff.eval(1.0), 0.0);
+            assert_near(falloff.eval(1.5), 0.0);
+            assert_near(falloff.eval(-0.5), 1.0);
+        }
+    }
+
+    #[test]
+    fn custom_curves_are_sorted_and_interpolated() {
+        let falloff = Falloff::custom(vec![[1.0, 0.0], [0.0, 1.0], [0.5, 0.2]]);
+        assert_eq!(falloff, Falloff::Custom(vec![[0.0, 1.0], [0.5, 0.2], [1.0, 0.0]]));
+        assert_near(falloff.eval(0.0), 1.0);
+        assert_near(falloff.eval(0.25), 0.6);
+        assert_near(falloff.eval(0.5), 0.2);
+        assert_near(falloff.eval(0.75), 0.1);
+
+        // Flat past the first and last points, and kept within 0..1
+        let falloff = Falloff::custom(vec![[0.8, -0.5], [0.2, 1.5]]);
+        assert_near(falloff.eval(0.1), 1.0);
+        assert_near(falloff.eval(0.9), 0.0);
+        assert_near(falloff.eval(0.5), 0.5);
+        assert_near(Falloff::custom(Vec::new()).eval(0.5), 0.0);
+    }
+
+    #[test]
+    fn samples_match_eval() {
+        let custom = Falloff::custom(vec![[0.0, 1.0], [0.3, 0.9], [1.0, 0.1]]);
+        for falloff in Falloff::PRESETS.iter().chain([&custom]) {
+            let samples = falloff.sample();
+            for (i, &sample) in samples.iter().enumerate() {
+                let t = i as f32 / (CURVE_SAMPLES - 1) as f32;
+                assert_near(sample, falloff.eval(t.min(0.9999)));
+            }
+            assert_near(samples[0], 1.0);
+        }
+        // The last sample sits just inside the rim, so a constant brush keeps its hard edge
+        assert_near(Falloff::Constant.sample()[CURVE_SAMPLES - 1], 1.0);
+    }
+
+    #[test]
+    fn presets_cycle_back_to_the_start() {
+        let mut falloff = Falloff::custom(vec![[0.0, 1.0]]);
+        for preset in Falloff::PRESETS.iter().chain([&Falloff::PRESETS[0]]) {
+            falloff = falloff.next_preset();
+            assert_eq!(&falloff, preset);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::{borrow::Cow, collections::HashMap};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Write;
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use cgmath::{Matrix4, SquareMatrix, Vector3, Zero};
+#[cfg(not(target_arch = "wasm32"))]
+use gltf::json::{self, validation::{Checked::Valid, USize64}};
+
+use crate::{instance::Instance, sculpt_mesh::SculptMesh, texture::EncodedImage};
+
+pub struct ImportedMaterial {
+    pub name: String,
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: Option<EncodedImage>,
+    pub normal_texture: Option<EncodedImage>,
+    pub metallic_roughness_texture: Option<EncodedImage>,
+}
+
+pub struct ImportedMesh {
+    pub name: String,
+    pub mesh: SculptMesh,
+    // Index into the imported materials
+    pub material: Option<usize>,
+}
+
+/// Everything taken from a glTF scene.
+/// When every node draws the same mesh the nodes become instances of its primitives,
+/// otherwise each node's primitives are baked into world space and placed by a single identity instance.
+pub struct ImportedScene {
+    pub meshes: Vec<ImportedMesh>,
+    pub materials: Vec<ImportedMaterial>,
+    pub instances: Vec<Instance>,
+}
+
+/// Buffers and images stored in separate files, which the caller has to fetch before importing.
+/// Data URIs and the GLB binary chunk are decoded in place.
+pub fn external_uris(document: &gltf::Document) -> Vec<String> {
+    let buffers = document.buffers().filter_map(|buffer| match buffer.source() {
+        gltf::buffer::Source::Uri(uri) => Some(uri),
+        gltf::buffer::Source::Bin => None,
+    });
+    let images = document.images().filter_map(|image| match image.source() {
+        gltf::image::Source::Uri { uri, .. } => Some(uri),
+        gltf::image::Source::View { .. } => None,
+    });
+    buffers.chain(images)
+        .filter(|uri| !uri.starts_with("data:"))
+        .map(str::to_string)
+        .collect()
+}
+
+fn resolve_uri<'a>(uri: &str, external: &'a HashMap<String, Vec<u8>>) -> anyhow::Result<Cow<'a, [u8]>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (kind, payload) = data.split_once(',').context("malformed data URI")?;
+        if !kind.ends_with(";base64") {
+            bail!("only base64 data URIs are supported");
+        }
+        let bytes = base64::engine::general_purpose::STANDARD.decode(payload).context("bad base64 in data URI")?;
+        return Ok(Cow::Owned(bytes));
+    }
+    external.get(uri)
+        .map(|bytes| Cow::Borrowed(bytes.as_slice()))
+        .with_context(|| format!("{} was not loaded", uri))
+}
+
+pub fn import(gltf: &gltf::Gltf, external: &HashMap<String, Vec<u8>>) -> anyhow::Result<ImportedScene> {
+    let document = &gltf.document;
+
+    let buffers = document.buffers().map(|buffer| {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.as_deref().context("GLB has no binary chunk")?.to_vec(),
+            gltf::buffer::Source::Uri(uri) => resolve_uri(uri, external)?.into_owned(),
+        };
+        if data.len() < buffer.length() {
+            bail!("buffer {} is {} bytes but should be {}", buffer.index(), data.len(), buffer.length());
+        }
+        Ok(data)
+    }).collect::<anyhow::Result<Vec<_>>>()?;
+
+    let image_bytes = |texture: gltf::Texture| -> anyhow::Result<EncodedImage> {
+        let bytes = match texture.source().source() {
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &buffers[view.buffer().index()];
+                buffer.get(view.offset()..view.offset() + view.length())
+                    .context("image runs past the end of its buffer")?
+                    .to_vec()
+            }
+            gltf::image::Source::Uri { uri, .. } => resolve_uri(uri, external)?.into_owned(),
+        };
+        Ok(EncodedImage::new(bytes))
+    };
+
+    let materials = document.materials().map(|material| {
+        let pbr = material.pbr_metallic_roughness();
+        Ok(ImportedMaterial {
+            name: material.name().map(str::to_string).unwrap_or_else(|| format!("material_{}", material.index().unwrap_or(0))),
+            base_color_factor: pbr.base_color_factor(),
+            base_color_texture: pbr.base_color_texture().map(|info| image_bytes(info.texture())).transpose()?,
+            normal_texture: material.normal_texture().map(|info| image_bytes(info.texture())).transpose()?,
+            metallic_roughness_texture: pbr.metallic_roughness_texture().map(|info| image_bytes(info.texture())).transpose()?,
+        })
+    }).collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Every node drawing a mesh, with its transform from the scene root
+    let mut placed = Vec::new();
+    match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(scene) => {
+            for node in scene.nodes() {
+                collect_nodes(node, Matrix4::identity(), &mut placed);
+            }
+        }
+        // No scene at all, show every mesh where it was modeled
+        None => placed.extend(document.meshes().map(|mesh| (mesh, Matrix4::identity()))),
+    }
+    if placed.is_empty() {
+        bail!("glTF has no meshes");
+    }
+
+    let single_mesh = placed.iter().all(|(mesh, _)| mesh.index() == placed[0].0.index());
+    let mut meshes = Vec::new();
+    let instances;
+    if single_mesh {
+        meshes = read_mesh(&placed[0].0, &buffers)?;
+        instances = placed.iter().map(|(_, transform)| Instance::from_matrix(transform)).collect();
+    } else {
+        for (mesh, transform) in &placed {
+            for mut imported in read_mesh(mesh, &buffers)? {
+                imported.mesh = imported.mesh.transformed(transform);
+                meshes.push(imported);
+            }
+        }
+        instances = vec![Instance::from_matrix(&Matrix4::identity())];
+    }
+    if meshes.is_empty() {
+        bail!("glTF has no triangles");
+    }
+
+    Ok(ImportedScene { meshes, materials, instances })
+}
+
+fn collect_nodes<'a>(node: gltf::Node<'a>, parent: Matrix4<f32>, placed: &mut Vec<(gltf::Mesh<'a>, Matrix4<f32>)>) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        placed.push((mesh, transform));
+    }
+    for child in node.children() {
+        collect_nodes(child, transform, placed);
+    }
+}
+
+// One sculptable mesh per triangle primitive
+fn read_mesh(mesh: &gltf::Mesh, buffers: &[Vec<u8>]) -> anyhow::Result<Vec<ImportedMesh>> {
+    let mut meshes = Vec::new();
+    for primitive in mesh.primitives() {
+        let name = mesh.name().map(str::to_string).unwrap_or_else(|| format!("mesh_{}", mesh.index()));
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            log::warn!("Skipping a {:?} primitive of {}, only triangles can be sculpted", primitive.mode(), name);
+            continue;
+        }
+
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+        let positions = reader.read_positions()
+            .with_context(|| format!("{} has a primitive without positions", name))?
+            .map(Into::into)
+            .collect::<Vec<_>>();
+        let count = positions.len();
+        let normals = reader.read_normals().map(|normals| normals.map(Into::into).collect::<Vec<_>>());
+        // glTF texture coordinates already start at the top left, like the OBJ loader's after flipping
+        let uvs = reader.read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().collect())
+            .unwrap_or_else(|| vec![[0.0, 0.0]; count]);
+        let colors = reader.read_colors(0).map(|colors| colors.into_rgba_f32().collect::<Vec<_>>());
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+            None => (0..count as u32).collect(),
+        };
+
+        if uvs.len() != count || normals.as_ref().is_some_and(|n| n.len() != count) || colors.as_ref().is_some_and(|c| c.len() != count) {
+            bail!("{} has attributes of different lengths", name);
+        }
+        if let Some(&i) = indices.iter().find(|&&i| i as usize >= count) {
+            bail!("{} refers to vertex {} but there are only {}", name, i, count);
+        }
+
+        let has_normals = normals.is_some();
+        let mut sculpt = SculptMesh::new(positions, normals.unwrap_or_else(|| vec![Vector3::zero(); count]), uvs, indices);
+        sculpt.colors = colors.unwrap_or_default();
+        if !has_normals {
+            sculpt.recompute_normals();
+        }
+        sculpt.take_dirty();
+
+        meshes.push(ImportedMesh { name, mesh: sculpt, material: primitive.material().index() });
+    }
+    Ok(meshes)
+}
+
+/// A mesh to export, drawn with one of the exported materials.
+/// The browser build has no save path, so the exporter is native only.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ExportMesh<'a> {
+    pub mesh: &'a SculptMesh,
+    pub material: usize,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ExportMaterial<'a> {
+    pub name: &'a str,
+    // Linear RGBA, multiplied with the base color texture like on import
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: Option<&'a EncodedImage>,
+    pub normal_texture: Option<&'a EncodedImage>,
+    pub metallic_roughness_texture: Option<&'a EncodedImage>,
+}
+
+// Collects everything that goes in the binary chunk, each piece 4 byte aligned
+#[cfg(not(target_arch = "wasm32"))]
+struct BinaryChunk {
+    data: Vec<u8>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BinaryChunk {
+    fn push(&mut self, root: &mut json::Root, bytes: &[u8], target: Option<json::buffer::Target>) -> json::Index<json::buffer::View> {
+        let offset = self.data.len();
+        self.data.extend_from_slice(bytes);
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+        root.push(json::buffer::View {
+            buffer: json::Index::new(0),
+            byte_length: USize64::from(bytes.len()),
+            byte_offset: Some(USize64::from(offset)),
+            byte_stride: None,
+            extensions: Default::default(),
+            extras: Default::default(),
+            name: None,
+            target: target.map(Valid),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn push_accessor(
+    root: &mut json::Root,
+    view: json::Index<json::buffer::View>,
+    count: usize,
+    component_type: json::accessor::ComponentType,
+    type_: json::accessor::Type,
+    bounds: Option<([f32; 3], [f32; 3])>,
+) -> json::Index<json::Accessor> {
+    root.push(json::Accessor {
+        buffer_view: Some(view),
+        byte_offset: Some(USize64(0)),
+        count: USize64::from(count),
+        component_type: Valid(json::accessor::GenericComponentType(component_type)),
+        extensions: Default::default(),
+        extras: Default::default(),
+        type_: Valid(type_),
+        min: bounds.map(|(min, _)| json::Value::from(min.to_vec())),
+        max: bounds.map(|(_, max)| json::Value::from(max.to_vec())),
+        name: None,
+        normalized: false,
+        sparse: None,
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn f32_bytes<const N: usize>(values: impl Iterator<Item = [f32; N]>) -> Vec<u8> {
+    values.flat_map(|value| value.into_iter().flat_map(f32::to_le_bytes)).collect()
+}
+
+/// Writes a binary glTF with one mesh holding a primitive per exported mesh, and a node drawing it
+/// for every instance, or a single untransformed node when there are none. Textures are embedded,
+/// except for any that aren't PNG or JPEG, which glTF doesn't allow and which are left out.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_glb<W: Write>(
+    writer: &mut W,
+    meshes: &[ExportMesh],
+    materials: &[ExportMaterial],
+    instances: &[Instance],
+) -> anyhow::Result<()> {
+    let mut root = json::Root::default();
+    root.asset.generator = Some("web_sculpt".to_string());
+    let mut bin = BinaryChunk { data: Vec::new() };
+
+    let sampler = root.push(json::texture::Sampler::default());
+    let mut push_texture = |root: &mut json::Root, image: &EncodedImage, material: &str| {
+        let Some(mime_type) = image.gltf_mime_type() else {
+            log::warn!("Leaving a {} texture of {} out of the GLB, only PNG and JPEG can be embedded", image.mime_type, material);
+            return None;
+        };
+        let view = bin.push(root, &image.bytes, None);
+        let source = root.push(json::Image {
+            buffer_view: Some(view),
+            mime_type: Some(json::image::MimeType(mime_type.to_string())),
+            name: None,
+            uri: None,
+            extensions: Default::default(),
+            extras: Default::default(),
+        });
+        Some(root.push(json::Texture {
+            name: None,
+            sampler: Some(sampler),
+            source,
+            extensions: Default::default(),
+            extras: Default::default(),
+        }))
+    };
+    let info = |index| json::texture::Info {
+        index,
+        tex_coord: 0,
+        extensions: Default::default(),
+        extras: Default::default(),
+    };
+
+    let material_indices = materials.iter().map(|material| {
+        let mut exported = json::Material { name: Some(material.name.to_string()), ..Default::default() };
+        exported.pbr_metallic_roughness.base_color_factor = json::material::PbrBaseColorFactor(material.base_color_factor);
+        let mut texture = |image: Option<&EncodedImage>| image.and_then(|image| push_texture(&mut root, image, material.name));
+        exported.pbr_metallic_roughness.base_color_texture = texture(material.base_color_texture).map(info);
+        exported.pbr_metallic_roughness.metallic_roughness_texture = texture(material.metallic_roughness_texture).map(info);
+        exported.normal_texture = texture(material.normal_texture).map(|index| json::material::NormalTexture {
+            index,
+            scale: 1.0,
+            tex_coord: 0,
+            extensions: Default::default(),
+            extras: Default::default(),
+        });
+        root.push(exported)
+    }).collect::<Vec<_>>();
+
+    let mut primitives = Vec::new();
+    for exported in meshes {
+        let mesh = exported.mesh;
+        let count = mesh.num_vertices();
+        let bounds = mesh.positions.iter().fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), p| {
+            ([min[0].min(p.x), min[1].min(p.y), min[2].min(p.z)], [max[0].max(p.x), max[1].max(p.y), max[2].max(p.z)])
+        });
+
+        use json::accessor::{ComponentType, Type};
+        use json::buffer::Target::{ArrayBuffer, ElementArrayBuffer};
+        let positions = bin.push(&mut root, &f32_bytes(mesh.positions.iter().map(|&p| p.into())), Some(ArrayBuffer));
+        let positions = push_accessor(&mut root, positions, count, ComponentType::F32, Type::Vec3, Some(bounds));
+        let normals = bin.push(&mut root, &f32_bytes(mesh.normals.iter().map(|&n| n.into())), Some(ArrayBuffer));
+        let normals = push_accessor(&mut root, normals, count, ComponentType::F32, Type::Vec3, None);
+        let uvs = bin.push(&mut root, &f32_bytes(mesh.uvs.iter().copied()), Some(ArrayBuffer));
+        let uvs = push_accessor(&mut root, uvs, count, ComponentType::F32, Type::Vec2, None);
+        let indices = mesh.indices.iter().flat_map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+        let indices = bin.push(&mut root, &indices, Some(ElementArrayBuffer));
+        let indices = push_accessor(&mut root, indices, mesh.indices.len(), ComponentType::U32, Type::Scalar, None);
+
+        let mut attributes = std::collections::BTreeMap::new();
+        attributes.insert(Valid(json::mesh::Semantic::Positions), positions);
+        attributes.insert(Valid(json::mesh::Semantic::Normals), normals);
+        attributes.insert(Valid(json::mesh::Semantic::TexCoords(0)), uvs);
+        if mesh.has_colors() {
+            let colors = bin.push(&mut root, &f32_bytes(mesh.colors.iter().copied()), Some(ArrayBuffer));
+            let colors = push_accessor(&mut root, colors, count, ComponentType::F32, Type::Vec4, None);
+            attributes.insert(Valid(json::mesh::Semantic::Colors(0)), colors);
+        }
+
+        primitives.push(json::mesh::Primitive {
+            attributes,
+            extensions: Default::default(),
+            extras: Default::default(),
+            indices: Some(indices),
+            material: material_indices.get(exported.material).copied(),
+            mode: Valid(json::mesh::Mode::Triangles),
+            targets: None,
+        });
+    }
+
+    let mesh = root.push(json::Mesh {
+        extensions: Default::default(),
+        extras: Default::default(),
+        name: Some("sculpt".to_string()),
+        primitives,
+        weights: None,
+    });
+
+    let nodes = if instances.is_empty() {
+        vec![root.push(json::Node { mesh: Some(mesh), ..Default::default() })]
+    } else {
+        instances.iter().map(|instance| {
+            let r = instance.rotation;
+            root.push(json::Node {
+                mesh: Some(mesh),
+                translation: Some(instance.position.into()),
+                rotation: Some(json::scene::UnitQuaternion([r.v.x, r.v.y, r.v.z, r.s])),
+                scale: Some(instance.scale.into()),
+                ..Default::default()
+            })
+        }).collect()
+    };
+    let scene = root.push(json::Scene {
+        extensions: Default::default(),
+        extras: Default::default(),
+        name: None,
+        nodes,
+    });
+    root.scene = Some(scene);
+
+    root.push(json::Buffer {
+        byte_length: USize64::from(bin.data.len()),
+        extensions: Default::default(),
+        extras: Default::default(),
+        name: None,
+        uri: None,
+    });
+
+    let json = json::serialize::to_vec(&root).context("Failed to serialize glTF")?;
+    let glb = gltf::binary::Glb {
+        header: gltf::binary::Header {
+            magic: *b"glTF",
+            version: 2,
+            // Worked out again when writing
+            length: 0,
+        },
+        json: Cow::Owned(json),
+        bin: Some(Cow::Owned(bin.data)),
+    };
+    glb.to_writer(writer).context("Failed to write GLB")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources;
+
+    fn encode(format: image::ImageFormat) -> EncodedImage {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([200, 100, 50])));
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        EncodedImage::new(bytes.into_inner())
+    }
+
+    #[test]
+    fn images_are_labelled_with_their_real_format() {
+        assert_eq!(encode(image::ImageFormat::Png).mime_type, "image/png");
+        assert_eq!(encode(image::ImageFormat::Jpeg).mime_type, "image/jpeg");
+        let gif = EncodedImage::new(b"GIF89a\x01\x00\x01\x00".to_vec());
+        assert_eq!(gif.mime_type, "image/gif");
+        assert_eq!(gif.gltf_mime_type(), None);
+    }
+
+    #[test]
+    fn glb_round_trip_keeps_meshes_and_embeddable_textures() {
+        let mesh = resources::test_mesh("cube.obj");
+        let (png, jpeg) = (encode(image::ImageFormat::Png), encode(image::ImageFormat::Jpeg));
+        let gif = EncodedImage::new(b"GIF89a\x01\x00\x01\x00".to_vec());
+        // Saved by an older project as PNG, but really a GIF
+        let mislabelled = EncodedImage { bytes: gif.bytes.clone(), mime_type: "image/png".to_string() };
+        let materials = [
+            ExportMaterial { name: "textured", base_color_factor: [1.0; 4], base_color_texture: Some(&png), normal_texture: Some(&jpeg), metallic_roughness_texture: Some(&png) },
+            ExportMaterial { name: "gif", base_color_factor: [0.8, 0.2, 0.1, 1.0], base_color_texture: Some(&gif), normal_texture: Some(&mislabelled), metallic_roughness_texture: None },
+        ];
+        let meshes = [ExportMesh { mesh: &mesh, material: 0 }, ExportMesh { mesh: &mesh, material: 1 }];
+        let mut written = Vec::new();
+        write_glb(&mut written, &meshes, &materials, &[]).unwrap();
+
+        let gltf = gltf::Gltf::from_slice(&written).unwrap();
+        let scene = import(&gltf, &HashMap::new()).unwrap();
+        assert_eq!(scene.meshes.len(), 2);
+        for (imported, material) in scene.meshes.iter().zip([0, 1]) {
+            assert_eq!(imported.material, Some(material));
+            assert_eq!(imported.mesh.indices, mesh.indices);
+            assert_eq!(imported.mesh.positions, mesh.positions);
+        }
+
+        let textured = &scene.materials[0];
+        assert_eq!(textured.name, "textured");
+        assert_eq!(textured.base_color_factor, [1.0; 4]);
+        assert_eq!(textured.base_color_texture.as_ref().unwrap().bytes, png.bytes);
+        assert_eq!(textured.normal_texture.as_ref().unwrap().mime_type, "image/jpeg");
+        assert_eq!(textured.metallic_roughness_texture.as_ref().unwrap().bytes, png.bytes);
+        let gif = &scene.materials[1];
+        assert!(gif.base_color_texture.is_none() && gif.normal_texture.is_none());
+        // Untextured but tinted, so the factor is all that's left of its color
+        assert_eq!(gif.base_color_factor, [0.8, 0.2, 0.1, 1.0]);
+        assert!(gltf.document.images().all(|image| matches!(image.source(), gltf::image::Source::View { mime_type: "image/png" | "image/jpeg", .. })));
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{HashSet, VecDeque};
+
+use cgmath::{Point3, Vector3};
+
+use crate::sculpt_mesh::SculptMesh;
+
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+
+/// The vertices a stroke touched and what they held before it.
+/// Undoing swaps the stored values with the mesh, so the same delta then redoes.
+pub struct VertexDelta {
+    pub indices: Vec<usize>,
+    pub positions: Vec<Point3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+}
+
+impl VertexDelta {
+    fn swap(&mut self, mesh: &mut SculptMesh) {
+        for (i, &v) in self.indices.iter().enumerate() {
+            std::mem::swap(&mut self.positions[i], &mut mesh.positions[v]);
+            std::mem::swap(&mut self.normals[i], &mut mesh.normals[v]);
+            mesh.mark_dirty(v);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct VertexSnapshot {
+    pub index: usize,
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub uv: [f32; 2],
+    // Only meaningful when the mesh has colors
+    pub color: [f32; 4],
+}
+
+impl VertexSnapshot {
+    pub fn of(mesh: &SculptMesh, v: usize) -> Self {
+        Self {
+            index: v,
+            position: mesh.positions[v],
+            normal: mesh.normals[v],
+            uv: mesh.uvs[v],
+            color: mesh.colors.get(v).copied().unwrap_or([1.0; 4]),
+        }
+    }
+}
+
+/// The part of a mesh a topology edit rewrote, plus the vertex and face counts
+/// so vertices and faces the edit appended or dropped can be truncated or restored.
+pub struct RegionSnapshot {
+    pub vertex_count: usize,
+    pub face_count: usize,
+    pub vertices: Vec<VertexSnapshot>,
+    pub faces: Vec<(usize, [u32; 3])>,
+}
+
+impl RegionSnapshot {
+    // Indices past the end of the mesh are skipped, they simply don't exist in this state
+    pub fn capture(mesh: &SculptMesh, vertices: &[usize], faces: &[usize]) -> Self {
+        Self {
+            vertex_count: mesh.num_vertices(),
+            face_count: mesh.num_faces(),
+            vertices: vertices.iter()
+                .filter(|&&v| v < mesh.num_vertices())
+                .map(|&v| VertexSnapshot::of(mesh, v))
+                .collect(),
+            faces: faces.iter()
+                .filter(|&&f| f < mesh.num_faces())
+                .map(|&f| (f, [mesh.indices[f * 3], mesh.indices[f * 3 + 1], mesh.indices[f * 3 + 2]]))
+                .collect(),
+        }
+    }
+
+    pub fn restore(&self, mesh: &mut SculptMesh) {
+        mesh.positions.resize(self.vertex_count, Point3::new(0.0, 0.0, 0.0));
+        mesh.normals.resize(self.vertex_count, Vector3::new(0.0, 0.0, 0.0));
+        mesh.uvs.resize(self.vertex_count, [0.0, 0.0]);
+        if mesh.has_colors() {
+            mesh.colors.resize(self.vertex_count, [1.0; 4]);
+        }
+        mesh.indices.resize(self.face_count * 3, 0);
+
+        for vertex in &self.vertices {
+            mesh.positions[vertex.index] = vertex.position;
+            mesh.normals[vertex.index] = vertex.normal;
+            mesh.uvs[vertex.index] = vertex.uv;
+            if mesh.has_colors() {
+                mesh.colors[vertex.index] = vertex.color;
+            }
+        }
+        for &(f, face) in &self.faces {
+            mesh.indices[f * 3..f * 3 + 3].copy_from_slice(&face);
+        }
+        mesh.rebuild_adjacency();
+        mesh.recompute_tangents();
+    }
+
+    fn size(&self) -> usize {
+        self.vertices.len() * std::mem::size_of::<VertexSnapshot>()
+            + self.faces.len() * std::mem::size_of::<(usize, [u32; 3])>()
+    }
+}
+
+pub enum EditKind {
+    Stroke(VertexDelta),
+    Topology { before: RegionSnapshot, after: RegionSnapshot },
+}
+
+pub struct Edit {
+    pub mesh: usize,
+    pub kind: EditKind,
+}
+
+impl Edit {
+    pub fn undo(&mut self, mesh: &mut SculptMesh) {
+        match &mut self.kind {
+            EditKind::Stroke(delta) => delta.swap(mesh),
+            EditKind::Topology { before, .. } => before.restore(mesh),
+        }
+    }
+
+    pub fn redo(&mut self, mesh: &mut SculptMesh) {
+        match &mut self.kind {
+            EditKind::Stroke(delta) => delta.swap(mesh),
+            EditKind::Topology { after, .. } => after.restore(mesh),
+        }
+    }
+
+    /// An edit swapping the whole of `before` for `after`, for operations that rebuild a mesh from scratch.
+    pub fn replace(mesh: usize, before: &SculptMesh, after: &SculptMesh) -> Self {
+        let capture = |sculpt: &SculptMesh| RegionSnapshot::capture(
+            sculpt,
+            &(0..sculpt.num_vertices()).collect::<Vec<_>>(),
+            &(0..sculpt.num_faces()).collect::<Vec<_>>(),
+        );
+        Self { mesh, kind: EditKind::Topology { before: capture(before), after: capture(after) } }
+    }
+
+    pub fn changes_topology(&self) -> bool {
+        matches!(self.kind, EditKind::Topology { .. })
+    }
+
+    // Vertices to re-upload and refit after undoing or redoing a stroke
+    pub fn vertices(&self) -> &[usize] {
+        match &self.kind {
+            EditKind::Stroke(delta) => &delta.indices,
+            EditKind::Topology { .. } => &[],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match &self.kind {
+            EditKind::Stroke(delta) => delta.indices.len()
+                * (std::mem::size_of::<usize>() + std::mem::size_of::<Point3<f32>>() + std::mem::size_of::<Vector3<f32>>()),
+            EditKind::Topology { before, after } => before.size() + after.size(),
+        }
+    }
+}
+
+/// Collects the original values of every vertex a stroke touches, the first time it touches them.
+pub struct DeltaRecorder {
+    pub mesh: usize,
+    seen: HashSet<usize>,
+    delta: VertexDelta,
+}
+
+impl DeltaRecorder {
+    pub fn new(mesh: usize) -> Self {
+        Self {
+            mesh,
+            seen: HashSet::new(),
+            delta: VertexDelta { indices: Vec::new(), positions: Vec::new(), normals: Vec::new() },
+        }
+    }
+
+    pub fn record(&mut self, mesh: &SculptMesh, vertices: &[usize]) {
+        for &v in vertices {
+            if self.seen.insert(v) {
+                self.delta.indices.push(v);
+                self.delta.positions.push(mesh.positions[v]);
+                self.delta.normals.push(mesh.normals[v]);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Option<Edit> {
+        if self.delta.indices.is_empty() {
+            return None;
+        }
+        Some(Edit { mesh: self.mesh, kind: EditKind::Stroke(self.delta) })
+    }
+}
+
+/// Collects what a topology changing stroke overwrites, the first time it overwrites it, so the whole
+/// stroke undoes as one `EditKind::Topology`. Every vertex and face slot has to be recorded before it
+/// is changed, including slots that only change because another vertex or face was moved into them.
+pub struct TopologyRecorder {
+    pub mesh: usize,
+    before: RegionSnapshot,
+    seen_vertices: HashSet<usize>,
+    seen_faces: HashSet<usize>,
+}
+
+impl TopologyRecorder {
+    pub fn new(mesh: usize, sculpt: &SculptMesh) -> Self {
+        Self {
+            mesh,
+            before: RegionSnapshot {
+                vertex_count: sculpt.num_vertices(),
+                face_count: sculpt.num_faces(),
+                vertices: Vec::new(),
+                faces: Vec::new(),
+            },
+            seen_vertices: HashSet::new(),
+            seen_faces: HashSet::new(),
+        }
+    }
+
+    // Slots past the original end held nothing worth restoring, they only need to be in the after state
+    pub fn record_vertices(&mut self, mesh: &SculptMesh, vertices: &[usize]) {
+        for &v in vertices {
+            if self.seen_vertices.insert(v) && v < self.before.vertex_count && v < mesh.num_vertices() {
+                self.before.vertices.push(VertexSnapshot::of(mesh, v));
+            }
+        }
+    }
+
+    pub fn record_faces(&mut self, mesh: &SculptMesh, faces: &[usize]) {
+        for &f in faces {
+            if self.seen_faces.insert(f) && f < self.before.face_count && f < mesh.num_faces() {
+                self.before.faces.push((f, [mesh.indices[f * 3], mesh.indices[f * 3 + 1], mesh.indices[f * 3 + 2]]));
+            }
+        }
+    }
+
+    pub fn finish(self, mesh: &SculptMesh) -> Option<Edit> {
+        if self.seen_vertices.is_empty() && self.seen_faces.is_empty() {
+            return None;
+        }
+        let vertices = self.seen_vertices.into_iter().collect::<Vec<_>>();
+        let faces = self.seen_faces.into_iter().collect::<Vec<_>>();
+        let after = RegionSnapshot::capture(mesh, &vertices, &faces);
+        Some(Edit { mesh: self.mesh, kind: EditKind::Topology { before: self.before, after } })
+    }
+}
+
+pub struct History {
+    // Oldest edits at the front so they're the first to go when over budget
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    memory_budget: usize,
+    memory_used: usize,
+}
+
+impl History {
+    pub fn new(memory_budget: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            memory_budget,
+            memory_used: 0,
+        }
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub fn push(&mut self, edit: Edit) {
+        for dropped in self.redo.drain(..) {
+            self.memory_used -= dropped.size();
+        }
+        self.memory_used += edit.size();
+        self.undo.push_back(edit);
+        self.trim();
+    }
+
+    // Always keeps the latest edit, even if it alone is over budget
+    fn trim(&mut self) {
+        while self.memory_used > self.memory_budget && self.undo.len() > 1 {
+            if let Some(dropped) = self.undo.pop_front() {
+                self.memory_used -= dropped.size();
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Moves the latest edit to the redo stack and hands it back so the caller can apply it to its mesh.
+    pub fn undo(&mut self) -> Option<&mut Edit> {
+        let edit = self.undo.pop_back()?;
+        self.redo.push(edit);
+        self.redo.last_mut()
+    }
+
+    pub fn redo(&mut self) -> Option<&mut Edit> {
+        let edit = self.redo.pop()?;
+        self.undo.push_back(edit);
+        self.undo.back_mut()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.memory_used = 0;
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A stroke over `count` vertices, `mesh` doubling as a tag to tell edits apart
+    fn stroke(mesh: usize, count: usize) -> Edit {
+        let mut recorder = DeltaRecorder::new(mesh);
+        let sculpt = SculptMesh::new(vec![Point3::new(0.0, 0.0, 0.0); count], vec![Vector3::unit_y(); count], vec![[0.0, 0.0]; count], Vec::new());
+        recorder.record(&sculpt, &(0..count).collect::<Vec<_>>());
+        recorder.finish().unwrap()
+    }
+
+    fn undo_tags(history: &mut History) -> Vec<usize> {
+        std::iter::from_fn(|| history.undo().map(|edit| edit.mesh)).collect()
+    }
+
+    #[test]
+    fn oldest_edits_are_dropped_past_the_budget() {
+        let size = stroke(0, 10).size();
+        let mut history = History::new(size * 3);
+        for tag in 0..5 {
+            history.push(stroke(tag, 10));
+            assert!(history.memory_used() <= size * 3);
+        }
+        assert_eq!(history.memory_used(), size * 3);
+        assert_eq!(undo_tags(&mut history), [4, 3, 2]);
+    }
+
+    #[test]
+    fn the_latest_edit_stays_even_over_budget() {
+        let mut history = History::new(1);
+        history.push(stroke(0, 10));
+        history.push(stroke(1, 100));
+        assert_eq!(history.memory_used(), stroke(1, 100).size());
+        assert_eq!(undo_tags(&mut history), [1]);
+    }
+
+    #[test]
+    fn pushing_clears_redo() {
+        let mut history = History::default();
+        for tag in 0..3 {
+            history.push(stroke(tag, 10));
+        }
+        history.undo();
+        history.undo();
+        assert!(history.can_redo());
+
+        history.push(stroke(3, 10));
+        assert!(!history.can_redo());
+        assert!(history.redo().is_none());
+        assert_eq!(history.memory_used(), stroke(0, 10).size() * 2);
+        assert_eq!(undo_tags(&mut history), [3, 0]);
+    }
+
+    #[test]
+    fn undo_and_redo_swap_the_stroke_back_and_forth() {
+        let mut mesh = SculptMesh::new(vec![Point3::new(0.0, 0.0, 0.0); 3], vec![Vector3::unit_y(); 3], vec![[0.0, 0.0]; 3], vec![0, 1, 2]);
+        let mut recorder = DeltaRecorder::new(0);
+        recorder.record(&mesh, &[1]);
+        mesh.positions[1] = Point3::new(0.0, 1.0, 0.0);
+        let mut history = History::default();
+        history.push(recorder.finish().unwrap());
+
+        history.undo().unwrap().undo(&mut mesh);
+        assert_eq!(mesh.positions[1], Point3::new(0.0, 0.0, 0.0));
+        history.redo().unwrap().redo(&mut mesh);
+        assert_eq!(mesh.positions[1], Point3::new(0.0, 1.0, 0.0));
+    }
+}
@@ -1,19 +1,57 @@
 
+#[derive(Debug, Clone)]
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
+    normal: [[f32; 3]; 3],
 }
 
 impl Instance {
+    pub fn model_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Splits a transform back into translation, rotation and scale. Shear can't be represented and is lost.
+    pub fn from_matrix(matrix: &cgmath::Matrix4<f32>) -> Self {
+        use cgmath::{InnerSpace, SquareMatrix};
+        let mut columns = [matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate()];
+        let mut scale = cgmath::Vector3::new(columns[0].magnitude(), columns[1].magnitude(), columns[2].magnitude());
+        // A mirrored transform flips one axis so what's left is a proper rotation
+        if cgmath::Matrix3::from_cols(columns[0], columns[1], columns[2]).determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+        for (column, s) in columns.iter_mut().zip([scale.x, scale.y, scale.z]) {
+            if s != 0.0 {
+                *column /= s;
+            }
+        }
+
+        Self {
+            position: matrix.w.truncate(),
+            rotation: cgmath::Quaternion::from(cgmath::Matrix3::from_cols(columns[0], columns[1], columns[2])).normalize(),
+            scale,
+        }
+    }
+
     pub fn to_raw(&self) -> InstanceRaw {
+        use cgmath::{Matrix, SquareMatrix};
+        let model = self.model_matrix();
+        let upper = cgmath::Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        // Inverse transpose, so normals stay perpendicular to the surface under any transform
+        let normal = upper.invert().unwrap_or(cgmath::Matrix3::identity()).transpose();
+
         InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into()
+            model: model.into(),
+            normal: normal.into(),
         }
     }
 }
@@ -44,6 +82,21 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4
                 },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x3
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x3
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x3
+                },
             ]
         }
     }
@@ -5,15 +5,34 @@ mod camera;
 mod instance;
 mod model;
 mod resources;
-mod brush;
+pub mod brush;
+pub mod sculpt_mesh;
+pub mod picking;
+pub mod bvh;
+pub mod deform;
+pub mod falloff;
+pub mod stroke;
+pub mod history;
+pub mod dyntopo;
+pub mod subdivision;
+pub mod multires;
+pub mod remesh;
+pub mod cli;
+pub mod obj;
+pub mod stl;
+pub mod ply;
+mod light;
+mod matcap;
+mod gltf_io;
+mod project;
+mod autosave;
 
+use cli::Args;
 use state::State;
 use winit::{
     event::*, event_loop::EventLoop, keyboard::{KeyCode, PhysicalKey}, window::WindowBuilder
 };
 
-use cgmath::{prelude::*, Point3};
-
 #[cfg(target_arch="wasm32")]
 use wasm_bindgen::prelude::*;
 
@@ -31,13 +50,46 @@ pub async fn run() {
     
 
 
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            // ?restore in the page's address reopens the autosaved session, ?quads keeps OBJ quads,
+            // ?undo-memory=MB sets the undo history budget
+            let search = web_sys::window()
+                .and_then(|window| window.location().search().ok())
+                .unwrap_or_default();
+            let params = search.trim_start_matches('?').split('&').collect::<Vec<_>>();
+            let has_param = |name: &str| params.contains(&name);
+            let mut args = Args { restore: has_param("restore"), keep_quads: has_param("quads"), ..Args::default() };
+            if let Some(value) = params.iter().find_map(|param| param.strip_prefix("undo-memory=")) {
+                match cli::parse_megabytes(Some(value)) {
+                    Ok(undo_memory) => args.undo_memory = undo_memory,
+                    Err(e) => log::warn!("{:#}", e),
+                }
+            }
+        } else {
+            let args = match Args::parse(std::env::args().skip(1)) {
+                Ok(args) => args,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+        }
+    }
+
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
-    let mut state = State::new(&window).await;
+    let mut state = match State::new(&window, &args).await {
+        Ok(state) => state,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return;
+        }
+    };
 
     let _loop = event_loop.run(move |event, control_flow| match event {
-        Event::WindowEvent { window_id, ref event } if window_id == state.window.id() => if !state.input(event) {
+        Event::WindowEvent { window_id, ref event } if window_id == state.window.id() && !state.input(event) => {
             match event {
                 WindowEvent::CloseRequested | WindowEvent::KeyboardInput {
                     event: KeyEvent {
@@ -46,28 +98,10 @@ pub async fn run() {
                         ..
                     },
                     ..
-                } => control_flow.exit(),
-                WindowEvent::CursorMoved {
-                    position,
-                    ..
                 } => {
-                    state.brush.update_position(Point3::new(position.x as f32, position.y as f32, 0.0));
+                    state.close();
+                    control_flow.exit();
                 },
-                WindowEvent::MouseWheel { 
-                    delta,
-                    ..
-                } => {
-                    let mut is_positive = true;
-                    match delta {
-                        MouseScrollDelta::LineDelta(x, y) => is_positive = *y >= 0.0,
-                        MouseScrollDelta::PixelDelta(physical_position) => is_positive = physical_position.y >= 0.0,
-                    };
-                    if is_positive {
-                        state.brush.update_radius(state.brush.radius + 1.0);
-                    } else {
-                        state.brush.update_radius(state.brush.radius - 1.0);
-                    }
-                }
                 WindowEvent::Resized(physical_size) => {
                     state.resize(*physical_size);
                 },
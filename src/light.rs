@@ -0,0 +1,188 @@
+use cgmath::{InnerSpace, Vector3};
+use wgpu::util::DeviceExt;
+
+use crate::camera::Camera;
+
+pub const NUM_LIGHTS: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ShadingMode {
+    #[default]
+    Lit,
+    // Looks the color up in a sphere image by view space normal, ignoring the lights
+    Matcap,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DirectionalLight {
+    // Direction the light travels in. In view space (x right, y up, z towards the viewer)
+    // when the rig follows the camera, otherwise in world space
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub specular: f32,
+}
+
+/// Key, fill and rim lights, shaded with Lambert diffuse and Blinn-Phong highlights.
+#[derive(Debug, Clone)]
+pub struct LightRig {
+    pub key: DirectionalLight,
+    pub fill: DirectionalLight,
+    pub rim: DirectionalLight,
+    pub ambient: [f32; 3],
+    pub shininess: f32,
+    // Lights stay put relative to the view while tumbling, which is what sculptors expect
+    pub follow_camera: bool,
+    // Off for plain clay shading
+    pub use_texture: bool,
+    pub mode: ShadingMode,
+}
+
+impl Default for LightRig {
+    fn default() -> Self {
+        Self {
+            key: DirectionalLight {
+                direction: Vector3::new(0.5, -0.6, -0.6),
+                color: [1.0, 0.97, 0.92],
+                intensity: 0.9,
+                specular: 0.35,
+            },
+            fill: DirectionalLight {
+                direction: Vector3::new(-0.7, -0.2, -0.4),
+                color: [0.8, 0.85, 1.0],
+                intensity: 0.35,
+                specular: 0.0,
+            },
+            rim: DirectionalLight {
+                direction: Vector3::new(0.0, -0.3, 1.0),
+                color: [1.0, 1.0, 1.0],
+                intensity: 0.5,
+                specular: 0.2,
+            },
+            ambient: [0.12, 0.12, 0.14],
+            shininess: 32.0,
+            follow_camera: true,
+            use_texture: true,
+            mode: ShadingMode::default(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DirectionalLightRaw {
+    direction: [f32; 3],
+    intensity: f32,
+    color: [f32; 3],
+    specular: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    lights: [DirectionalLightRaw; NUM_LIGHTS],
+    ambient: [f32; 3],
+    shininess: f32,
+    use_texture: u32,
+    mode: u32,
+    _padding: [u32; 2],
+}
+
+impl LightRig {
+    pub fn to_uniform(&self, camera: &Camera) -> LightUniform {
+        // Camera basis for turning view space directions into world space
+        let back = (camera.eye - camera.target).normalize();
+        let right = camera.up.cross(back).normalize();
+        let up = back.cross(right);
+
+        let raw = |light: &DirectionalLight| {
+            let direction = if self.follow_camera {
+                right * light.direction.x + up * light.direction.y + back * light.direction.z
+            } else {
+                light.direction
+            };
+            DirectionalLightRaw {
+                direction: direction.normalize().into(),
+                intensity: light.intensity,
+                color: light.color,
+                specular: light.specular,
+            }
+        };
+
+        LightUniform {
+            lights: [raw(&self.key), raw(&self.fill), raw(&self.rim)],
+            ambient: self.ambient,
+            shininess: self.shininess,
+            use_texture: self.use_texture as u32,
+            mode: self.mode as u32,
+            _padding: [0; 2],
+        }
+    }
+}
+
+pub struct Lighting {
+    pub rig: LightRig,
+
+    pub buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Lighting {
+    pub fn new(device: &wgpu::Device, camera: &Camera) -> Self {
+        let rig = LightRig::default();
+
+        let buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Light Buffer"),
+                contents: bytemuck::cast_slice(&[rig.to_uniform(camera)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST
+            }
+        );
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None },
+                    count: None,
+                }
+            ]
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding()
+                }
+            ]
+        });
+
+        Self {
+            rig,
+            buffer,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    pub fn toggle_texture(&mut self) {
+        self.rig.use_texture = !self.rig.use_texture;
+    }
+
+    pub fn toggle_matcap(&mut self) {
+        self.rig.mode = match self.rig.mode {
+            ShadingMode::Lit => ShadingMode::Matcap,
+            ShadingMode::Matcap => ShadingMode::Lit,
+        };
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.rig.to_uniform(camera)]));
+    }
+}
@@ -0,0 +1,144 @@
+use cgmath::{InnerSpace, Vector3};
+
+use crate::{resources, texture};
+
+// Any PNGs in here, next to the models, are loaded as matcaps
+pub const MATCAP_DIR: &str = "matcaps";
+
+// Width and height of the rendered spheres
+const MATCAP_SIZE: u32 = 256;
+
+/// How a matcap sphere is lit: a diffuse base color under a single key light from the upper left,
+/// a Blinn-Phong highlight and a rim color towards the silhouette. Colors are linear.
+#[derive(Debug, Copy, Clone)]
+pub struct MatcapStyle {
+    pub name: &'static str,
+    pub base: [f32; 3],
+    pub specular: f32,
+    pub shininess: f32,
+    pub rim: [f32; 3],
+    pub ambient: f32,
+}
+
+// Rendered at startup when there are no matcap images, so there's always something to switch between
+pub const MATCAP_STYLES: &[MatcapStyle] = &[
+    MatcapStyle { name: "clay", base: [0.62, 0.55, 0.48], specular: 0.15, shininess: 12.0, rim: [0.15, 0.14, 0.13], ambient: 0.25 },
+    MatcapStyle { name: "red wax", base: [0.55, 0.12, 0.08], specular: 0.45, shininess: 40.0, rim: [0.35, 0.1, 0.08], ambient: 0.25 },
+    MatcapStyle { name: "jade", base: [0.18, 0.5, 0.32], specular: 0.6, shininess: 60.0, rim: [0.2, 0.45, 0.35], ambient: 0.35 },
+    MatcapStyle { name: "chrome", base: [0.35, 0.37, 0.4], specular: 1.0, shininess: 80.0, rim: [0.6, 0.65, 0.7], ambient: 0.1 },
+];
+
+impl MatcapStyle {
+    /// Renders the lit sphere, seen head on and filling the image, as sRGB.
+    pub fn render(&self, size: u32) -> image::RgbaImage {
+        let light = Vector3::new(-0.5, 0.6, 0.65).normalize();
+        let half = (light + Vector3::unit_z()).normalize();
+        image::RgbaImage::from_fn(size, size, |i, j| {
+            let x = (i as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let y = 1.0 - (j as f32 + 0.5) / size as f32 * 2.0;
+            // Outside the sphere the rim continues, so filtering at the edge has nothing dark to pick up
+            let r = (x * x + y * y).sqrt().max(1.0);
+            let (x, y) = (x / r, y / r);
+            let normal = Vector3::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt());
+
+            let diffuse = normal.dot(light).max(0.0);
+            let highlight = normal.dot(half).max(0.0).powf(self.shininess) * self.specular;
+            let rim = (1.0 - normal.z).powi(3);
+            let channel = |c: usize| {
+                let linear = (self.base[c] * (self.ambient + (1.0 - self.ambient) * diffuse) + highlight + self.rim[c] * rim).min(1.0);
+                (linear.powf(1.0 / 2.2) * 255.0).round() as u8
+            };
+            image::Rgba([channel(0), channel(1), channel(2), 255])
+        })
+    }
+}
+
+pub struct Matcap {
+    pub name: String,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Matcap {
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, name: &str, texture: &texture::Texture) -> Self {
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                label: Some(name),
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&texture.view)
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&texture.sampler)
+                    },
+                ]
+            }
+        );
+
+        Self { name: name.to_string(), bind_group }
+    }
+}
+
+pub struct MatcapLibrary {
+    pub matcaps: Vec<Matcap>,
+    pub current: usize,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl MatcapLibrary {
+    /// Loads every PNG in `MATCAP_DIR`, rendering the built in spheres instead when there are none.
+    pub async fn load(device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<Self> {
+        // A matcap is just a texture and a sampler
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Matcap Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false
+                    },
+                    count: None
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None
+                },
+            ]
+        });
+
+        let mut matcaps = Vec::new();
+        for file_name in resources::list_pngs(MATCAP_DIR).await {
+            let name = std::path::Path::new(&file_name).file_stem().map_or(file_name.clone(), |stem| stem.to_string_lossy().into_owned());
+            match resources::load_texture(&file_name, false, device, queue).await {
+                Ok(texture) => matcaps.push(Matcap::new(device, &bind_group_layout, &name, &texture)),
+                Err(e) => log::warn!("Skipping matcap {}: {:#}", file_name, e),
+            }
+        }
+        if !matcaps.is_empty() {
+            return Ok(Self { matcaps, current: 0, bind_group_layout });
+        }
+
+        for style in MATCAP_STYLES {
+            let image = image::DynamicImage::ImageRgba8(style.render(MATCAP_SIZE));
+            let texture = texture::Texture::from_image(device, queue, &image, Some(style.name), false)?;
+            matcaps.push(Matcap::new(device, &bind_group_layout, style.name, &texture));
+        }
+
+        Ok(Self { matcaps, current: 0, bind_group_layout })
+    }
+
+    pub fn current(&self) -> &Matcap {
+        &self.matcaps[self.current]
+    }
+
+    pub fn next(&mut self) {
+        self.current = (self.current + 1) % self.matcaps.len();
+    }
+}
@@ -1,42 +1,225 @@
 use std::ops::Range;
 
-use crate::texture;
+use wgpu::util::DeviceExt;
+
+use crate::{bvh::{Aabb, Bvh}, instance::Instance, multires::Multires, sculpt_mesh::SculptMesh, texture, vertex::ModelVertex};
 
 
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: texture::Texture,
+    // The files the textures were decoded from, None for generated fallbacks
+    pub diffuse_source: Option<texture::EncodedImage>,
+    pub normal_source: Option<texture::EncodedImage>,
+    // Not shaded, only carried from a glTF import through to a glTF export
+    pub metallic_roughness_source: Option<texture::EncodedImage>,
+    // The glTF base color factor, already baked into the diffuse texture and kept for a glTF export
+    pub base_color_factor: [f32; 4],
+    // Defined in one of the model's .mtl files, so an OBJ export can refer to it by name
+    pub in_mtllib: bool,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    pub fn new(
+        device: &wgpu::Device,
+        name: &str,
+        diffuse_texture: &texture::Texture,
+        normal_texture: &texture::Texture,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                label: Some(name),
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view)
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler)
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&normal_texture.view)
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&normal_texture.sampler)
+                    },
+                ]
+            }
+        );
+
+        Self {
+            name: name.to_string(),
+            diffuse_source: None,
+            normal_source: None,
+            metallic_roughness_source: None,
+            base_color_factor: [1.0; 4],
+            in_mtllib: false,
+            bind_group,
+        }
+    }
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
-    pub material: usize // index into materials
+    // How many vertices and indices the buffers have room for, dyntopo grows the mesh into the slack
+    pub vertex_capacity: usize,
+    pub index_capacity: usize,
+    pub material: usize, // index into materials
+    pub sculpt: SculptMesh,
+    pub bvh: Bvh,
+    // Subdivision levels, `sculpt` being the current one. None until the mesh is first subdivided.
+    pub multires: Option<Multires>,
+}
+
+impl Mesh {
+    pub fn new(device: &wgpu::Device, name: &str, mut sculpt: SculptMesh, material: usize) -> Self {
+        let vertices = sculpt.vertices(0..sculpt.num_vertices());
+        sculpt.take_dirty();
+        let bvh = Bvh::build(&sculpt);
+
+        let vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Vertex Buffer", name)),
+                contents: bytemuck::cast_slice(&vertices),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST
+            }
+        );
+        let index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Index Buffer", name)),
+                contents: bytemuck::cast_slice(&sculpt.indices),
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST
+            }
+        );
+
+        Self {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: sculpt.indices.len() as u32,
+            vertex_capacity: sculpt.num_vertices(),
+            index_capacity: sculpt.indices.len(),
+            material,
+            sculpt,
+            bvh,
+            multires: None,
+        }
+    }
+
+    pub fn write_vertices(&self, queue: &wgpu::Queue, range: Range<usize>) {
+        let offset = (range.start * std::mem::size_of::<ModelVertex>()) as wgpu::BufferAddress;
+        let vertices = self.sculpt.vertices(range);
+        queue.write_buffer(&self.vertex_buffer, offset, bytemuck::cast_slice(&vertices));
+    }
+
+    // Topology changed, so the buffers no longer fit and have to be recreated
+    pub fn reupload(&mut self, device: &wgpu::Device) {
+        let vertices = self.sculpt.vertices(0..self.sculpt.num_vertices());
+        self.vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Vertex Buffer", self.name)),
+                contents: bytemuck::cast_slice(&vertices),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST
+            }
+        );
+        self.index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Index Buffer", self.name)),
+                contents: bytemuck::cast_slice(&self.sculpt.indices),
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST
+            }
+        );
+        self.num_elements = self.sculpt.indices.len() as u32;
+        self.vertex_capacity = self.sculpt.num_vertices();
+        self.index_capacity = self.sculpt.indices.len();
+        self.sculpt.take_dirty();
+    }
+
+    /// Uploads a mesh whose topology changed during a stroke. The buffers are only recreated, with room
+    /// to spare, once the mesh outgrows them, otherwise the indices and the dirty vertices are written in place.
+    pub fn sync_topology(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let vertices = self.sculpt.num_vertices();
+        let indices = self.sculpt.indices.len();
+        if vertices > self.vertex_capacity || indices > self.index_capacity {
+            let vertex_capacity = vertices.max(self.vertex_capacity + self.vertex_capacity / 2);
+            let index_capacity = indices.max(self.index_capacity + self.index_capacity / 2);
+            self.vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{:?} Vertex Buffer", self.name)),
+                size: (vertex_capacity * std::mem::size_of::<ModelVertex>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{:?} Index Buffer", self.name)),
+                size: (index_capacity * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.vertex_capacity = vertex_capacity;
+            self.index_capacity = index_capacity;
+            self.sculpt.take_dirty();
+            self.write_vertices(queue, 0..vertices);
+        } else if let Some(range) = self.sculpt.take_dirty() {
+            // Vertices removed from the end leave a dirty range past it
+            let range = range.start.min(vertices)..range.end.min(vertices);
+            if !range.is_empty() {
+                self.write_vertices(queue, range);
+            }
+        }
+        // Writes must be a multiple of 4 bytes, which u32 indices always are
+        if indices > 0 {
+            queue.write_buffer(&self.index_buffer, 0, bytemuck::cast_slice(&self.sculpt.indices));
+        }
+        self.num_elements = indices as u32;
+    }
+
+    /// Swaps in a mesh with different topology, like another multires level.
+    pub fn replace_sculpt(&mut self, device: &wgpu::Device, sculpt: SculptMesh) {
+        self.sculpt = sculpt;
+        self.bvh = Bvh::build(&self.sculpt);
+        self.reupload(device);
+    }
+
+    // Upload whatever the brushes touched since the last sync
+    pub fn sync(&mut self, queue: &wgpu::Queue) {
+        if let Some(range) = self.sculpt.take_dirty() {
+            self.write_vertices(queue, range);
+        }
+    }
 }
 
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    // Material libraries named by the source file, relative to it, so exports can point back at them
+    pub mtllib: Vec<String>,
+    // Placement the file asked for, empty when it has none and the app decides
+    pub instances: Vec<Instance>,
+}
+
+impl Model {
+    pub fn bounds(&self) -> Aabb {
+        self.meshes.iter().fold(Aabb::empty(), |bounds, mesh| bounds.union(&mesh.bvh.bounds()))
+    }
 }
 
 pub trait DrawModel<'a> {
-    fn draw_mesh(&mut self, mesh: &'a Mesh, material: &'a Material, camera_bind_group: &'a wgpu::BindGroup);
     fn draw_mesh_instanced(&mut self, mesh: &'a Mesh, material: &'a Material, instances: Range<u32>, camera_bind_group: &'a wgpu::BindGroup);
 
-    fn draw_model(&mut self, model: &'a Model, camera_bind_group: &'a wgpu::BindGroup);
     fn draw_model_instanced(&mut self, model: &'a Model, instances: Range<u32>, camera_bind_group: &'a wgpu::BindGroup);
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where 'b: 'a,
 {
-    fn draw_mesh(&mut self, mesh: &'b Mesh, material: &'a Material, camera_bind_group: &'a wgpu::BindGroup) {
-        self.draw_mesh_instanced(mesh, material, 0..1, camera_bind_group);
-    }
-
     fn draw_mesh_instanced(&mut self, mesh: &'b Mesh, material: &'a Material,  instances: Range<u32>, camera_bind_group: &'a wgpu::BindGroup) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
@@ -47,10 +230,6 @@ where 'b: 'a,
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
 
-    fn draw_model(&mut self, model: &'b Model, camera_bind_group: &'b wgpu::BindGroup) {
-        self.draw_model_instanced(model, 0..1, camera_bind_group);
-    }
-
     fn draw_model_instanced(&mut self, model: &'b Model, instances: Range<u32>, camera_bind_group: &'b wgpu::BindGroup) {
         for mesh in &model.meshes {
             let material = &model.materials[mesh.material];
@@ -0,0 +1,325 @@
+use cgmath::{EuclideanSpace, Matrix, Vector3, Zero};
+
+use crate::{sculpt_mesh::SculptMesh, subdivision::{PolyMesh, Scheme}};
+
+// Subdividing past this many faces would run out of memory before it got anywhere useful
+pub const MAX_FACES: usize = 4_000_000;
+
+#[derive(Clone)]
+struct Level {
+    mesh: PolyMesh,
+    // Offsets from the subdivided level below, in its vertex frames so they ride along when
+    // the level below is reshaped. Empty for the base level.
+    details: Vec<Vector3<f32>>,
+}
+
+/// A multires stack as projects save it: the base level, and for every finer level what it adds on top
+/// of subdividing the one below. The levels themselves are rebuilt from these when loading.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiresSnapshot {
+    pub scheme: Scheme,
+    pub base: PolyMesh,
+    pub details: Vec<Vec<Vector3<f32>>>,
+    pub current: usize,
+}
+
+/// A base mesh and ever finer subdivisions of it, any of which can be sculpted.
+/// Only the level being sculpted lives in a `SculptMesh`, the others catch up when leaving it:
+/// finer levels are subdivided again from it with their details on top, and coarser levels
+/// move along with the vertices they share with it.
+#[derive(Clone)]
+pub struct Multires {
+    pub scheme: Scheme,
+    levels: Vec<Level>,
+    current: usize,
+    // Which vertex of the current level every vertex of its SculptMesh is
+    sculpt_vertices: Vec<usize>,
+}
+
+impl Multires {
+    /// Starts on `base`, subdividing it with Loop if it is all triangles and Catmull-Clark otherwise.
+    /// Also returns the base level to sculpt, which replaces whatever mesh `base` came from.
+    pub fn new(base: PolyMesh) -> (Self, SculptMesh) {
+        let (sculpt, sculpt_vertices) = base.to_sculpt();
+        let multires = Self {
+            scheme: Scheme::for_mesh(&base),
+            levels: vec![Level { mesh: base, details: Vec::new() }],
+            current: 0,
+            sculpt_vertices,
+        };
+        (multires, sculpt)
+    }
+
+    pub fn from_sculpt(sculpt: &SculptMesh) -> (Self, SculptMesh) {
+        Self::new(PolyMesh::from_sculpt(sculpt))
+    }
+
+    /// Rebuilds a saved stack, with `sculpt` as its current level.
+    /// None when the snapshot doesn't fit together or doesn't match `sculpt`.
+    pub fn from_snapshot(snapshot: MultiresSnapshot, sculpt: &SculptMesh) -> Option<Self> {
+        let mut multires = Self {
+            scheme: snapshot.scheme,
+            levels: vec![Level { mesh: snapshot.base, details: Vec::new() }],
+            current: 0,
+            sculpt_vertices: Vec::new(),
+        };
+        for details in snapshot.details {
+            let mesh = multires.levels[multires.levels.len() - 1].mesh.subdivide(multires.scheme);
+            if details.len() != mesh.num_vertices() {
+                return None;
+            }
+            multires.levels.push(Level { mesh, details });
+            multires.apply_details(multires.levels.len() - 1);
+        }
+
+        multires.current = snapshot.current.min(multires.levels.len() - 1);
+        let (_, sculpt_vertices) = multires.levels[multires.current].mesh.to_sculpt();
+        if sculpt_vertices.len() != sculpt.num_vertices() {
+            return None;
+        }
+        multires.sculpt_vertices = sculpt_vertices;
+        Some(multires)
+    }
+
+    /// What saving needs to rebuild the stack, with the sculpting on `sculpt` taken in first.
+    pub fn snapshot(&self, sculpt: &SculptMesh) -> MultiresSnapshot {
+        let mut stored = self.clone();
+        stored.store(sculpt);
+        let mut levels = stored.levels.into_iter();
+        let base = levels.next().map(|level| level.mesh).unwrap_or_else(|| PolyMesh::from_sculpt(sculpt));
+        MultiresSnapshot {
+            scheme: self.scheme,
+            base,
+            details: levels.map(|level| level.details).collect(),
+            current: self.current,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    fn next_face_count(&self) -> usize {
+        let top = &self.levels[self.levels.len() - 1].mesh;
+        match self.scheme {
+            // One quad per corner
+            Scheme::CatmullClark => top.corners.len(),
+            Scheme::Loop => top.num_faces() * 4,
+        }
+    }
+
+    /// Takes the sculpted current level back into the stack and updates every other level from it.
+    pub fn store(&mut self, sculpt: &SculptMesh) {
+        // Anything that changed the topology under the stack can't be mapped back onto it
+        if sculpt.num_vertices() != self.sculpt_vertices.len() {
+            log::warn!("Multires levels lost the sculpting done since the mesh topology changed");
+            return;
+        }
+
+        // Brushes move the copies of a vertex split at a uv seam separately, they're averaged back together
+        let level = &mut self.levels[self.current].mesh;
+        let mut sums = vec![(Vector3::zero(), 0); level.num_vertices()];
+        for (&v, p) in self.sculpt_vertices.iter().zip(&sculpt.positions) {
+            sums[v].0 += p.to_vec();
+            sums[v].1 += 1;
+        }
+        let deltas = sums.into_iter().zip(&mut level.positions).map(|((sum, count), position)| {
+            if count == 0 {
+                return Vector3::zero();
+            }
+            let moved = sum / count as f32;
+            let delta = moved - position.to_vec();
+            *position = cgmath::Point3::from_vec(moved);
+            delta
+        }).collect::<Vec<_>>();
+
+        // Coarser levels keep their vertex indices in every finer one, so each moves like its counterpart
+        for level in &mut self.levels[..self.current] {
+            for (position, delta) in level.mesh.positions.iter_mut().zip(&deltas) {
+                *position += *delta;
+            }
+        }
+        for l in 1..=self.current {
+            self.measure_details(l);
+        }
+        for l in self.current + 1..self.levels.len() {
+            self.apply_details(l);
+        }
+    }
+
+    // What level `l` adds on top of the subdivided level below it
+    fn measure_details(&mut self, l: usize) {
+        let smooth = self.levels[l - 1].mesh.subdivide(self.scheme);
+        let frames = smooth.frames();
+        let level = &mut self.levels[l];
+        level.details = level.mesh.positions.iter().zip(&smooth.positions).zip(&frames)
+            .map(|((position, smooth), frame)| frame.transpose() * (position - smooth))
+            .collect();
+    }
+
+    // Rebuilds level `l` from the level below it and its details
+    fn apply_details(&mut self, l: usize) {
+        let smooth = self.levels[l - 1].mesh.subdivide(self.scheme);
+        let frames = smooth.frames();
+        let level = &mut self.levels[l];
+        for (v, position) in level.mesh.positions.iter_mut().enumerate() {
+            let detail = level.details.get(v).copied().unwrap_or(Vector3::zero());
+            *position = smooth.positions[v] + frames[v] * detail;
+        }
+    }
+
+    /// Stores `sculpt` and switches to `level`, returning the mesh to sculpt it with.
+    pub fn set_level(&mut self, sculpt: &SculptMesh, level: usize) -> SculptMesh {
+        self.store(sculpt);
+        self.current = level.min(self.levels.len() - 1);
+        let (sculpt, sculpt_vertices) = self.levels[self.current].mesh.to_sculpt();
+        self.sculpt_vertices = sculpt_vertices;
+        sculpt
+    }
+
+    /// One level finer, subdividing once more when already at the finest.
+    /// None when that would go over `MAX_FACES`.
+    pub fn finer(&mut self, sculpt: &SculptMesh) -> Option<SculptMesh> {
+        if self.current + 1 == self.levels.len() {
+            if self.next_face_count() > MAX_FACES {
+                log::warn!("Not subdividing past {} faces", MAX_FACES);
+                return None;
+            }
+            // Stored first so the new level starts from the latest sculpting
+            self.store(sculpt);
+            let mesh = self.levels[self.current].mesh.subdivide(self.scheme);
+            let details = vec![Vector3::zero(); mesh.num_vertices()];
+            self.levels.push(Level { mesh, details });
+            self.current += 1;
+            let (sculpt, sculpt_vertices) = self.levels[self.current].mesh.to_sculpt();
+            self.sculpt_vertices = sculpt_vertices;
+            return Some(sculpt);
+        }
+        Some(self.set_level(sculpt, self.current + 1))
+    }
+
+    pub fn coarser(&mut self, sculpt: &SculptMesh) -> Option<SculptMesh> {
+        if self.current == 0 {
+            return None;
+        }
+        Some(self.set_level(sculpt, self.current - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use cgmath::{InnerSpace, Point3};
+
+    use super::*;
+    use crate::resources;
+
+    // Eight corners and six quads, so Catmull-Clark has nothing to triangulate
+    fn quad_cube() -> PolyMesh {
+        let positions = (0..8)
+            .map(|i| Point3::new((i & 1) as f32 * 2.0 - 1.0, (i >> 1 & 1) as f32 * 2.0 - 1.0, (i >> 2 & 1) as f32 * 2.0 - 1.0))
+            .collect::<Vec<_>>();
+        let indices = [0, 2, 3, 1, 4, 5, 7, 6, 0, 1, 5, 4, 2, 6, 7, 3, 0, 4, 6, 2, 1, 3, 7, 5];
+        PolyMesh::from_indexed(&positions, &[], &[], &indices, &[4; 6])
+    }
+
+    fn num_edges(mesh: &PolyMesh) -> usize {
+        let mut edges = HashSet::new();
+        for f in 0..mesh.num_faces() {
+            let face = mesh.face(f);
+            for (i, &a) in face.iter().enumerate() {
+                let b = face[(i + 1) % face.len()];
+                edges.insert((a.min(b), a.max(b)));
+            }
+        }
+        edges.len()
+    }
+
+    fn assert_close(a: &[Point3<f32>], b: &[Point3<f32>]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn catmull_clark_adds_edge_and_face_points() {
+        let cube = quad_cube();
+        assert_eq!(Scheme::for_mesh(&cube), Scheme::CatmullClark);
+        assert_eq!((cube.num_vertices(), num_edges(&cube), cube.num_faces()), (8, 12, 6));
+
+        // A vertex per vertex, edge and face, a quad per corner
+        let once = cube.subdivide(Scheme::CatmullClark);
+        assert_eq!((once.num_vertices(), once.num_faces()), (8 + 12 + 6, 24));
+        assert_eq!(num_edges(&once), 48);
+        let twice = once.subdivide(Scheme::CatmullClark);
+        assert_eq!((twice.num_vertices(), twice.num_faces()), (26 + 48 + 24, 96));
+    }
+
+    #[test]
+    fn loop_adds_edge_points() {
+        let cube = PolyMesh::from_sculpt(&resources::test_mesh("cube.obj"));
+        assert_eq!(Scheme::for_mesh(&cube), Scheme::Loop);
+
+        // A vertex per vertex and edge, four triangles per triangle
+        let mut mesh = cube;
+        for _ in 0..2 {
+            let next = mesh.subdivide(Scheme::Loop);
+            assert_eq!(next.num_vertices(), mesh.num_vertices() + num_edges(&mesh));
+            assert_eq!(next.num_faces(), mesh.num_faces() * 4);
+            assert!(next.is_triangles());
+            mesh = next;
+        }
+    }
+
+    #[test]
+    fn details_survive_going_coarser_and_finer() {
+        let (mut multires, base) = Multires::new(quad_cube());
+        let mut sculpt = multires.finer(&base).unwrap();
+        // Pull out a face point and push in an edge point, neither of which the base level has
+        for (v, offset) in [(sculpt.num_vertices() - 1, 0.3), (sculpt.num_vertices() - 7, -0.2)] {
+            let normal = sculpt.positions[v].to_vec().normalize();
+            sculpt.positions[v] += normal * offset;
+        }
+        let sculpted = sculpt.positions.clone();
+
+        let finest = multires.finer(&sculpt).unwrap();
+        let middle = multires.coarser(&finest).unwrap();
+        assert_close(&middle.positions, &sculpted);
+        let coarsest = multires.coarser(&middle).unwrap();
+        assert_eq!(multires.coarser(&coarsest).map(|_| ()), None);
+        let middle = multires.finer(&coarsest).unwrap();
+        assert_close(&middle.positions, &sculpted);
+        assert_eq!(multires.num_levels(), 3);
+    }
+
+    #[test]
+    fn snapshots_rebuild_every_level() {
+        let (mut multires, base) = Multires::new(quad_cube());
+        let mut sculpt = multires.finer(&base).unwrap();
+        sculpt.positions[20] += Vector3::new(0.1, 0.2, 0.3);
+        let sculpt = multires.finer(&sculpt).unwrap();
+        let sculpt = multires.coarser(&sculpt).unwrap();
+        multires.store(&sculpt);
+
+        let snapshot = multires.snapshot(&sculpt);
+        assert_eq!((snapshot.details.len(), snapshot.current), (2, 1));
+        let restored = Multires::from_snapshot(snapshot.clone(), &sculpt).unwrap();
+        assert_eq!((restored.num_levels(), restored.current()), (3, 1));
+        for (restored, level) in restored.levels.iter().zip(&multires.levels) {
+            assert_eq!(restored.mesh.corners, level.mesh.corners);
+            assert_close(&restored.mesh.positions, &level.mesh.positions);
+        }
+
+        // A mesh that isn't the current level, or details that don't fit their level, are refused
+        assert!(Multires::from_snapshot(snapshot.clone(), &base).is_none());
+        let mut short = snapshot;
+        short.details[1].pop();
+        assert!(Multires::from_snapshot(short, &sculpt).is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use std::io::{self, Write};
+
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Transform};
+
+use crate::sculpt_mesh::SculptMesh;
+
+/// One mesh to write as an OBJ group.
+pub struct ObjGroup<'a> {
+    pub name: &'a str,
+    pub mesh: &'a SculptMesh,
+    // Name of a material in one of the libraries given to `write_obj`
+    pub material: Option<&'a str>,
+}
+
+/// Writes the meshes as Wavefront OBJ, one group per mesh.
+/// With no transforms every mesh is written once in its own space. Otherwise every mesh
+/// is written once per transform, baked into world space, in groups suffixed with the instance.
+/// Vertices share one index for position, uv and normal, so the file loads back vertex for vertex.
+pub fn write_obj<W: Write>(
+    writer: &mut W,
+    groups: &[ObjGroup],
+    transforms: &[Matrix4<f32>],
+    mtllib: &[String],
+) -> io::Result<()> {
+    writeln!(writer, "# web_sculpt")?;
+    for library in mtllib {
+        writeln!(writer, "mtllib {}", library)?;
+    }
+
+    let identity = [Matrix4::identity()];
+    let baked = !transforms.is_empty();
+    let transforms = if baked { transforms } else { &identity[..] };
+
+    // OBJ indices are 1 based and count every vertex written so far
+    let mut offset = 1;
+    for (instance, transform) in transforms.iter().enumerate() {
+        let normal_matrix = normal_matrix(transform);
+        for group in groups {
+            let mesh = group.mesh;
+            if baked {
+                writeln!(writer, "g {}_{}", group.name, instance)?;
+            } else {
+                writeln!(writer, "g {}", group.name)?;
+            }
+            if let Some(material) = group.material {
+                writeln!(writer, "usemtl {}", material)?;
+            }
+
+            for position in &mesh.positions {
+                let p = transform.transform_point(*position);
+                writeln!(writer, "v {} {} {}", p.x, p.y, p.z)?;
+            }
+            // The loader flips v so textures read top down, flip it back
+            for uv in &mesh.uvs {
+                writeln!(writer, "vt {} {}", uv[0], 1.0 - uv[1])?;
+            }
+            for normal in &mesh.normals {
+                let n = normal_matrix * normal;
+                let n = if n.magnitude2() > 0.0 { n.normalize() } else { n };
+                writeln!(writer, "vn {} {} {}", n.x, n.y, n.z)?;
+            }
+            for face in 0..mesh.num_faces() {
+                let [a, b, c] = mesh.face(face).map(|v| v + offset);
+                writeln!(writer, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+            }
+            offset += mesh.num_vertices();
+        }
+    }
+    Ok(())
+}
+
+// Inverse transpose of the upper 3x3, so normals stay perpendicular under non-uniform scale
+fn normal_matrix(transform: &Matrix4<f32>) -> Matrix3<f32> {
+    let m = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+    m.invert().map(|m| m.transpose()).unwrap_or(m)
+}
+
+/// The material libraries an OBJ refers to, as written in the file.
+pub fn mtllib_names(obj_text: &str) -> Vec<String> {
+    // One line can name several libraries
+    obj_text.lines()
+        .map(|line| line.split_whitespace())
+        .filter_map(|mut words| (words.next() == Some("mtllib")).then_some(words))
+        .flatten()
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources;
+
+    // A folder of the test's own, removed again even when the test fails
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("web_sculpt_{}_{}", name, std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn cube_survives_export_and_reimport() {
+        let scene = pollster::block_on(resources::parse_obj("cube.obj", false)).unwrap();
+        let groups = scene.meshes.iter().map(|mesh| ObjGroup {
+            name: &mesh.name,
+            mesh: &mesh.sculpt,
+            material: scene.materials.get(mesh.material).map(|m| m.name.as_str()),
+        }).collect::<Vec<_>>();
+        let mut written = Vec::new();
+        write_obj(&mut written, &groups, &[], &scene.mtllib).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        assert_eq!(text.matches("usemtl ").count(), 1);
+
+        // With a copy of the bundled .mtl next to it, so the library it names still resolves
+        let dir = TempDir::new("obj_round_trip");
+        let models = std::path::Path::new(env!("OUT_DIR")).join("models");
+        for name in &scene.mtllib {
+            std::fs::copy(models.join(name), dir.0.join(name)).unwrap();
+        }
+        let path = dir.0.join("cube_export.obj");
+        std::fs::write(&path, text).unwrap();
+        let reloaded = pollster::block_on(resources::parse_obj(&path.to_string_lossy(), false)).unwrap();
+
+        assert!(reloaded.materials_error.is_none());
+        assert_eq!(reloaded.mtllib, scene.mtllib);
+        assert_eq!(reloaded.meshes.len(), scene.meshes.len());
+        for (before, after) in scene.meshes.iter().zip(&reloaded.meshes) {
+            assert_eq!(reloaded.materials[after.material].name, scene.materials[before.material].name);
+            let (before, after) = (&before.sculpt, &after.sculpt);
+            assert_eq!(after.indices, before.indices);
+            for v in 0..before.num_vertices() {
+                assert!((after.positions[v] - before.positions[v]).magnitude() < 1e-5);
+                // The file's normals are rounded off unit length, they're written normalized
+                assert!((after.normals[v] - before.normals[v].normalize()).magnitude() < 1e-5);
+                assert!((after.uvs[v][0] - before.uvs[v][0]).abs() < 1e-5 && (after.uvs[v][1] - before.uvs[v][1]).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn groups_without_a_material_have_no_usemtl() {
+        let scene = pollster::block_on(resources::parse_obj("tests/fixtures/missing_mtl.obj", false)).unwrap();
+        let groups = [ObjGroup { name: "mesh", mesh: &scene.meshes[0].sculpt, material: None }];
+        let mut written = Vec::new();
+        write_obj(&mut written, &groups, &[], &scene.mtllib).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        assert!(text.contains("mtllib does_not_exist.mtl"));
+        assert!(!text.contains("usemtl"));
+    }
+
+    #[test]
+    fn every_library_on_an_mtllib_line_is_named() {
+        let text = "# mtllib commented.mtl\nmtllib a.mtl  b.mtl\n  mtllib\tc.mtl\nmtllibx d.mtl\nmtllib\n";
+        assert_eq!(mtllib_names(text), ["a.mtl", "b.mtl", "c.mtl"]);
+    }
+}
@@ -0,0 +1,279 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, Point3, SquareMatrix, Vector3, Vector4};
+
+use crate::{bvh::Bvh, camera::Camera, instance::Instance, model::Model, sculpt_mesh::SculptMesh};
+
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Hit {
+    pub point: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+    pub mesh: usize,
+    pub face: usize,
+    pub barycentric: [f32; 3],
+    pub instance: usize,
+}
+
+impl Ray {
+    // Cursor is in physical pixels with the origin at the top left, like winit reports it
+    pub fn from_screen(camera: &Camera, cursor: [f32; 2], size: [f32; 2]) -> Option<Self> {
+        let inverse_vp = camera.build_vp_matrix().invert()?;
+        let ndc_x = 2.0 * cursor[0] / size[0] - 1.0;
+        let ndc_y = 1.0 - 2.0 * cursor[1] / size[1];
+
+        let unproject = |z: f32| {
+            let p = inverse_vp * Vector4::new(ndc_x, ndc_y, z, 1.0);
+            Point3::new(p.x / p.w, p.y / p.w, p.z / p.w)
+        };
+        let near = unproject(0.0);
+        let far = unproject(1.0);
+
+        Some(Self {
+            origin: near,
+            direction: (far - near).normalize(),
+        })
+    }
+
+    // The direction is deliberately not renormalized so `t` stays comparable between spaces
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+        let origin = matrix * self.origin.to_homogeneous();
+        let direction = matrix * self.direction.extend(0.0);
+        Self {
+            origin: Point3::from_homogeneous(origin),
+            direction: direction.truncate(),
+        }
+    }
+
+    pub fn at(&self, t: f32) -> Point3<f32> {
+        self.origin + self.direction * t
+    }
+}
+
+// Möller–Trumbore, two sided. Returns (t, u, v) where u and v weight `b` and `c`.
+pub fn intersect_triangle(ray: &Ray, a: Point3<f32>, b: Point3<f32>, c: Point3<f32>) -> Option<(f32, f32, f32)> {
+    const EPSILON: f32 = 1e-8;
+
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = ray.direction.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - a;
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = ray.direction.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(q) * inv_det;
+    if t <= EPSILON {
+        return None;
+    }
+
+    Some((t, u, v))
+}
+
+// Interpolated vertex normal, falling back to the face normal when the mesh has none
+pub fn surface_normal(mesh: &SculptMesh, face: usize, barycentric: [f32; 3]) -> Vector3<f32> {
+    let [a, b, c] = mesh.face(face);
+    let interpolated = mesh.normals[a] * barycentric[0]
+        + mesh.normals[b] * barycentric[1]
+        + mesh.normals[c] * barycentric[2];
+
+    if interpolated.magnitude2() > 1e-12 {
+        interpolated.normalize()
+    } else {
+        let pa = mesh.positions[a];
+        (mesh.positions[b] - pa).cross(mesh.positions[c] - pa).normalize()
+    }
+}
+
+pub fn pick(ray: &Ray, model: &Model, instances: &[Instance]) -> Option<Hit> {
+    pick_meshes(ray, model.meshes.iter().map(|mesh| (&mesh.sculpt, &mesh.bvh)), instances)
+}
+
+/// `pick` over bare meshes and their BVHs, so it works without any GPU buffers.
+pub fn pick_meshes<'a, I>(ray: &Ray, meshes: I, instances: &[Instance]) -> Option<Hit>
+where
+    I: Iterator<Item = (&'a SculptMesh, &'a Bvh)> + Clone,
+{
+    let mut closest: Option<Hit> = None;
+
+    for (instance_index, instance) in instances.iter().enumerate() {
+        let model_matrix = instance.model_matrix();
+        let inverse = match model_matrix.invert() {
+            Some(inverse) => inverse,
+            None => continue,
+        };
+        let local_ray = ray.transform(&inverse);
+
+        for (mesh_index, (sculpt, bvh)) in meshes.clone().enumerate() {
+            let (face, t, barycentric) = match bvh.ray_cast(sculpt, &local_ray) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            if closest.is_some_and(|c| c.distance <= t) {
+                continue;
+            }
+
+            let local_normal = surface_normal(sculpt, face, barycentric);
+            let normal = (inverse.transpose() * local_normal.extend(0.0)).truncate().normalize();
+
+            closest = Some(Hit {
+                point: ray.at(t),
+                normal,
+                distance: t,
+                mesh: mesh_index,
+                face,
+                barycentric,
+                instance: instance_index,
+            });
+        }
+    }
+
+    closest
+}
+
+// Inverse of `Ray::from_screen`, returns None for points behind the camera
+pub fn project_to_screen(camera: &Camera, point: Point3<f32>, size: [f32; 2]) -> Option<[f32; 2]> {
+    let clip = camera.build_vp_matrix() * point.to_homogeneous();
+    if clip.w <= 0.0 {
+        return None;
+    }
+    let ndc = clip.truncate() / clip.w;
+    Some([
+        (ndc.x + 1.0) * 0.5 * size[0],
+        (1.0 - ndc.y) * 0.5 * size[1],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{EuclideanSpace, Quaternion, Rotation3, Zero};
+
+    use super::*;
+    use crate::{camera::Projection, resources};
+
+    const SIZE: [f32; 2] = [800.0, 600.0];
+
+    fn camera(eye: Point3<f32>, target: Point3<f32>) -> Camera {
+        Camera {
+            eye,
+            target,
+            up: Vector3::unit_y(),
+            aspect: SIZE[0] / SIZE[1],
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+            projection: Projection::Perspective,
+        }
+    }
+
+    fn cube() -> (SculptMesh, Bvh) {
+        let mesh = resources::test_mesh("cube.obj");
+        let bvh = Bvh::build(&mesh);
+        (mesh, bvh)
+    }
+
+    fn identity() -> Instance {
+        Instance { position: Vector3::zero(), rotation: Quaternion::from_angle_y(cgmath::Deg(0.0)), scale: Vector3::new(1.0, 1.0, 1.0) }
+    }
+
+    fn pick_cube(camera: &Camera, cursor: [f32; 2], instances: &[Instance]) -> Option<Hit> {
+        let (mesh, bvh) = cube();
+        let ray = Ray::from_screen(camera, cursor, SIZE).unwrap();
+        pick_meshes(&ray, std::iter::once((&mesh, &bvh)), instances)
+    }
+
+    #[test]
+    fn screen_center_hits_the_front_face() {
+        let (mesh, _) = cube();
+        let camera = camera(Point3::new(0.0, 0.0, 5.0), Point3::origin());
+        let ray = Ray::from_screen(&camera, [SIZE[0] / 2.0, SIZE[1] / 2.0], SIZE).unwrap();
+        assert!((ray.direction - -Vector3::unit_z()).magnitude() < 1e-5, "{:?}", ray);
+
+        let hit = pick_cube(&camera, [SIZE[0] / 2.0, SIZE[1] / 2.0], &[identity()]).unwrap();
+        assert!((hit.point - Point3::new(0.0, 0.0, 1.0)).magnitude() < 1e-4, "{:?}", hit.point);
+        assert!((hit.normal - Vector3::unit_z()).magnitude() < 1e-4, "{:?}", hit.normal);
+        assert!((hit.distance - (4.0 - camera.znear)).abs() < 1e-3);
+        assert_eq!((hit.mesh, hit.instance), (0, 0));
+
+        // The face is one of the two triangles of the flat front quad, and the barycentrics land on the hit
+        let corners = mesh.face(hit.face).map(|v| mesh.positions[v]);
+        assert!(corners.iter().all(|p| p.z == 1.0), "{:?}", corners);
+        assert!(hit.barycentric.iter().all(|&w| (0.0..=1.0).contains(&w)));
+        assert!((hit.barycentric.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        let interpolated = corners.iter().zip(hit.barycentric)
+            .fold(Vector3::zero(), |sum, (p, w)| sum + p.to_vec() * w);
+        assert!((interpolated - hit.point.to_vec()).magnitude() < 1e-4);
+    }
+
+    #[test]
+    fn hits_go_through_the_instance_transform() {
+        // A plain cube off to the left and one moved right and doubled in size, looked at head on
+        let left = Instance { position: Vector3::new(-3.0, 0.0, 0.0), ..identity() };
+        let moved = Instance { position: Vector3::new(3.0, 0.0, 0.0), scale: Vector3::new(2.0, 2.0, 2.0), ..identity() };
+        let instances = [left, moved];
+        let center = [SIZE[0] / 2.0, SIZE[1] / 2.0];
+
+        let looking = camera(Point3::new(3.0, 1.5, 10.0), Point3::new(3.0, 1.5, 0.0));
+        let hit = pick_cube(&looking, center, &instances).unwrap();
+        assert_eq!(hit.instance, 1);
+        assert!((hit.point - Point3::new(3.0, 1.5, 2.0)).magnitude() < 1e-4, "{:?}", hit.point);
+        // A uniform scale and a translation leave the cube's own normal as it is
+        let (mesh, _) = cube();
+        assert!((hit.normal - surface_normal(&mesh, hit.face, hit.barycentric)).magnitude() < 1e-5);
+        assert!(hit.normal.z > 0.99, "{:?}", hit.normal);
+        assert!((hit.distance - (8.0 - looking.znear)).abs() < 1e-3);
+
+        // At the same height the unscaled cube is already passed over
+        let looking = camera(Point3::new(-3.0, 1.5, 10.0), Point3::new(-3.0, 1.5, 0.0));
+        assert!(pick_cube(&looking, center, &instances).is_none());
+    }
+
+    #[test]
+    fn rays_past_the_mesh_miss() {
+        let camera = camera(Point3::new(0.0, 0.0, 5.0), Point3::origin());
+        assert!(pick_cube(&camera, [0.0, 0.0], &[identity()]).is_none());
+        assert!(pick_cube(&camera, [SIZE[0], SIZE[1] / 2.0], &[identity()]).is_none());
+        // Facing away from the cube
+        let away = self::camera(Point3::new(0.0, 0.0, 5.0), Point3::new(0.0, 0.0, 10.0));
+        assert!(pick_cube(&away, [SIZE[0] / 2.0, SIZE[1] / 2.0], &[identity()]).is_none());
+    }
+
+    #[test]
+    fn projecting_to_screen_inverts_unprojecting() {
+        let mut camera = camera(Point3::new(2.0, 1.5, 4.0), Point3::new(0.2, -0.1, 0.0));
+        for projection in [Projection::Perspective, Projection::Orthographic] {
+            camera.projection = projection;
+            for point in [Point3::new(0.3, -0.2, 1.0), Point3::new(-0.9, 0.7, -0.4), camera.target] {
+                let screen = project_to_screen(&camera, point, SIZE).unwrap();
+                let ray = Ray::from_screen(&camera, screen, SIZE).unwrap();
+                // The ray back through the pixel passes through the point
+                let along = (point - ray.origin).dot(ray.direction);
+                assert!(along > 0.0);
+                assert!((ray.at(along) - point).magnitude() < 1e-3, "{:?} at {:?}", point, projection);
+            }
+            let center = project_to_screen(&camera, camera.target, SIZE).unwrap();
+            assert!((center[0] - SIZE[0] / 2.0).abs() < 1e-2 && (center[1] - SIZE[1] / 2.0).abs() < 1e-2);
+        }
+
+        // Behind a perspective camera there is no pixel to land on
+        camera.projection = Projection::Perspective;
+        assert!(project_to_screen(&camera, Point3::new(4.0, 3.0, 8.0), SIZE).is_none());
+    }
+}
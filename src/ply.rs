@@ -0,0 +1,365 @@
+use std::io::{self, Write};
+
+use anyhow::{bail, Context};
+use cgmath::{Point3, Vector3, Zero};
+
+use crate::sculpt_mesh::SculptMesh;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => bail!("unknown property type {}", name),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    // What a full intensity color is stored as, so colors come out in 0..1
+    fn color_scale(self) -> f64 {
+        match self {
+            Self::U8 | Self::I8 => 255.0,
+            Self::U16 | Self::I16 => 65535.0,
+            Self::I32 | Self::U32 => u32::MAX as f64,
+            Self::F32 | Self::F64 => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Property {
+    Scalar { name: String, ty: Scalar },
+    List { name: String, count: Scalar, item: Scalar },
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// Reads values one at a time from either whitespace separated text or packed binary
+struct Values<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    position: usize,
+    tokens: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl Values<'_> {
+    fn read(&mut self, ty: Scalar) -> anyhow::Result<f64> {
+        if self.format == Format::Ascii {
+            let token = self.tokens.next().context("PLY data ends early")?;
+            return token.parse::<f64>().with_context(|| format!("bad PLY value {}", token));
+        }
+
+        let size = ty.size();
+        let bytes = self.bytes.get(self.position..self.position + size).context("PLY data ends early")?;
+        self.position += size;
+        let mut raw = [0u8; 8];
+        raw[..size].copy_from_slice(bytes);
+        if self.format == Format::BinaryBigEndian {
+            raw[..size].reverse();
+        }
+        let value = match ty {
+            Scalar::I8 => raw[0] as i8 as f64,
+            Scalar::U8 => raw[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([raw[0], raw[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([raw[0], raw[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes(raw),
+        };
+        Ok(value)
+    }
+}
+
+fn parse_header(text: &str) -> anyhow::Result<(Format, Vec<Element>)> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("ply") {
+        bail!("not a PLY file");
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::BinaryLittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(Format::BinaryBigEndian),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().with_context(|| format!("bad element count {}", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements.last_mut()
+                .context("property before any element")?
+                .properties.push(Property::List { name: name.to_string(), count: Scalar::parse(count)?, item: Scalar::parse(item)? }),
+            ["property", ty, name] => elements.last_mut()
+                .context("property before any element")?
+                .properties.push(Property::Scalar { name: name.to_string(), ty: Scalar::parse(ty)? }),
+            ["end_header"] => break,
+            // comment, obj_info and blank lines
+            _ => {}
+        }
+    }
+    Ok((format.context("PLY header has no format")?, elements))
+}
+
+/// Reads an ASCII or binary PLY with positions, and optionally normals, texture coordinates,
+/// vertex colors and polygon faces, which are fanned into triangles. Other elements are skipped.
+pub fn read(bytes: &[u8]) -> anyhow::Result<SculptMesh> {
+    const END_HEADER: &[u8] = b"end_header";
+    let header_end = bytes.windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .context("PLY header never ends")?;
+    let body_start = bytes[header_end..].iter()
+        .position(|&b| b == b'\n')
+        .map(|i| header_end + i + 1)
+        .unwrap_or(bytes.len());
+
+    let header = std::str::from_utf8(&bytes[..body_start]).context("PLY header is not text")?;
+    let (format, elements) = parse_header(header)?;
+    let body = &bytes[body_start..];
+    let mut values = Values {
+        format,
+        bytes: body,
+        position: 0,
+        tokens: if format == Format::Ascii {
+            std::str::from_utf8(body).context("ASCII PLY data is not text")?.split_ascii_whitespace()
+        } else {
+            "".split_ascii_whitespace()
+        },
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+
+    for element in &elements {
+        let is_vertex = element.name == "vertex";
+        let is_face = element.name == "face";
+        let has = |names: &[&str]| element.properties.iter()
+            .any(|p| matches!(p, Property::Scalar { name, .. } if names.contains(&name.as_str())));
+        let has_colors = is_vertex && has(&["red"]);
+
+        for number in 0..element.count {
+            let mut position = [0.0; 3];
+            let mut normal = [0.0; 3];
+            let mut uv = [0.0; 2];
+            let mut color = [1.0; 4];
+
+            for property in &element.properties {
+                match property {
+                    Property::Scalar { name, ty } => {
+                        let value = values.read(*ty)?;
+                        if !is_vertex {
+                            continue;
+                        }
+                        match name.as_str() {
+                            "x" => position[0] = value as f32,
+                            "y" => position[1] = value as f32,
+                            "z" => position[2] = value as f32,
+                            "nx" => normal[0] = value as f32,
+                            "ny" => normal[1] = value as f32,
+                            "nz" => normal[2] = value as f32,
+                            "u" | "s" | "texture_u" | "texture_s" => uv[0] = value as f32,
+                            // Flipped like the OBJ loader does, so textures read top down
+                            "v" | "t" | "texture_v" | "texture_t" => uv[1] = 1.0 - value as f32,
+                            "red" => color[0] = (value / ty.color_scale()) as f32,
+                            "green" => color[1] = (value / ty.color_scale()) as f32,
+                            "blue" => color[2] = (value / ty.color_scale()) as f32,
+                            "alpha" => color[3] = (value / ty.color_scale()) as f32,
+                            _ => {}
+                        }
+                    }
+                    Property::List { name, count, item } => {
+                        let count = values.read(*count)? as usize;
+                        let list = (0..count).map(|_| values.read(*item)).collect::<anyhow::Result<Vec<_>>>()?;
+                        if is_face && (name == "vertex_indices" || name == "vertex_index") {
+                            if let Some(&index) = list.iter().find(|&&index| index < 0.0) {
+                                bail!("face {} refers to negative vertex {}", number, index);
+                            }
+                            for i in 1..count.saturating_sub(1) {
+                                indices.extend([list[0] as u32, list[i] as u32, list[i + 1] as u32]);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if is_vertex {
+                positions.push(Point3::from(position));
+                normals.push(Vector3::from(normal));
+                uvs.push(uv);
+                if has_colors {
+                    colors.push(color);
+                }
+            }
+        }
+    }
+
+    if positions.is_empty() {
+        bail!("PLY has no vertices");
+    }
+    if indices.is_empty() {
+        bail!("PLY has no faces, point clouds can't be sculpted");
+    }
+    if let Some(&i) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+        bail!("face refers to vertex {} but there are only {}", i, positions.len());
+    }
+
+    // Files without normals leave them all zero
+    let recompute = normals.iter().all(|n| n.is_zero());
+    let mut mesh = SculptMesh::new(positions, normals, uvs, indices);
+    mesh.colors = colors;
+    if recompute {
+        mesh.recompute_normals();
+    }
+    mesh.take_dirty();
+    Ok(mesh)
+}
+
+/// Writes positions, normals, texture coordinates, vertex colors if the mesh has them, and triangles.
+pub fn write<W: Write>(writer: &mut W, mesh: &SculptMesh, binary: bool) -> io::Result<()> {
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format {} 1.0", if binary { "binary_little_endian" } else { "ascii" })?;
+    writeln!(writer, "comment web_sculpt")?;
+    writeln!(writer, "element vertex {}", mesh.num_vertices())?;
+    for name in ["x", "y", "z", "nx", "ny", "nz", "s", "t"] {
+        writeln!(writer, "property float {}", name)?;
+    }
+    if mesh.has_colors() {
+        for name in ["red", "green", "blue", "alpha"] {
+            writeln!(writer, "property uchar {}", name)?;
+        }
+    }
+    writeln!(writer, "element face {}", mesh.num_faces())?;
+    writeln!(writer, "property list uchar int vertex_indices")?;
+    writeln!(writer, "end_header")?;
+
+    let color_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    for v in 0..mesh.num_vertices() {
+        let (p, n, uv) = (mesh.positions[v], mesh.normals[v], mesh.uvs[v]);
+        let floats = [p.x, p.y, p.z, n.x, n.y, n.z, uv[0], 1.0 - uv[1]];
+        let color = mesh.colors.get(v).map(|c| c.map(color_byte));
+
+        if binary {
+            for value in floats {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            if let Some(color) = color {
+                writer.write_all(&color)?;
+            }
+        } else {
+            let mut line = floats.map(|f| f.to_string()).join(" ");
+            if let Some(color) = color {
+                line += &format!(" {} {} {} {}", color[0], color[1], color[2], color[3]);
+            }
+            writeln!(writer, "{}", line)?;
+        }
+    }
+
+    for face in 0..mesh.num_faces() {
+        let [a, b, c] = mesh.face(face).map(|v| v as i32);
+        if binary {
+            writer.write_all(&[3])?;
+            for index in [a, b, c] {
+                writer.write_all(&index.to_le_bytes())?;
+            }
+        } else {
+            writeln!(writer, "3 {} {} {}", a, b, c)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+    use crate::resources;
+
+    fn round_trip(binary: bool) {
+        let mut mesh = resources::test_mesh("cube.obj");
+        // Colors go through bytes, so only byte levels come back exactly
+        mesh.colors = (0..mesh.num_vertices()).map(|v| [(v % 256) as f32 / 255.0, 0.0, 1.0, 128.0 / 255.0]).collect();
+        let mut written = Vec::new();
+        write(&mut written, &mesh, binary).unwrap();
+        let read = read(&written).unwrap();
+
+        assert_eq!(read.indices, mesh.indices);
+        assert_eq!(read.positions, mesh.positions);
+        assert_eq!(read.colors, mesh.colors);
+        for v in 0..mesh.num_vertices() {
+            assert!((read.normals[v] - mesh.normals[v]).magnitude() < 1e-6);
+            // v is flipped on the way out and back in
+            assert!((read.uvs[v][0] - mesh.uvs[v][0]).abs() < 1e-6 && (read.uvs[v][1] - mesh.uvs[v][1]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        round_trip(true);
+    }
+
+    #[test]
+    fn ascii_round_trip() {
+        round_trip(false);
+    }
+
+    #[test]
+    fn out_of_range_faces_are_rejected() {
+        let text = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n1 0 0\n0 1 0\n3 0 1 3\n";
+        let error = read(text.as_bytes()).err().unwrap();
+        assert!(error.to_string().contains("vertex 3"), "{}", error);
+    }
+
+    #[test]
+    fn negative_indices_are_rejected() {
+        let text = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+            element face 2\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n3 0 -1 2\n";
+        let error = read(text.as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(), "face 1 refers to negative vertex -1");
+    }
+}
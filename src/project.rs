@@ -0,0 +1,800 @@
+//! The `.wsculpt` project format, which keeps a whole sculpting session rather than just the surface.
+//!
+//! A file is the 8 byte magic, the format version as a little endian u32, then a run of chunks:
+//!
+//! ```text
+//! tag      [u8; 4]   what the chunk holds, e.g. b"MESH"
+//! version  u32       layout of this chunk's payload
+//! length   u32       payload size in bytes
+//! crc32    u32       checksum of the payload
+//! payload  [u8; length]
+//! ```
+//!
+//! The last chunk is `END\0`, so a file cut short is told apart from one that simply has fewer chunks.
+//! Readers skip chunks they don't know and chunk versions newer than theirs, so files from later
+//! versions still open with whatever this version understands. Fields are only ever appended to a
+//! chunk, with its version bumped, so every older layout stays readable.
+
+use std::io::{self, Write};
+
+use anyhow::{bail, Context};
+use cgmath::{Point3, Quaternion, Vector3};
+
+use crate::{brush::{BrushKind, BrushSettings}, camera::{Camera, Projection}, falloff::Falloff, instance::Instance, multires::MultiresSnapshot, sculpt_mesh::SculptMesh, stroke::StrokeSettings, subdivision::{PolyMesh, Scheme}, texture::EncodedImage};
+
+pub const MAGIC: &[u8; 8] = b"WSCULPT\0";
+pub const VERSION: u32 = 1;
+pub const EXTENSION: &str = "wsculpt";
+
+const CHUNK_HEADER_SIZE: usize = 16;
+
+// The newest layout of each chunk this version writes and reads
+const MESH: ([u8; 4], u32) = (*b"MESH", 1);
+const ATTRIBUTE: ([u8; 4], u32) = (*b"ATTR", 1);
+const MATERIAL: ([u8; 4], u32) = (*b"MATL", 1);
+const CAMERA: ([u8; 4], u32) = (*b"CAMR", 1);
+const BRUSH: ([u8; 4], u32) = (*b"BRSH", 1);
+const INSTANCES: ([u8; 4], u32) = (*b"INST", 1);
+const SOURCE: ([u8; 4], u32) = (*b"SRCE", 1);
+const MULTIRES: ([u8; 4], u32) = (*b"MRES", 1);
+const END: ([u8; 4], u32) = (*b"END\0", 1);
+
+// Per vertex attributes beyond the surface itself, stored by name so new ones don't need a new chunk
+const COLOR_ATTRIBUTE: &str = "color";
+
+pub struct ProjectMesh {
+    pub name: String,
+    // Index into the project's materials
+    pub material: usize,
+    pub mesh: SculptMesh,
+    // Subdivision levels, `mesh` being the current one
+    pub multires: Option<MultiresSnapshot>,
+}
+
+/// A material as the files its textures came from, None where the app generated a plain one.
+#[derive(Debug, Clone)]
+pub struct ProjectMaterial {
+    pub name: String,
+    pub diffuse: Option<EncodedImage>,
+    pub normal: Option<EncodedImage>,
+}
+
+/// Where the camera was looking from. The aspect ratio and clip planes follow the window and the scene.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraView {
+    pub eye: Point3<f32>,
+    pub target: Point3<f32>,
+    pub up: Vector3<f32>,
+    pub fovy: f32,
+    pub projection: Projection,
+}
+
+impl CameraView {
+    pub fn of(camera: &Camera) -> Self {
+        Self {
+            eye: camera.eye,
+            target: camera.target,
+            up: camera.up,
+            fovy: camera.fovy,
+            projection: camera.projection,
+        }
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.eye = self.eye;
+        camera.target = self.target;
+        camera.up = self.up;
+        camera.fovy = self.fovy;
+        camera.projection = self.projection;
+    }
+}
+
+/// Everything a session needs to pick up where it left off.
+pub struct Project {
+    pub meshes: Vec<ProjectMesh>,
+    pub materials: Vec<ProjectMaterial>,
+    pub camera: Option<CameraView>,
+    pub brush: Option<BrushSettings>,
+    pub instances: Vec<Instance>,
+    // The file the session was opened from, so a recovered session saves next to it again
+    pub source: Option<String>,
+}
+
+/// The CRC-32 used by zip and PNG.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, text: &str) {
+        self.bytes(text.as_bytes());
+    }
+
+    fn image(&mut self, image: Option<&EncodedImage>) {
+        match image {
+            Some(image) => {
+                self.u8(1);
+                self.str(&image.mime_type);
+                self.bytes(&image.bytes);
+            }
+            None => self.u8(0),
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self.position.checked_add(count).filter(|&end| end <= self.bytes.len()).context("chunk ends early")?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32s<const N: usize>(&mut self) -> anyhow::Result<[f32; N]> {
+        let mut values = [0.0; N];
+        for value in &mut values {
+            *value = self.f32()?;
+        }
+        Ok(values)
+    }
+
+    // Checks the count against what's left first, so a bad count can't ask for a huge allocation
+    fn count(&mut self, item_size: usize) -> anyhow::Result<usize> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(item_size) > self.bytes.len() - self.position {
+            bail!("chunk claims {} items but is too short for them", count);
+        }
+        Ok(count)
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let count = self.count(1)?;
+        self.take(count)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    fn image(&mut self) -> anyhow::Result<Option<EncodedImage>> {
+        if self.u8()? == 0 {
+            return Ok(None);
+        }
+        let mime_type = self.string()?;
+        let bytes = self.bytes()?.to_vec();
+        Ok(Some(EncodedImage { bytes, mime_type }))
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, (tag, version): ([u8; 4], u32), payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&tag)?;
+    writer.write_all(&version.to_le_bytes())?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32(payload).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+fn brush_kind_code(kind: BrushKind) -> u8 {
+    match kind {
+        BrushKind::Draw => 0,
+        BrushKind::Clay => 1,
+        BrushKind::Inflate => 2,
+        BrushKind::Smooth => 3,
+        BrushKind::Flatten => 4,
+        BrushKind::Pinch => 5,
+        BrushKind::Crease => 6,
+        BrushKind::Grab => 7,
+    }
+}
+
+fn brush_kind_from_code(code: u8) -> BrushKind {
+    match code {
+        1 => BrushKind::Clay,
+        2 => BrushKind::Inflate,
+        3 => BrushKind::Smooth,
+        4 => BrushKind::Flatten,
+        5 => BrushKind::Pinch,
+        6 => BrushKind::Crease,
+        7 => BrushKind::Grab,
+        _ => BrushKind::Draw,
+    }
+}
+
+// Custom curves are stored with their points, the built in ones by code alone
+fn falloff_code(falloff: &Falloff) -> u8 {
+    match falloff {
+        Falloff::Smooth => 0,
+        Falloff::Sphere => 1,
+        Falloff::Root => 2,
+        Falloff::Sharp => 3,
+        Falloff::Linear => 4,
+        Falloff::Constant => 5,
+        Falloff::Custom(_) => 6,
+    }
+}
+
+// The base level as polygons, then per finer level the offsets it adds on top of subdividing
+fn multires_chunk(mesh_index: usize, multires: &MultiresSnapshot) -> Encoder {
+    let mut chunk = Encoder::default();
+    chunk.u32(mesh_index as u32);
+    chunk.u8(match multires.scheme {
+        Scheme::CatmullClark => 0,
+        Scheme::Loop => 1,
+    });
+    chunk.u32(multires.current as u32);
+
+    let base = &multires.base;
+    chunk.u32(base.num_vertices() as u32);
+    for position in &base.positions {
+        chunk.f32s(&[position.x, position.y, position.z]);
+    }
+    chunk.u8(base.has_colors() as u8);
+    for color in &base.colors {
+        chunk.f32s(color);
+    }
+    chunk.u32(base.num_faces() as u32);
+    for f in 0..base.num_faces() {
+        chunk.u32(base.face(f).len() as u32);
+    }
+    for (&corner, uv) in base.corners.iter().zip(&base.corner_uvs) {
+        chunk.u32(corner as u32);
+        chunk.f32s(uv);
+    }
+
+    chunk.u32(multires.details.len() as u32);
+    for details in &multires.details {
+        chunk.u32(details.len() as u32);
+        for detail in details {
+            chunk.f32s(&[detail.x, detail.y, detail.z]);
+        }
+    }
+    chunk
+}
+
+/// Writes the project, textures included, as one self-contained file.
+pub fn write<W: Write>(writer: &mut W, project: &Project) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+
+    for material in &project.materials {
+        let mut chunk = Encoder::default();
+        chunk.str(&material.name);
+        chunk.image(material.diffuse.as_ref());
+        chunk.image(material.normal.as_ref());
+        write_chunk(writer, MATERIAL, &chunk.bytes)?;
+    }
+
+    for (index, mesh) in project.meshes.iter().enumerate() {
+        let sculpt = &mesh.mesh;
+        let mut chunk = Encoder::default();
+        chunk.str(&mesh.name);
+        chunk.u32(mesh.material as u32);
+        chunk.u32(sculpt.num_vertices() as u32);
+        for (position, normal) in sculpt.positions.iter().zip(&sculpt.normals) {
+            chunk.f32s(&[position.x, position.y, position.z, normal.x, normal.y, normal.z]);
+        }
+        for uv in &sculpt.uvs {
+            chunk.f32s(uv);
+        }
+        chunk.u32(sculpt.indices.len() as u32);
+        for &index in &sculpt.indices {
+            chunk.u32(index);
+        }
+        write_chunk(writer, MESH, &chunk.bytes)?;
+
+        if sculpt.has_colors() {
+            let mut chunk = Encoder::default();
+            chunk.u32(index as u32);
+            chunk.str(COLOR_ATTRIBUTE);
+            chunk.u32(4);
+            for color in &sculpt.colors {
+                chunk.f32s(color);
+            }
+            write_chunk(writer, ATTRIBUTE, &chunk.bytes)?;
+        }
+
+        if let Some(multires) = &mesh.multires {
+            write_chunk(writer, MULTIRES, &multires_chunk(index, multires).bytes)?;
+        }
+    }
+
+    if let Some(view) = &project.camera {
+        let mut chunk = Encoder::default();
+        chunk.f32s(&[view.eye.x, view.eye.y, view.eye.z]);
+        chunk.f32s(&[view.target.x, view.target.y, view.target.z]);
+        chunk.f32s(&[view.up.x, view.up.y, view.up.z, view.fovy]);
+        chunk.u8(match view.projection {
+            Projection::Perspective => 0,
+            Projection::Orthographic => 1,
+        });
+        write_chunk(writer, CAMERA, &chunk.bytes)?;
+    }
+
+    if let Some(brush) = &project.brush {
+        let mut chunk = Encoder::default();
+        chunk.u8(brush_kind_code(brush.kind));
+        chunk.f32s(&[brush.radius, brush.strength]);
+        chunk.f32s(&[brush.stroke_settings.spacing, brush.stroke_settings.lazy_radius]);
+        chunk.u8(falloff_code(&brush.falloff));
+        if let Falloff::Custom(points) = &brush.falloff {
+            chunk.u32(points.len() as u32);
+            for point in points {
+                chunk.f32s(point);
+            }
+        }
+        write_chunk(writer, BRUSH, &chunk.bytes)?;
+    }
+
+    if !project.instances.is_empty() {
+        let mut chunk = Encoder::default();
+        chunk.u32(project.instances.len() as u32);
+        for instance in &project.instances {
+            let (p, r, s) = (instance.position, instance.rotation, instance.scale);
+            chunk.f32s(&[p.x, p.y, p.z, r.v.x, r.v.y, r.v.z, r.s, s.x, s.y, s.z]);
+        }
+        write_chunk(writer, INSTANCES, &chunk.bytes)?;
+    }
+
+    if let Some(source) = &project.source {
+        let mut chunk = Encoder::default();
+        chunk.str(source);
+        write_chunk(writer, SOURCE, &chunk.bytes)?;
+    }
+
+    write_chunk(writer, END, &[])
+}
+
+/// Reads a project, checking every chunk against its checksum.
+pub fn read(bytes: &[u8]) -> anyhow::Result<Project> {
+    if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+        bail!("not a {} project", EXTENSION);
+    }
+    let version = u32::from_le_bytes(bytes[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+    if version > VERSION {
+        log::warn!("Project is from a newer version ({} > {}), anything this version doesn't know is skipped", version, VERSION);
+    }
+
+    let mut project = Project {
+        meshes: Vec::new(),
+        materials: Vec::new(),
+        camera: None,
+        brush: None,
+        instances: Vec::new(),
+        source: None,
+    };
+
+    let mut position = MAGIC.len() + 4;
+    loop {
+        let header = bytes.get(position..position + CHUNK_HEADER_SIZE).context("project is truncated")?;
+        let tag: [u8; 4] = header[0..4].try_into().unwrap();
+        let chunk_version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let length = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        position += CHUNK_HEADER_SIZE;
+
+        let name = String::from_utf8_lossy(&tag).trim_end_matches('\0').to_string();
+        let end = position.checked_add(length).with_context(|| format!("{} chunk claims an impossible length", name))?;
+        let payload = bytes.get(position..end).with_context(|| format!("project is truncated in its {} chunk", name))?;
+        position = end;
+        if crc32(payload) != checksum {
+            bail!("{} chunk is corrupt, its checksum doesn't match", name);
+        }
+
+        if tag == END.0 {
+            break;
+        }
+        let known = [MESH, ATTRIBUTE, MULTIRES, MATERIAL, CAMERA, BRUSH, INSTANCES, SOURCE].into_iter().find(|(known, _)| *known == tag);
+        match known {
+            Some((_, newest)) if chunk_version > newest => {
+                log::warn!("Skipping {} chunk version {}, only up to {} is understood", name, chunk_version, newest);
+                continue;
+            }
+            None => {
+                log::warn!("Skipping unknown {} chunk", name);
+                continue;
+            }
+            Some(_) => {}
+        }
+
+        read_chunk(tag, &mut Decoder::new(payload), &mut project)
+            .with_context(|| format!("Failed to read {} chunk", name))?;
+    }
+
+    if project.meshes.is_empty() {
+        bail!("project has no meshes");
+    }
+    Ok(project)
+}
+
+fn read_chunk(tag: [u8; 4], chunk: &mut Decoder, project: &mut Project) -> anyhow::Result<()> {
+    match tag {
+        t if t == MESH.0 => project.meshes.push(read_mesh(chunk)?),
+        t if t == ATTRIBUTE.0 => read_attribute(chunk, &mut project.meshes)?,
+        t if t == MULTIRES.0 => read_multires(chunk, &mut project.meshes)?,
+        t if t == MATERIAL.0 => project.materials.push(ProjectMaterial {
+            name: chunk.string()?,
+            diffuse: chunk.image()?,
+            normal: chunk.image()?,
+        }),
+        t if t == CAMERA.0 => {
+            let [ex, ey, ez, tx, ty, tz, ux, uy, uz, fovy] = chunk.f32s()?;
+            project.camera = Some(CameraView {
+                eye: Point3::new(ex, ey, ez),
+                target: Point3::new(tx, ty, tz),
+                up: Vector3::new(ux, uy, uz),
+                fovy,
+                projection: if chunk.u8()? == 1 { Projection::Orthographic } else { Projection::Perspective },
+            });
+        }
+        t if t == BRUSH.0 => project.brush = Some(read_brush(chunk)?),
+        t if t == INSTANCES.0 => {
+            let count = chunk.count(40)?;
+            for _ in 0..count {
+                let [px, py, pz, rx, ry, rz, rs, sx, sy, sz] = chunk.f32s()?;
+                project.instances.push(Instance {
+                    position: Vector3::new(px, py, pz),
+                    rotation: Quaternion::new(rs, rx, ry, rz),
+                    scale: Vector3::new(sx, sy, sz),
+                });
+            }
+        }
+        t if t == SOURCE.0 => project.source = Some(chunk.string()?),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn read_mesh(chunk: &mut Decoder) -> anyhow::Result<ProjectMesh> {
+    let name = chunk.string()?;
+    let material = chunk.u32()? as usize;
+    // Position, normal and uv
+    let count = chunk.count(32)?;
+    let mut positions = Vec::with_capacity(count);
+    let mut normals = Vec::with_capacity(count);
+    for _ in 0..count {
+        let [px, py, pz, nx, ny, nz] = chunk.f32s()?;
+        positions.push(Point3::new(px, py, pz));
+        normals.push(Vector3::new(nx, ny, nz));
+    }
+    let uvs = (0..count).map(|_| chunk.f32s()).collect::<anyhow::Result<Vec<_>>>()?;
+    let index_count = chunk.count(4)?;
+    let indices = (0..index_count).map(|_| chunk.u32()).collect::<anyhow::Result<Vec<_>>>()?;
+
+    if index_count % 3 != 0 {
+        bail!("mesh {:?} has {} indices, which isn't whole triangles", name, index_count);
+    }
+    if let Some(&i) = indices.iter().find(|&&i| i as usize >= count) {
+        bail!("mesh {:?} refers to vertex {} but has only {}", name, i, count);
+    }
+
+    let mut mesh = SculptMesh::new(positions, normals, uvs, indices);
+    mesh.take_dirty();
+    Ok(ProjectMesh { name, material, mesh, multires: None })
+}
+
+fn read_attribute(chunk: &mut Decoder, meshes: &mut [ProjectMesh]) -> anyhow::Result<()> {
+    let mesh_index = chunk.u32()? as usize;
+    let name = chunk.string()?;
+    let components = chunk.u32()? as usize;
+    let mesh = &mut meshes.get_mut(mesh_index)
+        .with_context(|| format!("{} attribute belongs to mesh {}, which comes later or not at all", name, mesh_index))?
+        .mesh;
+
+    match (name.as_str(), components) {
+        (COLOR_ATTRIBUTE, 4) => {
+            mesh.colors = (0..mesh.num_vertices()).map(|_| chunk.f32s()).collect::<anyhow::Result<Vec<_>>>()?;
+        }
+        _ => log::warn!("Skipping unknown vertex attribute {} with {} components", name, components),
+    }
+    Ok(())
+}
+
+fn read_multires(chunk: &mut Decoder, meshes: &mut [ProjectMesh]) -> anyhow::Result<()> {
+    let mesh_index = chunk.u32()? as usize;
+    let scheme = match chunk.u8()? {
+        0 => Scheme::CatmullClark,
+        1 => Scheme::Loop,
+        code => bail!("unknown subdivision scheme {}", code),
+    };
+    let current = chunk.u32()? as usize;
+
+    let vertex_count = chunk.count(12)?;
+    let positions = (0..vertex_count)
+        .map(|_| chunk.f32s().map(|[x, y, z]| Point3::new(x, y, z)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let colors = match chunk.u8()? {
+        0 => Vec::new(),
+        _ => (0..vertex_count).map(|_| chunk.f32s()).collect::<anyhow::Result<Vec<_>>>()?,
+    };
+    let face_count = chunk.count(4)?;
+    let mut face_starts = Vec::with_capacity(face_count + 1);
+    face_starts.push(0);
+    for _ in 0..face_count {
+        let size = chunk.u32()? as usize;
+        if size < 3 {
+            bail!("base level has a face with {} corners", size);
+        }
+        face_starts.push(face_starts[face_starts.len() - 1] + size);
+    }
+    let mut corners = Vec::new();
+    let mut corner_uvs = Vec::new();
+    for _ in 0..face_starts[face_count] {
+        let corner = chunk.u32()? as usize;
+        if corner >= vertex_count {
+            bail!("base level refers to vertex {} but has only {}", corner, vertex_count);
+        }
+        corners.push(corner);
+        corner_uvs.push(chunk.f32s()?);
+    }
+    let base = PolyMesh { positions, colors, corners, corner_uvs, face_starts };
+    if scheme == Scheme::Loop && !base.is_triangles() {
+        bail!("base level has to be triangles to be Loop subdivided");
+    }
+
+    let level_count = chunk.count(4)?;
+    let mut details = Vec::with_capacity(level_count);
+    for _ in 0..level_count {
+        let count = chunk.count(12)?;
+        details.push((0..count)
+            .map(|_| chunk.f32s().map(|[x, y, z]| Vector3::new(x, y, z)))
+            .collect::<anyhow::Result<Vec<_>>>()?);
+    }
+
+    let mesh = meshes.get_mut(mesh_index)
+        .with_context(|| format!("subdivision levels belong to mesh {}, which comes later or not at all", mesh_index))?;
+    mesh.multires = Some(MultiresSnapshot { scheme, base, details, current });
+    Ok(())
+}
+
+fn read_brush(chunk: &mut Decoder) -> anyhow::Result<BrushSettings> {
+    let kind = brush_kind_from_code(chunk.u8()?);
+    let [radius, strength, spacing, lazy_radius] = chunk.f32s()?;
+    let falloff = match chunk.u8()? {
+        1 => Falloff::Sphere,
+        2 => Falloff::Root,
+        3 => Falloff::Sharp,
+        4 => Falloff::Linear,
+        5 => Falloff::Constant,
+        6 => {
+            let count = chunk.count(8)?;
+            Falloff::custom((0..count).map(|_| chunk.f32s()).collect::<anyhow::Result<Vec<_>>>()?)
+        }
+        _ => Falloff::Smooth,
+    };
+    Ok(BrushSettings {
+        kind,
+        radius,
+        strength,
+        falloff,
+        stroke_settings: StrokeSettings { spacing, lazy_radius },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{multires::Multires, resources};
+
+    fn sample() -> Project {
+        let mut colored = resources::test_mesh("cube.obj");
+        colored.colors = (0..colored.num_vertices()).map(|v| [v as f32 / 256.0, 0.5, 0.25, 1.0]).collect();
+        let (mut multires, base) = Multires::from_sculpt(&colored);
+        let mut subdivided = multires.finer(&base).unwrap();
+        subdivided.positions[0].y += 0.25;
+        Project {
+            meshes: vec![
+                ProjectMesh { name: "plain".to_string(), material: 0, mesh: resources::test_mesh("cube.obj"), multires: None },
+                ProjectMesh { name: "colored".to_string(), material: 1, mesh: colored, multires: None },
+                ProjectMesh {
+                    name: "subdivided".to_string(),
+                    material: 1,
+                    multires: Some(multires.snapshot(&subdivided)),
+                    mesh: subdivided,
+                },
+            ],
+            materials: vec![
+                ProjectMaterial {
+                    name: "textured".to_string(),
+                    diffuse: Some(EncodedImage { bytes: vec![1, 2, 3], mime_type: "image/png".to_string() }),
+                    normal: None,
+                },
+                ProjectMaterial { name: "plain".to_string(), diffuse: None, normal: None },
+            ],
+            camera: Some(CameraView {
+                eye: Point3::new(1.0, 2.0, 3.0),
+                target: Point3::new(0.0, 0.5, 0.0),
+                up: Vector3::unit_y(),
+                fovy: 45.0,
+                projection: Projection::Orthographic,
+            }),
+            brush: Some(BrushSettings {
+                kind: BrushKind::Crease,
+                radius: 0.3,
+                strength: 0.7,
+                falloff: Falloff::custom(vec![[0.0, 1.0], [0.4, 0.8], [1.0, 0.0]]),
+                stroke_settings: StrokeSettings { spacing: 12.0, lazy_radius: 5.0 },
+            }),
+            instances: vec![Instance {
+                position: Vector3::new(1.0, 0.0, -2.0),
+                rotation: Quaternion::new(0.5, 0.5, 0.5, 0.5),
+                scale: Vector3::new(1.0, 2.0, 3.0),
+            }],
+            source: Some("models/cube.obj".to_string()),
+        }
+    }
+
+    fn written(project: &Project) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(&mut bytes, project).unwrap();
+        bytes
+    }
+
+    // Where each chunk's header starts, END included
+    fn chunk_offsets(bytes: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut position = MAGIC.len() + 4;
+        while position < bytes.len() {
+            offsets.push(position);
+            let length = u32::from_le_bytes(bytes[position + 8..position + 12].try_into().unwrap()) as usize;
+            position += CHUNK_HEADER_SIZE + length;
+        }
+        offsets
+    }
+
+    fn assert_same(read: &Project, project: &Project) {
+        assert_eq!(read.meshes.len(), project.meshes.len());
+        for (read, mesh) in read.meshes.iter().zip(&project.meshes) {
+            assert_eq!(read.name, mesh.name);
+            assert_eq!(read.material, mesh.material);
+            assert_eq!(read.mesh.positions, mesh.mesh.positions);
+            assert_eq!(read.mesh.normals, mesh.mesh.normals);
+            assert_eq!(read.mesh.uvs, mesh.mesh.uvs);
+            assert_eq!(read.mesh.indices, mesh.mesh.indices);
+            assert_eq!(read.mesh.colors, mesh.mesh.colors);
+            assert_eq!(read.multires, mesh.multires);
+        }
+        assert_eq!(read.materials.len(), project.materials.len());
+        for (read, material) in read.materials.iter().zip(&project.materials) {
+            assert_eq!(read.name, material.name);
+            assert_eq!(read.diffuse.as_ref().map(|i| (&i.bytes, &i.mime_type)), material.diffuse.as_ref().map(|i| (&i.bytes, &i.mime_type)));
+            assert_eq!(read.normal.is_some(), material.normal.is_some());
+        }
+        assert_eq!(read.camera, project.camera);
+        assert_eq!(read.brush, project.brush);
+        assert_eq!(read.instances.len(), project.instances.len());
+        for (read, instance) in read.instances.iter().zip(&project.instances) {
+            assert_eq!((read.position, read.rotation, read.scale), (instance.position, instance.rotation, instance.scale));
+        }
+        assert_eq!(read.source, project.source);
+    }
+
+    #[test]
+    fn round_trip_keeps_every_chunk() {
+        let project = sample();
+        let bytes = written(&project);
+        let tags = chunk_offsets(&bytes).into_iter().map(|o| bytes[o..o + 4].try_into().unwrap()).collect::<Vec<[u8; 4]>>();
+        for (tag, _) in [MESH, ATTRIBUTE, MULTIRES, MATERIAL, CAMERA, BRUSH, INSTANCES, SOURCE, END] {
+            assert!(tags.contains(&tag), "no {:?} chunk", tag);
+        }
+        assert_same(&read(&bytes).unwrap(), &project);
+    }
+
+    #[test]
+    fn corrupt_chunks_are_rejected() {
+        let bytes = written(&sample());
+        for offset in chunk_offsets(&bytes) {
+            let length = u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into().unwrap()) as usize;
+            if length == 0 {
+                continue;
+            }
+            let mut corrupt = bytes.clone();
+            corrupt[offset + CHUNK_HEADER_SIZE + length / 2] ^= 0x40;
+            let error = read(&corrupt).err().unwrap();
+            assert!(error.to_string().contains("corrupt"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn unknown_and_newer_chunks_are_skipped() {
+        let project = sample();
+        let mut bytes = written(&project);
+        // Spliced in before END, as a later version would write them
+        bytes.truncate(bytes.len() - CHUNK_HEADER_SIZE);
+        write_chunk(&mut bytes, (*b"XTRA", 1), b"from the future").unwrap();
+        write_chunk(&mut bytes, (CAMERA.0, CAMERA.1 + 1), &[0xff; 7]).unwrap();
+        write_chunk(&mut bytes, END, &[]).unwrap();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_same(&read(&bytes).unwrap(), &project);
+    }
+
+    #[test]
+    fn bad_multires_levels_are_errors() {
+        let mut project = sample();
+        let multires = project.meshes[2].multires.as_mut().unwrap();
+        let vertices = multires.base.num_vertices();
+        multires.base.corners[5] = vertices;
+        let error = read(&written(&project)).err().unwrap();
+        assert!(format!("{:#}", error).contains(&format!("refers to vertex {}", vertices)), "{:#}", error);
+
+        let mut project = sample();
+        project.meshes[2].multires.as_mut().unwrap().scheme = Scheme::Loop;
+        project.meshes[2].multires.as_mut().unwrap().base = PolyMesh::from_indexed(&[Point3::new(0.0, 0.0, 0.0); 4], &[], &[], &[0, 1, 2, 3], &[4]);
+        assert!(read(&written(&project)).is_err());
+    }
+
+    #[test]
+    fn truncated_and_oversized_chunks_are_errors() {
+        let bytes = written(&sample());
+        assert!(read(&bytes[..bytes.len() - CHUNK_HEADER_SIZE]).is_err());
+        assert!(read(&bytes[..bytes.len() / 2]).is_err());
+
+        let mut oversized = bytes.clone();
+        let first = chunk_offsets(&bytes)[0];
+        oversized[first + 8..first + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = read(&oversized).err().unwrap();
+        assert!(error.to_string().contains("truncated") || error.to_string().contains("impossible"), "{}", error);
+    }
+}
@@ -0,0 +1,403 @@
+//! Voxel remeshing: the mesh is sampled into a signed distance grid and a new surface is extracted
+//! from it with surface nets, which gives evenly sized, well shaped faces however stretched the old ones were.
+
+use std::{collections::VecDeque, sync::{atomic::{AtomicU32, Ordering}, Arc}};
+
+use anyhow::bail;
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3, Zero};
+
+use crate::{bvh::Bvh, picking::Ray, sculpt_mesh::SculptMesh};
+
+// Keeps a tiny voxel size from asking for more memory than there is
+pub const MAX_VOXELS: usize = 1 << 24;
+
+// Empty voxels around the mesh, so the flood fill from the border starts outside it
+const PADDING: usize = 2;
+
+struct Grid {
+    origin: Point3<f32>,
+    size: f32,
+    dims: [usize; 3],
+}
+
+impl Grid {
+    fn len(&self) -> usize {
+        self.dims[0] * self.dims[1] * self.dims[2]
+    }
+
+    fn index(&self, [x, y, z]: [usize; 3]) -> usize {
+        x + self.dims[0] * (y + self.dims[1] * z)
+    }
+
+    fn coords(&self, i: usize) -> [usize; 3] {
+        [i % self.dims[0], i / self.dims[0] % self.dims[1], i / (self.dims[0] * self.dims[1])]
+    }
+
+    fn point(&self, [x, y, z]: [usize; 3]) -> Point3<f32> {
+        self.origin + Vector3::new(x as f32, y as f32, z as f32) * self.size
+    }
+
+    // Voxels whose sample points fall in the box, clamped to the grid
+    fn range(&self, min: Point3<f32>, max: Point3<f32>) -> [std::ops::RangeInclusive<usize>; 3] {
+        [0, 1, 2].map(|axis| {
+            let lo = ((min[axis] - self.origin[axis]) / self.size).floor().max(0.0) as usize;
+            let hi = ((max[axis] - self.origin[axis]) / self.size).ceil().max(0.0) as usize;
+            lo.min(self.dims[axis] - 1)..=hi.min(self.dims[axis] - 1)
+        })
+    }
+}
+
+fn report(progress: &AtomicU32, percent: u32) {
+    progress.store(percent.min(100), Ordering::Relaxed);
+}
+
+/// Rebuilds `mesh` as an even surface with vertices about `voxel_size` apart.
+/// Vertex colors are carried over from the nearest point of the old surface, uvs can't survive
+/// the new topology and are dropped. The mesh has to be closed, a hole wider than a voxel lets
+/// the outside leak in and nothing is left. `progress` counts up to 100 as the work gets done.
+pub fn voxel_remesh(mesh: &SculptMesh, voxel_size: f32, progress: &AtomicU32) -> anyhow::Result<SculptMesh> {
+    if !(voxel_size.is_finite() && voxel_size > 0.0) {
+        bail!("voxel size must be positive, not {}", voxel_size);
+    }
+    if mesh.num_faces() == 0 {
+        bail!("there is no surface to remesh");
+    }
+    let bvh = Bvh::build(mesh);
+    let bounds = bvh.bounds();
+
+    // Shifted off the voxel lattice by a bit, so flat faces on round coordinates don't sit exactly on samples
+    let origin = bounds.min - Vector3::new(1.0, 1.0, 1.0) * voxel_size * (PADDING as f32 + 0.37);
+    let dims = [0, 1, 2].map(|axis| ((bounds.max[axis] - origin[axis]) / voxel_size).ceil() as usize + PADDING + 1);
+    let voxels = dims.iter().try_fold(1usize, |total, &d| total.checked_mul(d)).unwrap_or(usize::MAX);
+    if voxels > MAX_VOXELS {
+        bail!("a voxel size of {} needs {}x{}x{} voxels, more than the {} allowed", voxel_size, dims[0], dims[1], dims[2], MAX_VOXELS);
+    }
+    let grid = Grid { origin, size: voxel_size, dims };
+
+    // Unsigned distances, only measured near the surface since that's the only place they're interpolated
+    let mut distance = vec![f32::INFINITY; grid.len()];
+    let margin = Vector3::new(1.0, 1.0, 1.0) * voxel_size;
+    for face in 0..mesh.num_faces() {
+        let [a, b, c] = mesh.face(face).map(|v| mesh.positions[v]);
+        let min = Point3::new(a.x.min(b.x).min(c.x), a.y.min(b.y).min(c.y), a.z.min(b.z).min(c.z)) - margin;
+        let max = Point3::new(a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y), a.z.max(b.z).max(c.z)) + margin;
+        let [xs, ys, zs] = grid.range(min, max);
+        for z in zs {
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    // NaN marks a voxel still to be measured
+                    let i = grid.index([x, y, z]);
+                    if distance[i].is_infinite() {
+                        distance[i] = f32::NAN;
+                    }
+                }
+            }
+        }
+        if face % 1024 == 0 {
+            report(progress, (face * 10 / mesh.num_faces()) as u32);
+        }
+    }
+    let near = (0..grid.len()).filter(|&i| distance[i].is_nan()).collect::<Vec<_>>();
+    for (n, &i) in near.iter().enumerate() {
+        distance[i] = bvh.nearest_point(mesh, grid.point(grid.coords(i))).map_or(f32::INFINITY, |nearest| nearest.distance);
+        if n % 4096 == 0 {
+            report(progress, 10 + (n * 40 / near.len()) as u32);
+        }
+    }
+
+    // Outside is whatever the border reaches without crossing the surface. Only neighbors both
+    // within a voxel of the surface can have it between them, so only those need a ray cast.
+    let steps = [0, 1, 2].map(|axis| {
+        let mut step = Vector3::zero();
+        step[axis] = 1.0;
+        step
+    });
+    let crosses = |from: Point3<f32>, direction: Vector3<f32>| {
+        // Started a little behind, so a surface lying right on a sample still counts
+        let slack = voxel_size * 1e-3;
+        let ray = Ray { origin: from - direction * slack, direction };
+        bvh.ray_cast(mesh, &ray).is_some_and(|(_, t, _)| t <= voxel_size + slack * 2.0)
+    };
+    let mut outside = vec![false; grid.len()];
+    let mut queue = VecDeque::new();
+    for (i, outside) in outside.iter_mut().enumerate() {
+        let coords = grid.coords(i);
+        if (0..3).any(|axis| coords[axis] == 0 || coords[axis] == dims[axis] - 1) {
+            *outside = true;
+            queue.push_back(i);
+        }
+    }
+    let mut visited = 0;
+    while let Some(i) = queue.pop_front() {
+        let coords = grid.coords(i);
+        for axis in 0..3 {
+            for forward in [false, true] {
+                let neighbor = match forward {
+                    true if coords[axis] + 1 < dims[axis] => coords[axis] + 1,
+                    false if coords[axis] > 0 => coords[axis] - 1,
+                    _ => continue,
+                };
+                let mut next = coords;
+                next[axis] = neighbor;
+                let j = grid.index(next);
+                if outside[j] {
+                    continue;
+                }
+                let direction = if forward { steps[axis] } else { -steps[axis] };
+                if distance[i] <= voxel_size && distance[j] <= voxel_size && crosses(grid.point(coords), direction) {
+                    continue;
+                }
+                outside[j] = true;
+                queue.push_back(j);
+            }
+        }
+        visited += 1;
+        if visited % 65536 == 0 {
+            report(progress, 50 + (visited * 20 / grid.len()) as u32);
+        }
+    }
+
+    // Surface nets: a vertex in every cell the surface passes through, at the average of where it crosses the cell's edges
+    const CORNERS: [[usize; 3]; 8] = [[0, 0, 0], [1, 0, 0], [0, 1, 0], [1, 1, 0], [0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1]];
+    const EDGES: [(usize, usize); 12] = [(0, 1), (2, 3), (4, 5), (6, 7), (0, 2), (1, 3), (4, 6), (5, 7), (0, 4), (1, 5), (2, 6), (3, 7)];
+    let mut cell_vertex = vec![u32::MAX; grid.len()];
+    let mut positions = Vec::new();
+    for z in 0..dims[2] - 1 {
+        for y in 0..dims[1] - 1 {
+            for x in 0..dims[0] - 1 {
+                let corners = CORNERS.map(|[dx, dy, dz]| grid.index([x + dx, y + dy, z + dz]));
+                let inside = corners.map(|i| !outside[i]);
+                if inside.iter().all(|&inside| inside) || !inside.iter().any(|&inside| inside) {
+                    continue;
+                }
+                let (sum, count) = EDGES.iter()
+                    .filter(|&&(a, b)| inside[a] != inside[b])
+                    .fold((Vector3::zero(), 0), |(sum, count), &(a, b)| {
+                        let (da, db) = (distance[corners[a]], distance[corners[b]]);
+                        let t = if da + db > 0.0 { (da / (da + db)).clamp(0.0, 1.0) } else { 0.5 };
+                        let (pa, pb) = (grid.point(grid.coords(corners[a])), grid.point(grid.coords(corners[b])));
+                        (sum + pa.to_vec() + (pb - pa) * t, count + 1)
+                    });
+                cell_vertex[grid.index([x, y, z])] = positions.len() as u32;
+                positions.push(Point3::from_vec(sum / count as f32));
+            }
+        }
+        report(progress, 70 + (z * 10 / dims[2]) as u32);
+    }
+
+    // A quad around every grid edge the surface crosses, joining the four cells that share the edge
+    let mut indices = Vec::new();
+    for i in 0..grid.len() {
+        let coords = grid.coords(i);
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            // Edges on the border never cross the surface, the padding keeps it away
+            if coords[axis] + 1 >= dims[axis] || coords[u] == 0 || coords[v] == 0 {
+                continue;
+            }
+            let mut next = coords;
+            next[axis] += 1;
+            let inside = !outside[i];
+            if inside != outside[grid.index(next)] {
+                continue;
+            }
+            let cell = |du: usize, dv: usize| {
+                let mut c = coords;
+                c[u] -= 1 - du;
+                c[v] -= 1 - dv;
+                cell_vertex[grid.index(c)]
+            };
+            // Counter-clockwise around the axis, so the quad faces along it, flipped when that's inward
+            let mut quad = [cell(0, 0), cell(1, 0), cell(1, 1), cell(0, 1)];
+            if !inside {
+                quad.reverse();
+            }
+            if quad.contains(&u32::MAX) {
+                continue;
+            }
+            // Split along the shorter diagonal, the flatter of the two ways
+            let p = quad.map(|q| positions[q as usize]);
+            if (p[0] - p[2]).magnitude2() <= (p[1] - p[3]).magnitude2() {
+                indices.extend([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            } else {
+                indices.extend([quad[0], quad[1], quad[3], quad[1], quad[2], quad[3]]);
+            }
+        }
+    }
+    if indices.is_empty() {
+        bail!("nothing was left after remeshing, the mesh may have holes or the voxels may be larger than it");
+    }
+
+    let count = positions.len();
+    let mut remeshed = SculptMesh::new(positions, vec![Vector3::zero(); count], vec![[0.0, 0.0]; count], indices);
+    if mesh.has_colors() {
+        remeshed.colors = (0..count).map(|v| {
+            let nearest = bvh.nearest_point(mesh, remeshed.positions[v]);
+            if v % 4096 == 0 {
+                report(progress, 80 + (v * 20 / count) as u32);
+            }
+            nearest.map_or([1.0; 4], |nearest| {
+                let corners = mesh.face(nearest.face);
+                [0, 1, 2, 3].map(|c| (0..3).map(|k| mesh.colors[corners[k]][c] * nearest.barycentric[k]).sum())
+            })
+        }).collect();
+    }
+    remeshed.recompute_normals();
+    report(progress, 100);
+    Ok(remeshed)
+}
+
+/// A remesh running off the main thread. The web has no threads, so there it is done by the time `start` returns.
+pub struct RemeshJob {
+    pub mesh: usize,
+    progress: Arc<AtomicU32>,
+    reported: u32,
+    #[cfg(not(target_arch = "wasm32"))]
+    handle: std::thread::JoinHandle<anyhow::Result<SculptMesh>>,
+    #[cfg(target_arch = "wasm32")]
+    result: anyhow::Result<SculptMesh>,
+}
+
+impl RemeshJob {
+    /// Starts remeshing a copy of the mesh at index `mesh`, which the caller swaps in once it is finished.
+    pub fn start(mesh: usize, sculpt: SculptMesh, voxel_size: f32) -> anyhow::Result<Self> {
+        let progress = Arc::new(AtomicU32::new(0));
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                let result = voxel_remesh(&sculpt, voxel_size, &progress);
+                Ok(Self { mesh, progress, reported: 0, result })
+            } else {
+                use anyhow::Context;
+                let shared = progress.clone();
+                let handle = std::thread::Builder::new()
+                    .name("remesh".to_string())
+                    .spawn(move || voxel_remesh(&sculpt, voxel_size, &shared))
+                    .context("Failed to start remeshing")?;
+                Ok(Self { mesh, progress, reported: 0, handle })
+            }
+        }
+    }
+
+    pub fn progress(&self) -> u32 {
+        self.progress.load(Ordering::Relaxed)
+    }
+
+    /// The progress whenever it has moved on by another tenth since the last time, for logging.
+    pub fn report(&mut self) -> Option<u32> {
+        let progress = self.progress();
+        if progress < self.reported + 10 {
+            return None;
+        }
+        self.reported = progress - progress % 10;
+        Some(progress)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                true
+            } else {
+                self.handle.is_finished()
+            }
+        }
+    }
+
+    /// Waits for the remeshed mesh.
+    pub fn finish(self) -> anyhow::Result<SculptMesh> {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                self.result
+            } else {
+                self.handle.join().map_err(|_| anyhow::anyhow!("Remeshing crashed"))?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{dyntopo::is_manifold, resources};
+
+    // Every edge has the face on its other side wound the opposite way, so nothing is open
+    fn is_closed(mesh: &SculptMesh) -> bool {
+        let directed = (0..mesh.num_faces())
+            .flat_map(|f| {
+                let [a, b, c] = mesh.face(f);
+                [(a, b), (b, c), (c, a)]
+            })
+            .collect::<HashSet<_>>();
+        directed.iter().all(|&(a, b)| directed.contains(&(b, a)))
+    }
+
+    #[test]
+    fn remeshed_cube_is_closed_and_manifold() {
+        let cube = resources::test_mesh("cube.obj");
+        let mut previous = 0;
+        for voxel_size in [0.2, 0.1, 0.05] {
+            let progress = AtomicU32::new(0);
+            let remeshed = voxel_remesh(&cube, voxel_size, &progress).unwrap();
+            assert_eq!(progress.load(Ordering::Relaxed), 100);
+            assert!(is_closed(&remeshed), "open at voxel size {}", voxel_size);
+            assert!(is_manifold(&remeshed), "not manifold at voxel size {}", voxel_size);
+
+            let edges = (0..remeshed.num_vertices())
+                .flat_map(|v| remeshed.vertex_neighbors[v].iter().filter(move |&&n| n > v))
+                .count();
+            assert_eq!(remeshed.num_vertices() as i64 - edges as i64 + remeshed.num_faces() as i64, 2);
+            // The surface stays where it was, give or take a voxel
+            for p in &remeshed.positions {
+                let extent = p.x.abs().max(p.y.abs()).max(p.z.abs());
+                assert!((extent - 1.0).abs() < voxel_size, "{:?} is off the cube at voxel size {}", p, voxel_size);
+            }
+            // Finer voxels, more faces
+            assert!(remeshed.num_faces() > previous);
+            previous = remeshed.num_faces();
+        }
+    }
+
+    #[test]
+    fn bad_voxel_sizes_are_errors() {
+        let cube = resources::test_mesh("cube.obj");
+        let progress = AtomicU32::new(0);
+        for voxel_size in [0.0, -0.1, f32::NAN, f32::INFINITY] {
+            let error = voxel_remesh(&cube, voxel_size, &progress).err().unwrap();
+            assert!(error.to_string().contains("must be positive"), "{}", error);
+        }
+
+        let error = voxel_remesh(&cube, 1e-3, &progress).err().unwrap();
+        assert!(error.to_string().contains(&MAX_VOXELS.to_string()), "{}", error);
+        assert_eq!(progress.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn colors_come_back_from_the_nearest_face() {
+        let mut cube = resources::test_mesh("cube.obj");
+        const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+        const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+        cube.colors = cube.positions.iter().map(|p| if p.x > 0.95 { RED } else { BLUE }).collect();
+        let remeshed = voxel_remesh(&cube, 0.1, &AtomicU32::new(0)).unwrap();
+        assert_eq!(remeshed.colors.len(), remeshed.num_vertices());
+
+        // Away from the bevel, where the old faces are all one color
+        let (mut red, mut blue) = (0, 0);
+        for (p, color) in remeshed.positions.iter().zip(&remeshed.colors) {
+            if p.y.abs() > 0.8 || p.z.abs() > 0.8 {
+                continue;
+            }
+            let expected = if p.x > 0.9 {
+                red += 1;
+                RED
+            } else if p.x < -0.9 {
+                blue += 1;
+                BLUE
+            } else {
+                continue;
+            };
+            assert!(color.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-4), "{:?} at {:?}", color, p);
+        }
+        assert!(red > 0 && blue > 0);
+    }
+}
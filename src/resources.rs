@@ -1,9 +1,9 @@
-use std::io::{BufReader, Cursor};
+use std::{cell::RefCell, collections::HashMap, io::{BufReader, Cursor}};
 
+use anyhow::{bail, Context};
 use cfg_if::cfg_if;
-use wgpu::util::DeviceExt;
 
-use crate::{model::{self, Material, Mesh}, texture, vertex::ModelVertex};
+use crate::{brush::BrushSettings, gltf_io, model::{self, Material, Mesh}, multires::Multires, obj, ply, project::{self, CameraView}, sculpt_mesh::SculptMesh, stl, subdivision::PolyMesh, texture, vertex::ModelVertex};
 
 // https://sotrh.github.io/learn-wgpu/beginner/tutorial9-models/#accessing-files-from-wasm
 
@@ -19,19 +19,39 @@ fn format_url(file_name: &str) -> reqwest::Url {
     base.join(file_name).unwrap()
 }
 
+// Paths that exist are used as given, anything else is looked up in the models bundled at build time
+#[cfg(not(target_arch = "wasm32"))]
+fn native_path(file_name: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(file_name);
+    if path.exists() {
+        path.to_path_buf()
+    } else {
+        std::path::Path::new(env!("OUT_DIR")).join("models").join(file_name)
+    }
+}
+
+/// Resolves a file a model refers to, like its .mtl or textures, against the model's folder.
+pub fn relative_to(model_file: &str, file_name: &str) -> String {
+    match std::path::Path::new(model_file).parent() {
+        Some(dir) => dir.join(file_name).to_string_lossy().into_owned(),
+        None => file_name.to_string(),
+    }
+}
+
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
     cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             let url = format_url(file_name);
             let txt = reqwest::get(url)
                 .await?
+                .error_for_status()?
                 .text()
-                .await?;
+                .await
+                .with_context(|| format!("Failed to read {}", file_name))?;
         } else {
-            let path = std::path::Path::new(env!("OUT_DIR"))
-                .join("models")
-                .join(file_name);
-            let txt = std::fs::read_to_string(path)?;
+            let path = native_path(file_name);
+            let txt = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
         }
     }
 
@@ -44,14 +64,15 @@ pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
             let url = format_url(file_name);
             let data = reqwest::get(url)
                 .await?
+                .error_for_status()?
                 .bytes()
-                .await?
+                .await
+                .with_context(|| format!("Failed to read {}", file_name))?
                 .to_vec();
         } else {
-            let path = std::path::Path::new(env!("OUT_DIR"))
-                .join("models")
-                .join(file_name);
-            let data = std::fs::read(path)?;
+            let path = native_path(file_name);
+            let data = std::fs::read(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
         }
     }
 
@@ -60,115 +81,561 @@ pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
 
 pub async fn load_texture(
     file_name: &str,
+    is_normal_map: bool,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
 ) -> anyhow::Result<texture::Texture> {
     let data = load_binary(file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name)
+    texture::Texture::from_bytes(device, queue, &data, file_name, is_normal_map)
+        .with_context(|| format!("Failed to decode {}", file_name))
+}
+
+/// The PNGs in `dir`, as paths `load_binary` takes, sorted by name. The web can't list a folder,
+/// so there `dir` has to hold an `index.txt` naming one file per line.
+pub async fn list_pngs(dir: &str) -> Vec<String> {
+    cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            let index = load_string(&format!("{}/index.txt", dir)).await.unwrap_or_default();
+            let mut names = index.lines().map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect::<Vec<_>>();
+        } else {
+            let entries = std::fs::read_dir(native_path(dir)).into_iter().flatten().flatten();
+            let mut names = entries.filter_map(|entry| entry.file_name().into_string().ok()).collect::<Vec<_>>();
+        }
+    }
+    names.retain(|name| name.to_ascii_lowercase().ends_with(".png"));
+    names.sort();
+    names.into_iter().map(|name| format!("{}/{}", dir, name)).collect()
+}
+
+fn solid_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    color: [u8; 4],
+    label: &str,
+    is_normal_map: bool,
+) -> anyhow::Result<texture::Texture> {
+    let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+    texture::Texture::from_image(device, queue, &image, Some(label), is_normal_map)
+}
+
+// Missing or broken textures fall back to a solid one, a model with a bad texture is still worth sculpting.
+// Also hands back the file so it can be embedded in exports.
+async fn load_texture_or(
+    file_name: &str,
+    is_normal_map: bool,
+    fallback: [u8; 4],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<(texture::Texture, Option<texture::EncodedImage>)> {
+    if !file_name.is_empty() {
+        let loaded = match load_binary(file_name).await {
+            Ok(data) => texture::Texture::from_bytes(device, queue, &data, file_name, is_normal_map)
+                .with_context(|| format!("Failed to decode {}", file_name))
+                .map(|texture| (texture, Some(texture::EncodedImage::new(data)))),
+            Err(e) => Err(e),
+        };
+        match loaded {
+            Ok(loaded) => return Ok(loaded),
+            Err(e) => log::warn!("Using a plain texture instead of {}: {:#}", file_name, e),
+        }
+    }
+    Ok((solid_texture(device, queue, fallback, "fallback texture", is_normal_map)?, None))
+}
+
+// White so the clay color shows through untinted, and a straight up normal
+const DEFAULT_DIFFUSE: [u8; 4] = [255, 255, 255, 255];
+const FLAT_NORMAL: [u8; 4] = [128, 128, 255, 255];
+
+pub fn default_material(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<Material> {
+    let diffuse_texture = solid_texture(device, queue, DEFAULT_DIFFUSE, "default diffuse", false)?;
+    let normal_texture = solid_texture(device, queue, FLAT_NORMAL, "default normal map", true)?;
+    Ok(Material::new(device, "default", &diffuse_texture, &normal_texture, layout))
+}
+
+/// Meshes without a usable material share a default one, added after the others.
+pub fn add_default_material(
+    meshes: &mut [Mesh],
+    materials: &mut Vec<Material>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<()> {
+    if meshes.iter().any(|mesh| mesh.material >= materials.len()) {
+        let default = materials.len();
+        materials.push(default_material(device, queue, layout)?);
+        for mesh in meshes.iter_mut().filter(|mesh| mesh.material >= default) {
+            mesh.material = default;
+        }
+    }
+    Ok(())
+}
+
+/// Converts a single index tobj mesh into vertices.
+/// Missing texture coordinates become zero and missing normals are left zero for the caller to recompute.
+pub fn obj_vertices(mesh: &tobj::Mesh) -> anyhow::Result<Vec<ModelVertex>> {
+    let count = mesh.positions.len() / 3;
+    let has_uvs = !mesh.texcoords.is_empty();
+    let has_normals = !mesh.normals.is_empty();
+    if has_uvs && mesh.texcoords.len() != count * 2 {
+        bail!("expected {} texture coordinates, found {}", count, mesh.texcoords.len() / 2);
+    }
+    if has_normals && mesh.normals.len() != count * 3 {
+        bail!("expected {} normals, found {}", count, mesh.normals.len() / 3);
+    }
+    if let Some(&i) = mesh.indices.iter().find(|&&i| i as usize >= count) {
+        bail!("face refers to vertex {} but there are only {}", i, count);
+    }
+
+    Ok((0..count).map(|i| ModelVertex {
+        position: [mesh.positions[i * 3], mesh.positions[i * 3 + 1], mesh.positions[i * 3 + 2]],
+        tex_coords: if has_uvs {
+            [mesh.texcoords[i * 2], 1.0 - mesh.texcoords[i * 2 + 1]]
+        } else {
+            [0.0, 0.0]
+        },
+        normal: if has_normals {
+            [mesh.normals[i * 3], mesh.normals[i * 3 + 1], mesh.normals[i * 3 + 2]]
+        } else {
+            [0.0, 0.0, 0.0]
+        },
+        tangent: [0.0, 0.0, 0.0],
+        bitangent: [0.0, 0.0, 0.0],
+    }).collect())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ModelFormat {
+    Obj,
+    Stl,
+    Ply,
+    Gltf,
+    Project,
 }
 
+impl ModelFormat {
+    // Anything unrecognised is treated as OBJ, the format the app started out with
+    pub fn from_path(file_name: &str) -> Self {
+        let extension = std::path::Path::new(file_name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("stl") => Self::Stl,
+            Some("ply") => Self::Ply,
+            Some("gltf") | Some("glb") => Self::Gltf,
+            Some(project::EXTENSION) => Self::Project,
+            _ => Self::Obj,
+        }
+    }
+}
+
+/// `keep_quads` loads OBJ polygons as a cage for Catmull-Clark subdivision instead of triangulating them,
+/// other formats only have triangles anyway.
 pub async fn load_model(
     file_name: &str,
+    keep_quads: bool,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout
 ) -> anyhow::Result<model::Model> {
+    match ModelFormat::from_path(file_name) {
+        ModelFormat::Obj => load_obj(file_name, keep_quads, device, queue, layout).await,
+        ModelFormat::Stl | ModelFormat::Ply => load_mesh_file(file_name, device, queue, layout).await,
+        ModelFormat::Gltf => load_gltf(file_name, device, queue, layout).await,
+        ModelFormat::Project => Ok(load_project(file_name, device, queue, layout).await?.0),
+    }
+}
+
+/// Opens a project with the camera and brush it was saved with, for the caller to restore.
+pub async fn load_project(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout
+) -> anyhow::Result<(model::Model, Option<CameraView>, Option<BrushSettings>)> {
+    let data = load_binary(file_name).await?;
+    let project = project::read(&data).with_context(|| format!("Failed to read {}", file_name))?;
+    project_model(project, device, queue, layout)
+}
+
+/// Uploads a project's meshes and textures, handing back the camera and brush it was saved with.
+pub fn project_model(
+    project: project::Project,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout
+) -> anyhow::Result<(model::Model, Option<CameraView>, Option<BrushSettings>)> {
+    let mut materials = Vec::new();
+    for m in &project.materials {
+        let diffuse_texture = match m.diffuse.as_ref().and_then(|source| encoded_texture(device, queue, source, &m.name, false)) {
+            Some(texture) => texture,
+            None => solid_texture(device, queue, DEFAULT_DIFFUSE, "default diffuse", false)?,
+        };
+        let normal_texture = match m.normal.as_ref().and_then(|source| encoded_texture(device, queue, source, &m.name, true)) {
+            Some(texture) => texture,
+            None => solid_texture(device, queue, FLAT_NORMAL, "flat normal map", true)?,
+        };
+        let mut material = Material::new(device, &m.name, &diffuse_texture, &normal_texture, layout);
+        material.diffuse_source = m.diffuse.clone();
+        material.normal_source = m.normal.clone();
+        materials.push(material);
+    }
+
+    let mut meshes = project.meshes.into_iter()
+        .map(|m| {
+            let mut mesh = Mesh::new(device, &m.name, m.mesh, m.material);
+            if let Some(snapshot) = m.multires {
+                mesh.multires = Multires::from_snapshot(snapshot, &mesh.sculpt);
+                if mesh.multires.is_none() {
+                    log::warn!("Dropping the subdivision levels of {:?}, they don't match its mesh", m.name);
+                }
+            }
+            mesh
+        })
+        .collect::<Vec<_>>();
+    add_default_material(&mut meshes, &mut materials, device, queue, layout)?;
+
+    let model = model::Model { meshes, materials, mtllib: Vec::new(), instances: project.instances };
+    Ok((model, project.camera, project.brush))
+}
+
+// glTF colors are linear but the diffuse texture is sRGB
+fn linear_to_srgb(c: f32) -> f32 {
+    c.clamp(0.0, 1.0).powf(1.0 / 2.2)
+}
+
+// Base color textures are multiplied by the factor up front, there's no factor in the shader
+fn base_color_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    material: &gltf_io::ImportedMaterial,
+) -> anyhow::Result<texture::Texture> {
+    let factor = material.base_color_factor;
+    let srgb_factor = [linear_to_srgb(factor[0]), linear_to_srgb(factor[1]), linear_to_srgb(factor[2]), factor[3]];
+    let image = match &material.base_color_texture {
+        Some(source) => match image::load_from_memory(&source.bytes) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                log::warn!("Using a plain base color for {}: {}", material.name, e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let image = match image {
+        Some(image) if factor == [1.0; 4] => image,
+        Some(image) => {
+            let mut rgba = image.to_rgba8();
+            for pixel in rgba.pixels_mut() {
+                for (channel, f) in pixel.0.iter_mut().zip(srgb_factor) {
+                    *channel = (*channel as f32 * f).round() as u8;
+                }
+            }
+            image::DynamicImage::ImageRgba8(rgba)
+        }
+        None => {
+            let color = srgb_factor.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)))
+        }
+    };
+    texture::Texture::from_image(device, queue, &image, Some(&material.name), false)
+}
+
+fn encoded_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    source: &texture::EncodedImage,
+    label: &str,
+    is_normal_map: bool,
+) -> Option<texture::Texture> {
+    texture::Texture::from_bytes(device, queue, &source.bytes, label, is_normal_map)
+        .map_err(|e| log::warn!("Skipping texture of {}: {}", label, e))
+        .ok()
+}
+
+async fn load_gltf(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout
+) -> anyhow::Result<model::Model> {
+    let data = load_binary(file_name).await?;
+    let gltf = gltf::Gltf::from_slice(&data).with_context(|| format!("Failed to parse {}", file_name))?;
+
+    // Separate .bin and image files sit next to the .gltf, embedded ones need no fetching
+    let mut external = HashMap::new();
+    for uri in gltf_io::external_uris(&gltf.document) {
+        let bytes = load_binary(&relative_to(file_name, &uri)).await?;
+        external.insert(uri, bytes);
+    }
+    let scene = gltf_io::import(&gltf, &external).with_context(|| format!("Failed to import {}", file_name))?;
+
+    let mut materials = Vec::new();
+    for m in &scene.materials {
+        let diffuse_texture = base_color_texture(device, queue, m)?;
+        let normal_texture = match m.normal_texture.as_ref().and_then(|source| encoded_texture(device, queue, source, &m.name, true)) {
+            Some(texture) => texture,
+            None => solid_texture(device, queue, FLAT_NORMAL, "flat normal map", true)?,
+        };
+        let mut material = Material::new(device, &m.name, &diffuse_texture, &normal_texture, layout);
+        // The factor is baked into the uploaded texture, so only an untinted source can be exported as is,
+        // a tinted one goes back out as just its factor
+        if m.base_color_factor == [1.0; 4] {
+            material.diffuse_source = m.base_color_texture.clone();
+        }
+        material.base_color_factor = m.base_color_factor;
+        material.normal_source = m.normal_texture.clone();
+        material.metallic_roughness_source = m.metallic_roughness_texture.clone();
+        materials.push(material);
+    }
+
+    let mut meshes = scene.meshes.into_iter()
+        .map(|m| Mesh::new(device, &m.name, m.mesh, m.material.unwrap_or(usize::MAX)))
+        .collect::<Vec<_>>();
+    add_default_material(&mut meshes, &mut materials, device, queue, layout)?;
+
+    Ok(model::Model { meshes, materials, mtllib: Vec::new(), instances: scene.instances })
+}
+
+// STL and PLY hold a single mesh and no materials
+async fn load_mesh_file(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout
+) -> anyhow::Result<model::Model> {
+    let data = load_binary(file_name).await?;
+    let sculpt = match ModelFormat::from_path(file_name) {
+        ModelFormat::Stl => stl::read(&data),
+        _ => ply::read(&data),
+    }.with_context(|| format!("Failed to parse {}", file_name))?;
+
+    let name = std::path::Path::new(file_name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok(model::Model {
+        meshes: vec![Mesh::new(device, &name, sculpt, 0)],
+        materials: vec![default_material(device, queue, layout)?],
+        mtllib: Vec::new(),
+        instances: Vec::new(),
+    })
+}
+
+/// A mesh read from an OBJ, before anything is uploaded.
+pub struct ObjMesh {
+    pub name: String,
+    pub sculpt: SculptMesh,
+    pub multires: Option<Multires>,
+    // Index into the materials, out of range when the mesh has no usable one
+    pub material: usize,
+}
+
+/// Everything an OBJ and its .mtl hold, read without a GPU.
+pub struct ObjScene {
+    pub meshes: Vec<ObjMesh>,
+    pub materials: Vec<tobj::Material>,
+    pub mtllib: Vec<String>,
+    // Why the materials couldn't be read, naming the file at fault
+    pub materials_error: Option<anyhow::Error>,
+}
+
+pub async fn parse_obj(file_name: &str, keep_quads: bool) -> anyhow::Result<ObjScene> {
     let obj_text = load_string(file_name).await?;
+    let mtllib = obj::mtllib_names(&obj_text);
     let obj_cursor = Cursor::new(obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
 
+    // tobj only gets an error code back from the loader, the real error is kept here
+    let materials_error = RefCell::new(None);
     let (models, obj_materials) = tobj::load_obj_buf_async(
         &mut obj_reader, 
         &tobj::LoadOptions {
-            triangulate: true,
+            triangulate: !keep_quads,
             single_index: true,
             ..Default::default()
         },
-        |p| async move {
-            let material_text = load_string(&p).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(material_text)))
+        |p| {
+            let p = relative_to(file_name, &p);
+            let materials_error = &materials_error;
+            async move {
+                let loaded = match load_string(&p).await {
+                    Ok(material_text) => tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(material_text)))
+                        .with_context(|| format!("Failed to parse {}", p)),
+                    Err(e) => Err(e),
+                };
+                loaded.map_err(|e| {
+                    *materials_error.borrow_mut() = Some(e);
+                    tobj::LoadError::OpenFileFailed
+                })
+            }
+        }
+    ).await.with_context(|| format!("Failed to parse {}", file_name))?;
+
+    // A missing or broken .mtl only costs the textures
+    let mut materials_error = materials_error.into_inner();
+    let materials = obj_materials.unwrap_or_else(|e| {
+        materials_error.get_or_insert_with(|| anyhow::Error::new(e).context(format!("Failed to load the materials of {}", file_name)));
+        Vec::new()
+    });
+
+    let mut meshes = Vec::new();
+    for m in models {
+        if m.mesh.indices.is_empty() {
+            continue;
+        }
+        let vertices = obj_vertices(&m.mesh)
+            .with_context(|| format!("Invalid mesh {:?} in {}", m.name, file_name))?;
+        // Fixed up by the caller if it doesn't point at a loaded material
+        let material = m.mesh.material_id.unwrap_or(usize::MAX);
+
+        // Arities are only filled in when some face isn't a triangle
+        if !m.mesh.face_arities.is_empty() {
+            let positions = vertices.iter().map(|v| v.position.into()).collect::<Vec<_>>();
+            let uvs = vertices.iter().map(|v| v.tex_coords).collect::<Vec<_>>();
+            let cage = PolyMesh::from_indexed(&positions, &uvs, &[], &m.mesh.indices, &m.mesh.face_arities);
+            let (multires, sculpt) = Multires::new(cage);
+            meshes.push(ObjMesh { name: m.name, sculpt, multires: Some(multires), material });
+            continue;
         }
-    ).await?;
+
+        let mut sculpt = SculptMesh::from_vertices(&vertices, m.mesh.indices);
+        if m.mesh.normals.is_empty() {
+            sculpt.recompute_normals();
+        }
+        meshes.push(ObjMesh { name: m.name, sculpt, multires: None, material });
+    }
+
+    if meshes.is_empty() {
+        bail!("{} has no faces", file_name);
+    }
+
+    Ok(ObjScene { meshes, materials, mtllib, materials_error })
+}
+
+async fn load_obj(
+    file_name: &str,
+    keep_quads: bool,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout
+) -> anyhow::Result<model::Model> {
+    let scene = parse_obj(file_name, keep_quads).await?;
+    if let Some(e) = &scene.materials_error {
+        log::warn!("Loading {} without its materials: {:#}", file_name, e);
+    }
+
+    // Empty names mean the material has no such texture
+    let texture_path = |name: &str| if name.is_empty() { String::new() } else { relative_to(file_name, name) };
 
     let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture, device, queue).await?;
-        let bind_group = device.create_bind_group(
-            &wgpu::BindGroupDescriptor { 
-                label: None,
-                layout, 
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view)
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler)
-                    },
-                ] 
-            }
-        );
-
-        materials.push(Material { name: m.name, diffuse_texture, bind_group });
-    }
-
-    let meshes = models.into_iter().map(
-        |m| {
-            let vertices = (0..m.mesh.positions.len() / 3).map(
-                |i| {
-                    let mut return_vertex = ModelVertex {
-                        position: [
-                            m.mesh.positions[i*3],
-                            m.mesh.positions[i*3 + 1],
-                            m.mesh.positions[i*3 + 2]
-                        ],
-                        tex_coords: [
-                            m.mesh.texcoords[i*2] as f32,
-                            1.0 - m.mesh.texcoords[i*2 + 1] as f32,
-                        ],
-                        normal: [0.0, 0.0, 0.0]
-                    };
-
-                    if !m.mesh.normals.is_empty() {
-                        return_vertex.normal = [
-                            m.mesh.normals[i*3],
-                            m.mesh.normals[i*3 + 1],
-                            m.mesh.normals[i*3 + 2]
-                        ]
-                    }
-
-                    return_vertex
-            }).collect::<Vec<_>>();
-
-
-            let vertex_buffer = device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                    contents: bytemuck::cast_slice(&vertices),
-                    usage: wgpu::BufferUsages::VERTEX
-                }
-            );
+    for m in scene.materials {
+        let (diffuse_texture, diffuse_source) = load_texture_or(&texture_path(&m.diffuse_texture), false, DEFAULT_DIFFUSE, device, queue).await?;
+        let (normal_texture, normal_source) = load_texture_or(&texture_path(&m.normal_texture), true, FLAT_NORMAL, device, queue).await?;
+        let mut material = Material::new(device, &m.name, &diffuse_texture, &normal_texture, layout);
+        material.diffuse_source = diffuse_source;
+        material.normal_source = normal_source;
+        material.in_mtllib = true;
+        materials.push(material);
+    }
 
-            let index_buffer = device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("{:?} Index Buffer", file_name)),
-                    contents: bytemuck::cast_slice(&m.mesh.indices),
-                    usage: wgpu::BufferUsages::INDEX
-                }
-            );
-
-            Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            }
+    let mut meshes = Vec::new();
+    for m in scene.meshes {
+        let mut mesh = Mesh::new(device, &m.name, m.sculpt, m.material);
+        mesh.multires = m.multires;
+        meshes.push(mesh);
+    }
+
+    add_default_material(&mut meshes, &mut materials, device, queue, layout)?;
+
+    Ok(model::Model {meshes, materials, mtllib: scene.mtllib, instances: Vec::new()})
+}
+
+/// The first mesh of a bundled OBJ, triangulated and read without a GPU, for tests.
+#[cfg(test)]
+pub fn test_mesh(file_name: &str) -> SculptMesh {
+    let mut scene = pollster::block_on(parse_obj(file_name, false)).unwrap();
+    let mut sculpt = scene.meshes.swap_remove(0).sculpt;
+    sculpt.take_dirty();
+    sculpt
+}
 
-    }).collect::<Vec<_>>();
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+
+    fn parse_fixture(name: &str) -> anyhow::Result<ObjScene> {
+        pollster::block_on(parse_obj(&format!("tests/fixtures/{}", name), false))
+    }
 
-    
+    #[test]
+    fn obj_without_uvs_gets_zero_uvs() {
+        let scene = parse_fixture("no_uvs.obj").unwrap();
+        let sculpt = &scene.meshes[0].sculpt;
+        assert_eq!(sculpt.num_faces(), 2);
+        assert!(sculpt.uvs.iter().all(|&uv| uv == [0.0, 0.0]));
+        assert!(sculpt.normals.iter().all(|n| (n.z - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn obj_without_normals_gets_them_computed() {
+        let scene = parse_fixture("no_normals.obj").unwrap();
+        let sculpt = &scene.meshes[0].sculpt;
+        assert!(sculpt.normals.iter().all(|n| (n.magnitude() - 1.0).abs() < 1e-5));
+        // Counter-clockwise when seen from +z
+        assert!(sculpt.normals.iter().all(|n| n.z > 0.999));
+    }
+
+    #[test]
+    fn obj_without_material_is_left_for_the_default() {
+        let scene = parse_fixture("no_material.obj").unwrap();
+        assert!(scene.materials.is_empty());
+        assert!(scene.materials_error.is_none());
+        assert!(scene.meshes[0].material >= scene.materials.len());
+    }
 
-    Ok(model::Model {meshes, materials})
-}
\ No newline at end of file
+    #[test]
+    fn missing_mtl_is_named_and_not_fatal() {
+        let scene = parse_fixture("missing_mtl.obj").unwrap();
+        assert_eq!(scene.meshes.len(), 1);
+        assert!(scene.materials.is_empty());
+        let error = format!("{:#}", scene.materials_error.unwrap());
+        assert!(error.contains("does_not_exist.mtl"), "{}", error);
+    }
+
+    #[test]
+    fn broken_obj_error_names_the_file() {
+        let error = format!("{:#}", parse_fixture("bad_index.obj").err().unwrap());
+        assert!(error.contains("bad_index.obj"), "{}", error);
+    }
+
+    #[test]
+    fn bundled_cube_loads_its_material() {
+        let scene = pollster::block_on(parse_obj("cube.obj", false)).unwrap();
+        assert!(scene.materials_error.is_none());
+        assert_eq!(scene.mtllib, ["cube.mtl"]);
+        assert_eq!(scene.meshes[0].material, 0);
+        assert_eq!(scene.materials[0].diffuse_texture, "cube-diffuse.jpg");
+    }
+
+    #[test]
+    fn only_pngs_are_listed_in_order() {
+        let dir = std::env::temp_dir().join(format!("web_sculpt_list_pngs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.png", "a.PNG", "notes.txt", "c.jpg"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let dir_name = dir.to_string_lossy().into_owned();
+        let listed = pollster::block_on(list_pngs(&dir_name));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(listed, vec![format!("{}/a.PNG", dir_name), format!("{}/b.png", dir_name)]);
+
+        assert!(pollster::block_on(list_pngs("no_such_folder")).is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, Point3, SquareMatrix, Transform, Vector3, Zero};
+
+use crate::vertex::ModelVertex;
+
+/// CPU side copy of a mesh that brushes can deform.
+/// The GPU vertex buffer in `model::Mesh` mirrors `vertex(i)` for every vertex,
+/// and modified vertices are tracked as a dirty range so only they get re-uploaded.
+#[derive(Clone)]
+pub struct SculptMesh {
+    pub positions: Vec<Point3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub uvs: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+    // RGBA in 0..1 per vertex, empty when the source had no colors
+    pub colors: Vec<[f32; 4]>,
+    // Derived from positions and uvs, kept orthogonal to the normals
+    pub tangents: Vec<Vector3<f32>>,
+    pub bitangents: Vec<Vector3<f32>>,
+
+    // vertex -> faces using it, and vertex -> vertices sharing an edge with it
+    pub vertex_faces: Vec<Vec<usize>>,
+    pub vertex_neighbors: Vec<Vec<usize>>,
+
+    dirty: Option<Range<usize>>,
+}
+
+impl SculptMesh {
+    pub fn new(
+        positions: Vec<Point3<f32>>,
+        normals: Vec<Vector3<f32>>,
+        uvs: Vec<[f32; 2]>,
+        indices: Vec<u32>,
+    ) -> Self {
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            indices,
+            colors: Vec::new(),
+            tangents: Vec::new(),
+            bitangents: Vec::new(),
+            vertex_faces: Vec::new(),
+            vertex_neighbors: Vec::new(),
+            dirty: None,
+        };
+        mesh.rebuild_adjacency();
+        mesh.recompute_tangents();
+        mesh
+    }
+
+    pub fn from_vertices(vertices: &[ModelVertex], indices: Vec<u32>) -> Self {
+        Self::new(
+            vertices.iter().map(|v| v.position.into()).collect(),
+            vertices.iter().map(|v| v.normal.into()).collect(),
+            vertices.iter().map(|v| v.tex_coords).collect(),
+            indices,
+        )
+    }
+
+    pub fn has_colors(&self) -> bool {
+        !self.colors.is_empty()
+    }
+
+    /// A copy with positions moved by `transform` and normals by its inverse transpose.
+    pub fn transformed(&self, transform: &Matrix4<f32>) -> Self {
+        let upper = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+        let normal_matrix = upper.invert().map(|m| m.transpose()).unwrap_or(upper);
+        let mut mesh = Self::new(
+            self.positions.iter().map(|&p| transform.transform_point(p)).collect(),
+            self.normals.iter().map(|&n| {
+                let n = normal_matrix * n;
+                if n.magnitude2() > 0.0 { n.normalize() } else { n }
+            }).collect(),
+            self.uvs.clone(),
+            self.indices.clone(),
+        );
+        mesh.colors = self.colors.clone();
+        mesh
+    }
+
+    /// Appends another mesh's vertices and faces. Colors are kept if either has them, white filling the gaps.
+    pub fn append(&mut self, other: &SculptMesh) {
+        const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+        let offset = self.num_vertices() as u32;
+        if self.has_colors() || other.has_colors() {
+            self.colors.resize(self.num_vertices(), WHITE);
+            if other.has_colors() {
+                self.colors.extend_from_slice(&other.colors);
+            } else {
+                self.colors.resize(self.num_vertices() + other.num_vertices(), WHITE);
+            }
+        }
+        self.positions.extend_from_slice(&other.positions);
+        self.normals.extend_from_slice(&other.normals);
+        self.uvs.extend_from_slice(&other.uvs);
+        self.indices.extend(other.indices.iter().map(|&i| i + offset));
+        self.rebuild_adjacency();
+        self.recompute_tangents();
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn face(&self, face: usize) -> [usize; 3] {
+        [
+            self.indices[face * 3] as usize,
+            self.indices[face * 3 + 1] as usize,
+            self.indices[face * 3 + 2] as usize,
+        ]
+    }
+
+    pub fn rebuild_adjacency(&mut self) {
+        let n = self.num_vertices();
+        self.vertex_faces = vec![Vec::new(); n];
+        self.vertex_neighbors = vec![Vec::new(); n];
+
+        for face in 0..self.num_faces() {
+            let [a, b, c] = self.face(face);
+            for (v, others) in [(a, [b, c]), (b, [c, a]), (c, [a, b])] {
+                self.vertex_faces[v].push(face);
+                for o in others {
+                    if !self.vertex_neighbors[v].contains(&o) {
+                        self.vertex_neighbors[v].push(o);
+                    }
+                }
+            }
+        }
+    }
+
+    // Unit face normal scaled by the angle at each corner. Splitting a corner's angle between
+    // several triangles splits its weight the same way, so the sum doesn't depend on how a fan
+    // is triangulated, and long thin triangles only count as much as the angle they cover.
+    fn corner_normals(&self, face: usize) -> [Vector3<f32>; 3] {
+        let [a, b, c] = self.face(face);
+        let (pa, pb, pc) = (self.positions[a], self.positions[b], self.positions[c]);
+        let normal = (pb - pa).cross(pc - pa);
+        if normal.magnitude2() == 0.0 {
+            return [Vector3::zero(); 3];
+        }
+        let normal = normal.normalize();
+
+        let angle = |u: Vector3<f32>, v: Vector3<f32>| {
+            let len = (u.magnitude2() * v.magnitude2()).sqrt();
+            if len > 0.0 { (u.dot(v) / len).clamp(-1.0, 1.0).acos() } else { 0.0 }
+        };
+        [
+            normal * angle(pb - pa, pc - pa),
+            normal * angle(pc - pb, pa - pb),
+            normal * angle(pa - pc, pb - pc),
+        ]
+    }
+
+    // Vertices that end up with no usable normal, like loose points or fully degenerate fans, keep the old one
+    fn set_normal(&mut self, v: usize, sum: Vector3<f32>) {
+        if sum.magnitude2() > 1e-20 {
+            self.normals[v] = sum.normalize();
+        }
+        self.mark_dirty(v);
+    }
+
+    /// Smooth angle weighted normals for the whole mesh, used after loading.
+    pub fn recompute_normals(&mut self) {
+        let mut sums = vec![Vector3::zero(); self.num_vertices()];
+        for face in 0..self.num_faces() {
+            for (v, n) in self.face(face).into_iter().zip(self.corner_normals(face)) {
+                sums[v] += n;
+            }
+        }
+        for (v, sum) in sums.into_iter().enumerate() {
+            self.set_normal(v, sum);
+        }
+        self.recompute_tangents();
+    }
+
+    /// Recomputes the normals a change to `vertices` can affect: theirs and their one-ring's,
+    /// since every face around a moved vertex is shared with its neighbors.
+    pub fn recompute_normals_region(&mut self, vertices: &[usize]) {
+        let mut region = vertices.to_vec();
+        for &v in vertices {
+            region.extend_from_slice(&self.vertex_neighbors[v]);
+        }
+        region.sort_unstable();
+        region.dedup();
+
+        for &v in &region {
+            let sum = self.vertex_faces[v].iter().fold(Vector3::zero(), |sum, &face| {
+                let corner = self.face(face).iter().position(|&c| c == v).unwrap_or(0);
+                sum + self.corner_normals(face)[corner]
+            });
+            self.set_normal(v, sum);
+        }
+        for &v in &region {
+            self.update_tangent(v);
+        }
+    }
+
+    // Directions of increasing u and v across a face, zero where the uvs are degenerate
+    fn face_tangents(&self, face: usize) -> (Vector3<f32>, Vector3<f32>) {
+        let [a, b, c] = self.face(face);
+        let e1 = self.positions[b] - self.positions[a];
+        let e2 = self.positions[c] - self.positions[a];
+        let (uv0, uv1, uv2) = (self.uvs[a], self.uvs[b], self.uvs[c]);
+        let duv1 = [uv1[0] - uv0[0], uv1[1] - uv0[1]];
+        let duv2 = [uv2[0] - uv0[0], uv2[1] - uv0[1]];
+
+        let det = duv1[0] * duv2[1] - duv2[0] * duv1[1];
+        if det.abs() < 1e-12 {
+            return (Vector3::zero(), Vector3::zero());
+        }
+        let r = 1.0 / det;
+        ((e1 * duv2[1] - e2 * duv1[1]) * r, (e2 * duv1[0] - e1 * duv2[0]) * r)
+    }
+
+    // Averages the face tangents around a vertex and makes them orthogonal to its normal
+    fn update_tangent(&mut self, v: usize) {
+        let (tangent, bitangent) = self.vertex_faces[v].iter().fold(
+            (Vector3::zero(), Vector3::zero()),
+            |(t, b), &face| {
+                let (ft, fb) = self.face_tangents(face);
+                (t + ft, b + fb)
+            },
+        );
+
+        let normal = self.normals[v];
+        let tangent = tangent - normal * normal.dot(tangent);
+        let tangent = if tangent.magnitude2() > 1e-20 {
+            tangent.normalize()
+        } else {
+            // No usable uvs, any direction in the surface plane will do
+            let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+            let t = axis - normal * normal.dot(axis);
+            if t.magnitude2() > 1e-20 { t.normalize() } else { axis }
+        };
+        let bitangent = bitangent - normal * normal.dot(bitangent) - tangent * tangent.dot(bitangent);
+        let bitangent = if bitangent.magnitude2() > 1e-20 { bitangent.normalize() } else { normal.cross(tangent) };
+
+        self.tangents[v] = tangent;
+        self.bitangents[v] = bitangent;
+        self.mark_dirty(v);
+    }
+
+    pub fn recompute_tangents(&mut self) {
+        let n = self.num_vertices();
+        self.tangents.resize(n, Vector3::zero());
+        self.bitangents.resize(n, Vector3::zero());
+        for v in 0..n {
+            self.update_tangent(v);
+        }
+    }
+
+    pub fn vertex(&self, i: usize) -> ModelVertex {
+        ModelVertex {
+            position: self.positions[i].into(),
+            tex_coords: self.uvs[i],
+            normal: self.normals[i].into(),
+            tangent: self.tangents[i].into(),
+            bitangent: self.bitangents[i].into(),
+        }
+    }
+
+    pub fn vertices(&self, range: Range<usize>) -> Vec<ModelVertex> {
+        range.map(|i| self.vertex(i)).collect()
+    }
+
+    pub fn mark_dirty(&mut self, i: usize) {
+        self.dirty = Some(match self.dirty.take() {
+            Some(range) => range.start.min(i)..range.end.max(i + 1),
+            None => i..i + 1,
+        });
+    }
+
+    pub fn take_dirty(&mut self) -> Option<Range<usize>> {
+        self.dirty.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A welded 4 x 2 x 1 box. The top is a fan around its center, the other sides are split into
+    // two triangles each way round, so its corners see one, two or four triangles of a side.
+    fn box_mesh() -> SculptMesh {
+        let mut positions = (0..8)
+            .map(|i| Point3::new(if i & 1 == 0 { -2.0 } else { 2.0 }, if i & 2 == 0 { -1.0 } else { 1.0 }, if i & 4 == 0 { -0.5 } else { 0.5 }))
+            .collect::<Vec<_>>();
+        positions.push(Point3::new(0.0, 0.0, 0.5));
+
+        let sides = [[0, 2, 3, 1], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2], [1, 3, 7, 5]];
+        let mut indices = Vec::new();
+        for (i, [a, b, c, d]) in sides.into_iter().enumerate() {
+            if i % 2 == 0 {
+                indices.extend([a, b, c, a, c, d]);
+            } else {
+                indices.extend([b, c, d, b, d, a]);
+            }
+        }
+        for [a, b] in [[4, 5], [5, 7], [7, 6], [6, 4]] {
+            indices.extend([8, a, b]);
+        }
+
+        let count = positions.len();
+        let mut mesh = SculptMesh::new(positions, vec![Vector3::zero(); count], vec![[0.0, 0.0]; count], indices);
+        mesh.recompute_normals();
+        mesh
+    }
+
+    fn assert_near(normal: Vector3<f32>, expected: Vector3<f32>) {
+        assert!((normal - expected).magnitude() < 1e-5, "{:?} != {:?}", normal, expected);
+    }
+
+    #[test]
+    fn box_corners_weight_each_side_by_its_right_angle() {
+        let mesh = box_mesh();
+        // Each side meets a corner at a right angle however it was split, so all three count the same
+        // even though the sides differ in size and in how many triangles reach the corner
+        for v in 0..8 {
+            let p = mesh.positions[v];
+            let expected = Vector3::new(p.x.signum(), p.y.signum(), p.z.signum()) / 3f32.sqrt();
+            assert_near(mesh.normals[v], expected);
+        }
+        // The middle of the flat top only sees the top
+        assert_near(mesh.normals[8], Vector3::unit_z());
+    }
+
+    #[test]
+    fn region_update_matches_a_full_recompute() {
+        let mut mesh = box_mesh();
+        mesh.positions[7] += Vector3::new(0.3, -0.2, 0.4);
+        mesh.positions[8].z += 0.25;
+        mesh.recompute_normals_region(&[7, 8]);
+
+        let mut full = mesh.clone();
+        full.recompute_normals();
+        for v in 0..mesh.num_vertices() {
+            assert_near(mesh.normals[v], full.normals[v]);
+        }
+        // A neighbor of the raised center that wasn't asked for moved with it
+        assert!((mesh.normals[4] - box_mesh().normals[4]).magnitude() > 1e-3);
+    }
+}
@@ -3,23 +3,55 @@ struct InstanceInput {
     @location(5) model_0: vec4<f32>,
     @location(6) model_1: vec4<f32>,
     @location(7) model_2: vec4<f32>,
-    @location(8) model_3: vec4<f32>
+    @location(8) model_3: vec4<f32>,
+    // Inverse transpose of the model matrix, for normals
+    @location(9) normal_0: vec3<f32>,
+    @location(10) normal_1: vec3<f32>,
+    @location(11) normal_2: vec3<f32>,
 }
 
 struct CameraUniform {
+    view_position: vec4<f32>,
+    view: mat4x4<f32>,
     view_proj: mat4x4<f32>,
 };
 @group(1) @binding(0)
 var<uniform> camera: CameraUniform;
 
+struct DirectionalLight {
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    specular: f32,
+};
+
+struct LightUniform {
+    // Key, fill and rim
+    lights: array<DirectionalLight, 3>,
+    ambient: vec3<f32>,
+    shininess: f32,
+    use_texture: u32,
+    // 0 lit, 1 matcap
+    mode: u32,
+};
+@group(2) @binding(0)
+var<uniform> light: LightUniform;
+
 struct VertexInput {
     @location(0) position: vec3<f32>,
-    @location(1) tex_coords: vec2<f32>
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) world_position: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
 };
 
 @vertex
@@ -33,9 +65,22 @@ fn vs_main(
         instance.model_2,
         instance.model_3,
     );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_0,
+        instance.normal_1,
+        instance.normal_2,
+    );
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    out.world_normal = normal_matrix * model.normal;
+    // Tangents lie in the surface, so they transform like positions rather than normals
+    let tangent_matrix = mat3x3<f32>(model_matrix[0].xyz, model_matrix[1].xyz, model_matrix[2].xyz);
+    out.world_tangent = tangent_matrix * model.tangent;
+    out.world_bitangent = tangent_matrix * model.bitangent;
+    out.world_position = world_position.xyz;
+    out.clip_position = camera.view_proj * world_position;
     return out;
 }
 
@@ -43,9 +88,58 @@ fn vs_main(
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var s_normal: sampler;
+
+@group(3) @binding(0)
+var t_matcap: texture_2d<f32>;
+@group(3) @binding(1)
+var s_matcap: sampler;
+
+// Base color when the texture is hidden
+const CLAY_COLOR: vec3<f32> = vec3<f32>(0.8, 0.78, 0.75);
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
-}
+    let texture_color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let base = select(CLAY_COLOR, texture_color.rgb, light.use_texture != 0u);
 
+    // Tangent space normal map, stored in 0..1 so remap to -1..1
+    let normal_sample = textureSample(t_normal, s_normal, in.tex_coords).xyz * 2.0 - 1.0;
+    let tbn = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let normal = normalize(tbn * normal_sample);
+
+    // Sphere map lookup by view space normal, flipped since texture v runs down
+    let view_normal = normalize((camera.view * vec4<f32>(normal, 0.0)).xyz);
+    let matcap_uv = vec2<f32>(view_normal.x, -view_normal.y) * 0.495 + 0.5;
+    let matcap_color = textureSample(t_matcap, s_matcap, matcap_uv).rgb;
+    if (light.mode == 1u) {
+        let tint = select(vec3<f32>(1.0), texture_color.rgb, light.use_texture != 0u);
+        return vec4<f32>(matcap_color * tint, texture_color.a);
+    }
+
+    let view_dir = normalize(camera.view_position.xyz - in.world_position);
+
+    var color = base * light.ambient;
+    for (var i = 0u; i < 3u; i++) {
+        let l = light.lights[i];
+        let light_dir = normalize(-l.direction);
+        let radiance = l.color * l.intensity;
+
+        // Lambert
+        let diffuse = max(dot(normal, light_dir), 0.0);
+        // Blinn-Phong
+        let half_dir = normalize(light_dir + view_dir);
+        let specular = pow(max(dot(normal, half_dir), 0.0), light.shininess) * l.specular;
+
+        color += (base * diffuse + vec3<f32>(specular)) * radiance;
+    }
+
+    return vec4<f32>(color, texture_color.a);
+}
@@ -1,8 +1,37 @@
 use cgmath::prelude::*;
-use wgpu::{include_wgsl, util::DeviceExt, BufferUsages, Instance, ShaderStages};
-use winit::{event::WindowEvent, window::Window};
+use wgpu::{include_wgsl, util::DeviceExt, ShaderStages};
+use winit::{event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent}, keyboard::{KeyCode, ModifiersState, PhysicalKey}, window::Window};
 
-use crate::{brush::{self, Brush}, camera::{Camera, CameraController, CameraUniform}, instance::{self, InstanceRaw}, model::{DrawModel, Model}, resources, texture, vertex::{ModelVertex, Vertex, INDICES, VERTICES}};
+use crate::{autosave::{self, Autosave}, brush::{self, Brush, BrushKind, BrushSettings}, camera::{Camera, CameraController, CameraUniform, Projection}, bvh::{Aabb, Bvh}, deform::{self, Dab}, dyntopo::{self, DyntopoSettings}, history::{DeltaRecorder, Edit, History, TopologyRecorder}, multires::Multires, instance::{self, InstanceRaw}, light::Lighting, project::{self, CameraView, Project}, matcap::MatcapLibrary, model::{DrawModel, Model}, picking::{self, Hit, Ray}, remesh::RemeshJob, resources::{self, ModelFormat}, stroke::{Stroke, StrokeSample}, cli::Args, texture, vertex::{ModelVertex, Vertex}};
+
+// Number keys picking each of `BrushKind::ALL`
+const BRUSH_KEYS: [KeyCode; 8] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+];
+
+// A stroke in progress, locked to the mesh and instance it started on
+pub struct SculptStroke {
+    pub stroke: Stroke,
+    pub mesh: usize,
+    pub instance: usize,
+    pub kind: BrushKind,
+    pub invert: bool,
+    // Grab keeps dragging the vertices it picked up on press, and moves on a plane facing the camera
+    pub grab_vertices: Vec<usize>,
+    pub grab_plane: (cgmath::Point3<f32>, cgmath::Vector3<f32>),
+    pub recorder: DeltaRecorder,
+    // Set when dyntopo remeshes under the brush, then it records the stroke instead of `recorder`
+    pub topology: Option<TopologyRecorder>,
+    // Welding a uv seam has been warned about
+    pub welded_uvs: bool,
+}
 
 pub struct State<'a> {
     pub surface: wgpu::Surface<'a>,
@@ -14,7 +43,15 @@ pub struct State<'a> {
 
     pub render_pipeline: wgpu::RenderPipeline,
 
+    // Kept for opening other models, which only native builds do
+    #[cfg(not(target_arch = "wasm32"))]
+    pub texture_bind_group_layout: wgpu::BindGroupLayout,
     pub obj_model: Model,
+    // Where obj_model came from, exports go next to it
+    pub model_path: String,
+    // Open OBJ files with their quads, see `Args::keep_quads`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub keep_quads: bool,
 
     pub instances: Vec<instance::Instance>,
     pub instance_buffer: wgpu::Buffer,
@@ -27,7 +64,27 @@ pub struct State<'a> {
     pub camera_buffer: wgpu::Buffer,
     pub camera_bind_group: wgpu::BindGroup,
 
+    pub lighting: Lighting,
+    pub matcaps: MatcapLibrary,
+
     pub brush: brush::Brush,
+    pub dyntopo: DyntopoSettings,
+    // Spacing of the voxel remesh, in model units
+    pub voxel_size: f32,
+    pub remesh_job: Option<RemeshJob>,
+    // A dropped or typed in file, opened on the next update rather than inside the event handler
+    #[cfg(not(target_arch = "wasm32"))]
+    pub pending_open: Option<String>,
+    // The path Ctrl+O is waiting for on the terminal
+    #[cfg(not(target_arch = "wasm32"))]
+    pub open_prompt: Option<std::sync::mpsc::Receiver<String>>,
+    pub cursor: [f32; 2],
+    pub modifiers: ModifiersState,
+    pub sculpt_stroke: Option<SculptStroke>,
+    pub history: History,
+    // Every instance of the model, kept for the camera's clip planes
+    pub scene_bounds: Aabb,
+    pub autosave: Autosave,
 
     pub window: &'a Window
 }
@@ -36,7 +93,7 @@ const NUM_INSTANCES_PER_ROW: u32 = 10;
 
 impl<'a> State<'a> {
     
-    pub async fn new(window: &'a Window) -> State<'a> {
+    pub async fn new(window: &'a Window, args: &Args) -> anyhow::Result<State<'a>> {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -100,6 +157,23 @@ impl<'a> State<'a> {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None
                     },
+                    // Normal map
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture { 
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true }, 
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false
+                        },
+                        count: None
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None
+                    },
                 ],
                 label: Some("texture_bind_group_layout")
             }
@@ -119,51 +193,57 @@ impl<'a> State<'a> {
 
         let shader = device.create_shader_module(include_wgsl!("shader.wgsl"));
 
-        let obj_model = resources::load_model("cube.obj", &device, &queue, &texture_bind_group_layout).await.unwrap();
-        
-        const SPACE_BETWEEN: f32 = 3.0;
-        let instances = (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
-            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                let position = cgmath::Vector3::new(x as f32, 0.0, z as f32);
-                let rotation = if position.is_zero() {
-                    // https://sotrh.github.io/learn-wgpu/beginner/tutorial7-instancing/#the-instance-buffer 
-                    // this is needed so an object at (0, 0, 0) won't get scaled to zero
-                    // as Quaternions can affect scale if they're not created correctly
-                    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-                } else {
-                    cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-                };
-
-                instance::Instance {
-                    position,
-                    rotation,
-                }
-            })
-        }).collect::<Vec<_>>();
-
-        let instance_data = instances.iter().map(instance::Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX,
+        // A broken autosave isn't worth failing over, the model opens instead
+        let recovered = if args.restore {
+            match autosave::read_recovery() {
+                Ok(Some(project)) => Some(project),
+                Ok(None) => {
+                    log::warn!("Nothing to restore, no session was autosaved");
+                    None
+                },
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    None
+                },
             }
-        );
+        } else {
+            if let Some(location) = autosave::recovery_location() {
+                log::warn!("The last session didn't close cleanly, it was autosaved to {}, start with --restore (?restore on the web) to reopen it", location);
+            }
+            None
+        };
+        let model_path = recovered.as_ref()
+            .and_then(|project| project.source.clone())
+            .unwrap_or_else(|| args.model.clone());
+        let (obj_model, saved_view, saved_brush) = match recovered {
+            Some(project) => resources::project_model(project, &device, &queue, &texture_bind_group_layout)?,
+            None => Self::load(&model_path, args.keep_quads, &device, &queue, &texture_bind_group_layout).await?,
+        };
+        
+        let instances = Self::model_instances(&obj_model);
+        let instance_buffer = Self::create_instance_buffer(&device, &instances);
 
         let depth_texture: texture::Texture = texture::Texture::create_depth_texture(&device, &config, Some("depth_texture"));
 
-        let camera = Camera {
+        let mut camera = Camera {
             eye: (0.0, 1.0, 2.0).into(),
             target: (0.0, 0.0, 0.0).into(),
             up: cgmath::Vector3::unit_y(),
             aspect: config.width as f32 / config.height as f32,
             fovy: 45.0,
             znear: 0.1,
-            zfar: 100.0
+            zfar: 100.0,
+            projection: Projection::Perspective,
         };
 
+        let scene_bounds = Self::compute_scene_bounds(&obj_model, &instances);
+        let voxel_size = Self::default_voxel_size(&obj_model);
+        match saved_view {
+            Some(view) => view.apply(&mut camera),
+            None => camera.frame(&obj_model.bounds()),
+        }
+        camera.fit_clip_planes(&scene_bounds);
+
         let camera_controller = CameraController::new(0.2);
 
         let mut camera_uniform = CameraUniform::new();
@@ -183,7 +263,7 @@ impl<'a> State<'a> {
                 entries: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: ShaderStages::VERTEX,
+                        visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None },
                         count: None,
                     }
@@ -204,11 +284,16 @@ impl<'a> State<'a> {
             }
         );
 
+        let lighting = Lighting::new(&device, &camera);
+        let matcaps = MatcapLibrary::load(&device, &queue).await?;
+
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Render Pipeline Layout"),
             bind_group_layouts: &[
                 &texture_bind_group_layout,
                 &camera_bind_group_layout,
+                &lighting.bind_group_layout,
+                &matcaps.bind_group_layout,
             ],
             push_constant_ranges: &[]
         });
@@ -260,9 +345,12 @@ impl<'a> State<'a> {
             cache: None,
         });
 
-        let brush = Brush::new(&device, &config);
+        let mut brush = Brush::new(&device, &config);
+        if let Some(settings) = &saved_brush {
+            brush.apply_settings(settings);
+        }
 
-        Self {
+        Ok(Self {
             window,
             surface,
             device,
@@ -270,7 +358,12 @@ impl<'a> State<'a> {
             config,
             render_pipeline,
 
+            #[cfg(not(target_arch = "wasm32"))]
+            texture_bind_group_layout,
             obj_model,
+            model_path,
+            #[cfg(not(target_arch = "wasm32"))]
+            keep_quads: args.keep_quads,
 
             instances,
             instance_buffer,
@@ -283,16 +376,32 @@ impl<'a> State<'a> {
             camera_buffer,
             camera_bind_group,
 
+            lighting,
+            matcaps,
+
             brush,
+            dyntopo: DyntopoSettings::default(),
+            voxel_size,
+            remesh_job: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            pending_open: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            open_prompt: None,
+            cursor: [0.0, 0.0],
+            modifiers: ModifiersState::empty(),
+            sculpt_stroke: None,
+            history: History::new(args.undo_memory),
+            scene_bounds,
+            autosave: Autosave::new(autosave::INTERVAL),
 
             clear_color: wgpu::Color { r: 0.1, g: 0.2, b: 0.3, a: 1.0 },
             size
-        }
+        })
 
     }
 
     pub fn window(&self) -> &Window {
-        &self.window
+        self.window
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -307,26 +416,870 @@ impl<'a> State<'a> {
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
-        // match event {
-        //     WindowEvent::CursorMoved { device_id: _, position } => {
-        //         self.clear_color = wgpu::Color {
-        //             r: position.x / self.config.width as f64,
-        //             g: position.y / self.config.height as f64,
-        //             b: 0.0,
-        //             a: 1.0
-        //         }
-        //     },
-        //     _ => return false
-        // }
-        self.camera_controller.handle_event(event)
+        if let WindowEvent::ModifiersChanged(modifiers) = event {
+            self.modifiers = modifiers.state();
+        }
+        // Navigation wins, so tumbling with Alt + left drag never starts a stroke
+        if self.camera_controller.handle_event(event) {
+            return true;
+        }
+
+        match event {
+            WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                match state {
+                    ElementState::Pressed => self.begin_stroke(),
+                    ElementState::Released => self.end_stroke(),
+                }
+                true
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                self.move_cursor([position.x as f32, position.y as f32]);
+                true
+            },
+            // Dropping a model file on the window opens it
+            #[cfg(not(target_arch = "wasm32"))]
+            WindowEvent::DroppedFile(path) => {
+                self.pending_open = Some(path.to_string_lossy().into_owned());
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyZ),
+                    ..
+                },
+                ..
+            } if self.modifiers.control_key() => {
+                if self.modifiers.shift_key() {
+                    self.redo();
+                } else {
+                    self.undo();
+                }
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyC),
+                    ..
+                },
+                ..
+            } if !self.modifiers.control_key() => {
+                self.lighting.toggle_texture();
+                true
+            },
+            // Ctrl+S saves the model as is, with Shift the instances are saved too, baked into the mesh
+            // for formats without any notion of them
+            #[cfg(not(target_arch = "wasm32"))]
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyS),
+                    ..
+                },
+                ..
+            } if self.modifiers.control_key() => {
+                let path = self.export_path();
+                match self.save_model(&path, self.modifiers.shift_key()) {
+                    Ok(()) => {
+                        log::info!("Saved {}", path.display());
+                        self.autosave.discard();
+                    },
+                    Err(e) => log::error!("{:#}", e),
+                }
+                true
+            },
+            // Ctrl+O asks for the path of a model to open on the terminal, the window keeps running meanwhile
+            #[cfg(not(target_arch = "wasm32"))]
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyO),
+                    ..
+                },
+                ..
+            } if self.modifiers.control_key() => {
+                self.prompt_open();
+                true
+            },
+            // Ctrl+P saves the whole session, camera and brush included, as a project
+            #[cfg(not(target_arch = "wasm32"))]
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyP),
+                    ..
+                },
+                ..
+            } if self.modifiers.control_key() => {
+                let path = self.project_path();
+                match self.save_model(&path, false) {
+                    Ok(()) => {
+                        log::info!("Saved {}", path.display());
+                        self.autosave.discard();
+                    },
+                    Err(e) => log::error!("{:#}", e),
+                }
+                true
+            },
+            // Ctrl+D turns dynamic topology on and off, with Shift it switches between
+            // detail relative to the brush and detail relative to the screen
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyD),
+                    ..
+                },
+                ..
+            } if self.modifiers.control_key() => {
+                if self.modifiers.shift_key() {
+                    self.dyntopo.toggle_detail();
+                    log::info!("Dyntopo detail: {:?}", self.dyntopo.detail);
+                } else {
+                    self.dyntopo.enabled = !self.dyntopo.enabled;
+                    log::info!("Dyntopo {}", if self.dyntopo.enabled { "on" } else { "off" });
+                    if self.dyntopo.enabled && self.obj_model.meshes.iter().any(|mesh| mesh.multires.is_some()) {
+                        log::warn!("Dyntopo stays off on meshes with multires levels");
+                    }
+                }
+                true
+            },
+            // 1 to 8 pick the brush: draw, clay, inflate, smooth, flatten, pinch, crease and grab.
+            // Holding Shift still smooths and Ctrl still inverts whichever is picked
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(key),
+                    ..
+                },
+                ..
+            } if BRUSH_KEYS.contains(key) && !self.modifiers.control_key() => {
+                let index = BRUSH_KEYS.iter().position(|digit| digit == key).unwrap_or(0);
+                self.brush.kind = BrushKind::ALL[index];
+                log::info!("Brush: {:?}", self.brush.kind);
+                true
+            },
+            // - and = weaken and strengthen the brush, K cycles its falloff from soft to hard
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(key @ (KeyCode::Minus | KeyCode::Equal)),
+                    ..
+                },
+                ..
+            } => {
+                let step = if *key == KeyCode::Equal { 0.05 } else { -0.05 };
+                self.brush.update_strength(self.brush.strength + step);
+                log::info!("Brush strength: {:.2}", self.brush.strength);
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyK),
+                    ..
+                },
+                ..
+            } => {
+                self.brush.update_falloff(self.brush.falloff.next_preset());
+                log::info!("Brush falloff: {:?}", self.brush.falloff);
+                true
+            },
+            // R voxel remeshes the mesh under the cursor, [ and ] make the voxels smaller and larger
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyR),
+                    ..
+                },
+                ..
+            } if !self.modifiers.control_key() => {
+                self.start_remesh();
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(key @ (KeyCode::BracketLeft | KeyCode::BracketRight)),
+                    ..
+                },
+                ..
+            } => {
+                self.voxel_size *= if *key == KeyCode::BracketRight { 1.25 } else { 0.8 };
+                log::info!("Voxel size: {}", self.voxel_size);
+                true
+            },
+            // PageUp sculpts one multires level finer, subdividing when already at the finest, PageDown one coarser
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(key @ (KeyCode::PageUp | KeyCode::PageDown)),
+                    ..
+                },
+                ..
+            } => {
+                self.change_level(*key == KeyCode::PageUp);
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyM),
+                    ..
+                },
+                ..
+            } => {
+                self.lighting.toggle_matcap();
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyN),
+                    ..
+                },
+                ..
+            } => {
+                self.matcaps.next();
+                log::info!("Matcap: {}", self.matcaps.current().name);
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::Home),
+                    ..
+                },
+                ..
+            } => {
+                self.camera.frame(&self.obj_model.bounds());
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyF),
+                    ..
+                },
+                ..
+            } => {
+                if let Some(hit) = self.pick(self.cursor) {
+                    self.camera.focus(hit.point);
+                }
+                true
+            },
+            WindowEvent::KeyboardInput {
+                event: KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(KeyCode::KeyY),
+                    ..
+                },
+                ..
+            } if self.modifiers.control_key() => {
+                self.redo();
+                true
+            },
+            // Ctrl + wheel, the plain wheel dollies the camera
+            WindowEvent::MouseWheel { delta, .. } => {
+                let is_positive = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y >= 0.0,
+                    MouseScrollDelta::PixelDelta(physical_position) => physical_position.y >= 0.0,
+                };
+                if is_positive {
+                    self.brush.update_radius(self.brush.radius * 1.1);
+                } else {
+                    self.brush.update_radius(self.brush.radius / 1.1);
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn compute_scene_bounds(model: &Model, instances: &[instance::Instance]) -> Aabb {
+        let bounds = model.bounds();
+        instances.iter().fold(Aabb::empty(), |scene, instance| scene.union(&bounds.transform(&instance.model_matrix())))
+    }
+
+    pub fn screen_size(&self) -> [f32; 2] {
+        [self.config.width as f32, self.config.height as f32]
+    }
+
+    pub fn pick(&self, cursor: [f32; 2]) -> Option<Hit> {
+        let ray = Ray::from_screen(&self.camera, cursor, self.screen_size())?;
+        picking::pick(&ray, &self.obj_model, &self.instances)
+    }
+
+    pub fn move_cursor(&mut self, cursor: [f32; 2]) {
+        self.cursor = cursor;
+        let hit = self.pick(cursor);
+        if let Some(hit) = hit {
+            self.brush.update_position(hit.point, hit.normal);
+        }
+
+        let sample = match &self.sculpt_stroke {
+            Some(stroke) if stroke.kind == BrushKind::Grab => self.grab_sample(stroke, cursor),
+            Some(stroke) => hit
+                .filter(|hit| hit.mesh == stroke.mesh && hit.instance == stroke.instance)
+                .map(|hit| StrokeSample { position: hit.point, normal: hit.normal }),
+            None => None,
+        };
+        if let Some(sample) = sample {
+            self.continue_stroke(sample);
+        }
+    }
+
+    // Ctrl inverts the brush and Shift smooths for as long as it is held, like most sculpting apps
+    pub fn begin_stroke(&mut self) {
+        // The remesh would throw the stroke away when it lands
+        if self.remesh_job.is_some() {
+            return;
+        }
+        let hit = match self.pick(self.cursor) {
+            Some(hit) => hit,
+            None => return,
+        };
+
+        let kind = if self.modifiers.shift_key() { BrushKind::Smooth } else { self.brush.kind };
+        let view = (self.camera.target - self.camera.eye).normalize();
+
+        let mut grab_vertices = Vec::new();
+        if kind == BrushKind::Grab {
+            let mesh = &self.obj_model.meshes[hit.mesh];
+            let local = self.instances[hit.instance].model_matrix().invert().unwrap_or(cgmath::Matrix4::identity());
+            let center = cgmath::Point3::from_homogeneous(local * hit.point.to_homogeneous());
+            grab_vertices = mesh.bvh.vertices_in_sphere(&mesh.sculpt, center, self.brush.radius);
+        }
+
+        // Grab drags a fixed set of vertices, remeshing under it would pull them apart,
+        // and multires levels have to keep the topology they were subdivided with
+        let mut topology = None;
+        if self.dyntopo.enabled && kind != BrushKind::Grab && self.obj_model.meshes[hit.mesh].multires.is_none() {
+            topology = Some(TopologyRecorder::new(hit.mesh, &self.obj_model.meshes[hit.mesh].sculpt));
+        }
+
+        self.sculpt_stroke = Some(SculptStroke {
+            stroke: Stroke::new(self.brush.stroke_settings, self.brush.radius),
+            mesh: hit.mesh,
+            instance: hit.instance,
+            kind,
+            invert: self.modifiers.control_key(),
+            grab_vertices,
+            grab_plane: (hit.point, view),
+            recorder: DeltaRecorder::new(hit.mesh),
+            topology,
+            welded_uvs: false,
+        });
+        self.continue_stroke(StrokeSample { position: hit.point, normal: hit.normal });
+    }
+
+    fn grab_sample(&self, stroke: &SculptStroke, cursor: [f32; 2]) -> Option<StrokeSample> {
+        let ray = Ray::from_screen(&self.camera, cursor, self.screen_size())?;
+        let (origin, normal) = stroke.grab_plane;
+        let denom = ray.direction.dot(normal);
+        if denom.abs() < 1e-6 {
+            return None;
+        }
+        let t = (origin - ray.origin).dot(normal) / denom;
+        Some(StrokeSample { position: ray.at(t), normal: -normal })
+    }
+
+    fn continue_stroke(&mut self, sample: StrokeSample) {
+        let stroke = match &mut self.sculpt_stroke {
+            Some(stroke) => stroke,
+            None => return,
+        };
+
+        let previous = stroke.stroke.last_dab();
+        let dabs = stroke.stroke.add_sample(sample);
+
+        // Dabs arrive in world space, the mesh is deformed in the instance's local space
+        let model = self.instances[stroke.instance].model_matrix();
+        let local = model.invert().unwrap_or(cgmath::Matrix4::identity());
+        let mesh = &mut self.obj_model.meshes[stroke.mesh];
+
+        let mut last = previous;
+        for world in dabs {
+            let center = cgmath::Point3::from_homogeneous(local * world.position.to_homogeneous());
+            let normal = (model.transpose() * world.normal.extend(0.0)).truncate().normalize();
+
+            let mut dab = Dab {
+                kind: stroke.kind,
+                center,
+                normal,
+                radius: self.brush.radius,
+                strength: self.brush.strength,
+                falloff: &self.brush.falloff,
+                invert: stroke.invert,
+                grab_delta: cgmath::Vector3::zero(),
+            };
+
+            let vertices = if stroke.kind == BrushKind::Grab {
+                // Grab moves relative to where the previous dab left the surface
+                let previous = match last {
+                    Some(previous) => previous,
+                    None => {
+                        last = Some(world);
+                        continue;
+                    }
+                };
+                dab.center = cgmath::Point3::from_homogeneous(local * previous.position.to_homogeneous());
+                dab.grab_delta = (local * (world.position - previous.position).extend(0.0)).truncate();
+                stroke.grab_vertices.clone()
+            } else {
+                mesh.bvh.vertices_in_sphere(&mesh.sculpt, center, dab.radius)
+            };
+
+            let vertices = match &mut stroke.topology {
+                Some(topology) => {
+                    let weld = dyntopo::weld_seams(&mut mesh.sculpt, &vertices, topology);
+                    if weld.uvs_lost && !stroke.welded_uvs {
+                        log::warn!("Dynamic topology welded a uv seam under the brush, the texture smears across it there");
+                        stroke.welded_uvs = true;
+                    }
+                    let vertices = if weld.changed {
+                        mesh.bvh = Bvh::build(&mesh.sculpt);
+                        mesh.sync_topology(&self.device, &self.queue);
+                        mesh.bvh.vertices_in_sphere(&mesh.sculpt, center, dab.radius)
+                    } else {
+                        vertices
+                    };
+
+                    // Remeshing works in the instance's local space, like the dab
+                    let pixel_size = self.camera.pixel_size(world.position, self.size.height as f32);
+                    let pixel_size = (local * cgmath::Vector4::new(pixel_size, 0.0, 0.0, 0.0)).truncate().magnitude();
+                    let edge_length = self.dyntopo.edge_length(dab.radius, pixel_size);
+                    let vertices = if dyntopo::remesh(&mut mesh.sculpt, &vertices, center, dab.radius, edge_length, topology) {
+                        mesh.bvh = Bvh::build(&mesh.sculpt);
+                        mesh.sync_topology(&self.device, &self.queue);
+                        mesh.bvh.vertices_in_sphere(&mesh.sculpt, center, dab.radius)
+                    } else {
+                        vertices
+                    };
+                    // The dab moves the one-ring's normals too, and restoring a region doesn't recompute them
+                    let mut ring = vertices.clone();
+                    for &v in &vertices {
+                        ring.extend_from_slice(&mesh.sculpt.vertex_neighbors[v]);
+                    }
+                    topology.record_vertices(&mesh.sculpt, &ring);
+                    vertices
+                },
+                None => {
+                    stroke.recorder.record(&mesh.sculpt, &vertices);
+                    vertices
+                },
+            };
+            deform::apply_dab(&mut mesh.sculpt, &vertices, &dab);
+            mesh.sculpt.recompute_normals_region(&vertices);
+            mesh.bvh.refit_vertices(&mesh.sculpt, &vertices);
+            last = Some(world);
+        }
+    }
+
+    pub fn end_stroke(&mut self) {
+        let stroke = match self.sculpt_stroke.take() {
+            Some(stroke) => stroke,
+            None => return,
+        };
+        let edit = match stroke.topology {
+            Some(topology) => topology.finish(&self.obj_model.meshes[stroke.mesh].sculpt),
+            None => stroke.recorder.finish(),
+        };
+        if let Some(edit) = edit {
+            self.history.push(edit);
+            self.autosave.mark_changed();
+        }
+        self.scene_bounds = Self::compute_scene_bounds(&self.obj_model, &self.instances);
+    }
+
+    // Files that place their own instances get them, everything else is laid out on a grid
+    fn model_instances(model: &Model) -> Vec<instance::Instance> {
+        if !model.instances.is_empty() {
+            return model.instances.clone();
+        }
+
+        const SPACE_BETWEEN: f32 = 3.0;
+        (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
+            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
+                let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+                let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+                let position = cgmath::Vector3::new(x, 0.0, z);
+                let rotation = if position.is_zero() {
+                    // https://sotrh.github.io/learn-wgpu/beginner/tutorial7-instancing/#the-instance-buffer 
+                    // this is needed so an object at (0, 0, 0) won't get scaled to zero
+                    // as Quaternions can affect scale if they're not created correctly
+                    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
+                } else {
+                    cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
+                };
+
+                instance::Instance {
+                    position,
+                    rotation,
+                    scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+                }
+            })
+        }).collect()
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, instances: &[instance::Instance]) -> wgpu::Buffer {
+        let instance_data = instances.iter().map(instance::Instance::to_raw).collect::<Vec<_>>();
+        device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Instance Buffer"),
+                contents: bytemuck::cast_slice(&instance_data),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        )
+    }
+
+    // Projects also bring back the camera and brush they were saved with
+    async fn load(
+        path: &str,
+        keep_quads: bool,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> anyhow::Result<(Model, Option<CameraView>, Option<BrushSettings>)> {
+        match ModelFormat::from_path(path) {
+            ModelFormat::Project => resources::load_project(path, device, queue, layout).await,
+            _ => Ok((resources::load_model(path, keep_quads, device, queue, layout).await?, None, None)),
+        }
+    }
+
+    /// Replaces the model with the one at `path`, the current one stays if it fails to load.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn open_model(&mut self, path: &str) -> anyhow::Result<()> {
+        let (model, saved_view, saved_brush) = Self::load(path, self.keep_quads, &self.device, &self.queue, &self.texture_bind_group_layout).await?;
+
+        // Strokes, history and remeshing refer to meshes of the old model
+        self.sculpt_stroke = None;
+        self.remesh_job = None;
+        self.history.clear();
+        self.voxel_size = Self::default_voxel_size(&model);
+        self.instances = Self::model_instances(&model);
+        self.instance_buffer = Self::create_instance_buffer(&self.device, &self.instances);
+        self.obj_model = model;
+        self.model_path = path.to_string();
+        self.scene_bounds = Self::compute_scene_bounds(&self.obj_model, &self.instances);
+        match saved_view {
+            Some(view) => view.apply(&mut self.camera),
+            None => self.camera.frame(&self.obj_model.bounds()),
+        }
+        if let Some(settings) = &saved_brush {
+            self.brush.apply_settings(settings);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn prompt_open(&mut self) {
+        if self.open_prompt.is_some() {
+            log::info!("Still waiting for the path of the model to open");
+            return;
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reader = std::thread::Builder::new().name("open prompt".to_string()).spawn(move || {
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line).is_ok() {
+                let _ = sender.send(line.trim().to_string());
+            }
+        });
+        match reader {
+            Ok(_) => {
+                log::info!("Type the path of the model to open and press Enter");
+                self.open_prompt = Some(receiver);
+            },
+            Err(e) => log::error!("Failed to ask for a path: {}", e),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn open_pending(&mut self) {
+        use std::sync::mpsc::TryRecvError;
+
+        if let Some(prompt) = &self.open_prompt {
+            match prompt.try_recv() {
+                Ok(path) => {
+                    self.open_prompt = None;
+                    if !path.is_empty() {
+                        self.pending_open = Some(path);
+                    }
+                },
+                Err(TryRecvError::Disconnected) => self.open_prompt = None,
+                Err(TryRecvError::Empty) => {},
+            }
+        }
+        if let Some(path) = self.pending_open.take() {
+            if let Err(e) = pollster::block_on(self.open_model(&path)) {
+                log::error!("{:#}", e);
+            }
+        }
+    }
+
+    /// A copy of the session as it is now, for saving as a project.
+    pub fn project(&self) -> Project {
+        let model = &self.obj_model;
+        Project {
+            meshes: model.meshes.iter().map(|mesh| project::ProjectMesh {
+                name: mesh.name.clone(),
+                material: mesh.material,
+                mesh: mesh.sculpt.clone(),
+                multires: mesh.multires.as_ref().map(|multires| multires.snapshot(&mesh.sculpt)),
+            }).collect(),
+            // Generated textures, like a tinted glTF base color, have no source and come back plain
+            materials: model.materials.iter().map(|material| project::ProjectMaterial {
+                name: material.name.clone(),
+                diffuse: material.diffuse_source.clone(),
+                normal: material.normal_source.clone(),
+            }).collect(),
+            camera: Some(CameraView::of(&self.camera)),
+            brush: Some(self.brush.settings()),
+            instances: self.instances.clone(),
+            source: Some(self.model_path.clone()),
+        }
+    }
+
+    // model.obj is saved as model.wsculpt next to it, a project saves over itself
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn project_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.model_path).with_extension(project::EXTENSION)
+    }
+
+    // model.obj is saved as model_sculpt.obj in the same folder and format, so its material libraries still resolve
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn export_path(&self) -> std::path::PathBuf {
+        let path = std::path::Path::new(&self.model_path);
+        let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or("model".into());
+        let extension = match ModelFormat::from_path(&self.model_path) {
+            ModelFormat::Obj => "obj",
+            ModelFormat::Stl => "stl",
+            ModelFormat::Ply => "ply",
+            ModelFormat::Gltf => "glb",
+            ModelFormat::Project => project::EXTENSION,
+        };
+        path.with_file_name(format!("{}_sculpt.{}", stem, extension))
+    }
+
+    /// Saves the model in the format the path's extension names.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_model(&self, path: &std::path::Path, bake_instances: bool) -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        use crate::{gltf_io, obj, ply, sculpt_mesh::SculptMesh, stl};
+
+        let model = &self.obj_model;
+        let transforms = if bake_instances {
+            self.instances.iter().map(instance::Instance::model_matrix).collect()
+        } else {
+            Vec::new()
+        };
+
+        let file = std::fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = std::io::BufWriter::new(file);
+        let written: anyhow::Result<()> = match ModelFormat::from_path(&path.to_string_lossy()) {
+            ModelFormat::Obj => {
+                let groups = model.meshes.iter().map(|mesh| obj::ObjGroup {
+                    name: &mesh.name,
+                    mesh: &mesh.sculpt,
+                    // The loader's default material isn't in any library, so there's nothing to refer to
+                    material: Some(&model.materials[mesh.material]).filter(|m| m.in_mtllib).map(|m| m.name.as_str()),
+                }).collect::<Vec<_>>();
+                obj::write_obj(&mut writer, &groups, &transforms, &model.mtllib).map_err(Into::into)
+            },
+            // Instances stay instances, as nodes sharing the mesh
+            ModelFormat::Gltf => {
+                let meshes = model.meshes.iter()
+                    .map(|mesh| gltf_io::ExportMesh { mesh: &mesh.sculpt, material: mesh.material })
+                    .collect::<Vec<_>>();
+                let materials = model.materials.iter().map(|material| gltf_io::ExportMaterial {
+                    name: &material.name,
+                    base_color_factor: material.base_color_factor,
+                    base_color_texture: material.diffuse_source.as_ref(),
+                    normal_texture: material.normal_source.as_ref(),
+                    metallic_roughness_texture: material.metallic_roughness_source.as_ref(),
+                }).collect::<Vec<_>>();
+                let instances = if bake_instances { self.instances.as_slice() } else { &[] };
+                gltf_io::write_glb(&mut writer, &meshes, &materials, instances)
+            },
+            ModelFormat::Project => project::write(&mut writer, &self.project()).map_err(Into::into),
+            // Single mesh formats, so everything is merged into one
+            format => {
+                let mut merged = SculptMesh::new(Vec::new(), Vec::new(), Vec::new(), Vec::new());
+                for mesh in &model.meshes {
+                    if transforms.is_empty() {
+                        merged.append(&mesh.sculpt);
+                    }
+                    for transform in &transforms {
+                        merged.append(&mesh.sculpt.transformed(transform));
+                    }
+                }
+                match format {
+                    ModelFormat::Stl => stl::write_binary(&mut writer, &merged),
+                    _ => ply::write(&mut writer, &merged, true),
+                }.map_err(Into::into)
+            },
+        };
+        written
+            .and_then(|()| Ok(std::io::Write::flush(&mut writer)?))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    // A hundred voxels across the model
+    fn default_voxel_size(model: &Model) -> f32 {
+        let size = model.bounds().extent().magnitude() / 100.0;
+        if size.is_finite() && size > 0.0 { size } else { 0.01 }
+    }
+
+    /// Starts voxel remeshing the mesh under the cursor in the background, `update` swaps it in when done.
+    pub fn start_remesh(&mut self) {
+        if self.sculpt_stroke.is_some() || self.remesh_job.is_some() {
+            return;
+        }
+        let hit = match self.pick(self.cursor) {
+            Some(hit) => hit,
+            None => {
+                log::warn!("Point at the mesh to remesh");
+                return;
+            }
+        };
+        let sculpt = self.obj_model.meshes[hit.mesh].sculpt.clone();
+        log::info!("Remeshing {} with voxel size {}", self.obj_model.meshes[hit.mesh].name, self.voxel_size);
+        match RemeshJob::start(hit.mesh, sculpt, self.voxel_size) {
+            Ok(job) => self.remesh_job = Some(job),
+            Err(e) => log::error!("{:#}", e),
+        }
+    }
+
+    fn finish_remesh(&mut self, job: RemeshJob) {
+        let index = job.mesh;
+        let sculpt = match job.finish() {
+            Ok(sculpt) => sculpt,
+            Err(e) => {
+                log::error!("Remeshing failed: {:#}", e);
+                return;
+            }
+        };
+        log::info!("Remeshed to {} vertices", sculpt.num_vertices());
+        let mesh = &mut self.obj_model.meshes[index];
+        self.history.push(Edit::replace(index, &mesh.sculpt, &sculpt));
+        mesh.replace_sculpt(&self.device, sculpt);
+        // Levels can't follow a change of topology
+        if mesh.multires.take().is_some() {
+            log::info!("Remeshing removed the multires levels of {}", mesh.name);
+        }
+        self.autosave.mark_changed();
+        self.scene_bounds = Self::compute_scene_bounds(&self.obj_model, &self.instances);
+    }
+
+    /// Moves every mesh to its next finer or coarser multires level, starting the levels on first use.
+    pub fn change_level(&mut self, finer: bool) {
+        if self.sculpt_stroke.is_some() || self.remesh_job.is_some() {
+            return;
+        }
+        let mut level = None;
+        for mesh in &mut self.obj_model.meshes {
+            let sculpt = match &mut mesh.multires {
+                Some(multires) if finer => multires.finer(&mesh.sculpt),
+                Some(multires) => multires.coarser(&mesh.sculpt),
+                None if finer => {
+                    let (mut multires, base) = Multires::from_sculpt(&mesh.sculpt);
+                    let sculpt = multires.finer(&base);
+                    mesh.multires = Some(multires);
+                    sculpt
+                },
+                None => None,
+            };
+            if let Some(sculpt) = sculpt {
+                mesh.replace_sculpt(&self.device, sculpt);
+                level = mesh.multires.as_ref().map(|multires| (multires.current(), multires.num_levels()));
+            }
+        }
+
+        if let Some((current, levels)) = level {
+            // Edits refer to vertices of the level they were made on
+            self.history.clear();
+            self.autosave.mark_changed();
+            self.scene_bounds = Self::compute_scene_bounds(&self.obj_model, &self.instances);
+            log::info!("Multires level {} of {}", current, levels - 1);
+        }
+    }
+
+    pub fn undo(&mut self) {
+        if self.sculpt_stroke.is_some() || self.remesh_job.is_some() {
+            return;
+        }
+        if let Some(edit) = self.history.undo() {
+            let mesh = &mut self.obj_model.meshes[edit.mesh];
+            edit.undo(&mut mesh.sculpt);
+            Self::after_history_edit(&self.device, mesh, edit);
+            self.autosave.mark_changed();
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if self.sculpt_stroke.is_some() || self.remesh_job.is_some() {
+            return;
+        }
+        if let Some(edit) = self.history.redo() {
+            let mesh = &mut self.obj_model.meshes[edit.mesh];
+            edit.redo(&mut mesh.sculpt);
+            Self::after_history_edit(&self.device, mesh, edit);
+            self.autosave.mark_changed();
+        }
+    }
+
+    fn after_history_edit(device: &wgpu::Device, mesh: &mut crate::model::Mesh, edit: &Edit) {
+        if edit.changes_topology() {
+            mesh.bvh = Bvh::build(&mesh.sculpt);
+            mesh.reupload(device);
+        } else {
+            // The delta only holds the stroke's own vertices, their neighbors' normals moved with them
+            mesh.sculpt.recompute_normals_region(edit.vertices());
+            mesh.bvh.refit_vertices(&mesh.sculpt, edit.vertices());
+        }
     }
 
     pub fn update(&mut self) {
        self.camera_controller.update_camera(&mut self.camera);
+       self.camera.fit_clip_planes(&self.scene_bounds);
        self.camera_uniform.update_view_proj(&self.camera);
        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+       self.lighting.update(&self.queue, &self.camera);
 
+       self.brush.update_overlay(&self.camera, self.cursor, self.screen_size());
        self.queue.write_buffer(&self.brush.buffer, 0, bytemuck::cast_slice(&[self.brush.uniform]));
+
+       // Only native windows get dropped files, and reading one there never has to wait
+       #[cfg(not(target_arch = "wasm32"))]
+       self.open_pending();
+
+       for mesh in &mut self.obj_model.meshes {
+           mesh.sync(&self.queue);
+       }
+
+       if let Some(job) = &mut self.remesh_job {
+           if let Some(progress) = job.report() {
+               log::info!("Remeshing {}%", progress);
+           }
+           if job.is_finished() {
+               if let Some(job) = self.remesh_job.take() {
+                   self.finish_remesh(job);
+               }
+           }
+       }
+
+       // Never mid stroke, the snapshot would hold half of it with no history to undo it by
+       if self.sculpt_stroke.is_none() && self.autosave.is_due() {
+           let project = self.project();
+           self.autosave.save(project);
+       }
+    }
+
+    /// Closing cleanly leaves nothing to recover, so the next launch doesn't offer it.
+    pub fn close(&mut self) {
+        self.end_stroke();
+        self.autosave.discard();
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -365,6 +1318,8 @@ impl<'a> State<'a> {
 
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+            render_pass.set_bind_group(2, &self.lighting.bind_group, &[]);
+            render_pass.set_bind_group(3, &self.matcaps.current().bind_group, &[]);
             render_pass.draw_model_instanced(&self.obj_model, 0..self.instances.len() as u32, &self.camera_bind_group);
             
         }
@@ -0,0 +1,207 @@
+use std::{collections::HashMap, io::{self, Write}};
+
+use anyhow::{bail, Context};
+use cgmath::{InnerSpace, Point3, Vector3, Zero};
+
+use crate::sculpt_mesh::SculptMesh;
+
+const HEADER_SIZE: usize = 80;
+const TRIANGLE_SIZE: usize = 50;
+
+/// Reads a binary or ASCII STL. STL stores every triangle with its own corners,
+/// so corners at the same position are welded back into shared vertices and smooth normals recomputed.
+pub fn read(bytes: &[u8]) -> anyhow::Result<SculptMesh> {
+    let triangles = if is_binary(bytes) {
+        read_binary(bytes)?
+    } else {
+        read_ascii(std::str::from_utf8(bytes).context("STL is neither binary nor ASCII")?)?
+    };
+    if triangles.is_empty() {
+        bail!("STL has no triangles");
+    }
+
+    let (positions, indices) = weld(&triangles);
+    let count = positions.len();
+    let mut mesh = SculptMesh::new(positions, vec![Vector3::zero(); count], vec![[0.0, 0.0]; count], indices);
+    mesh.recompute_normals();
+    mesh.take_dirty();
+    Ok(mesh)
+}
+
+// ASCII files start with "solid", but so do plenty of binary headers, so trust the size when it adds up
+fn is_binary(bytes: &[u8]) -> bool {
+    if bytes.len() >= HEADER_SIZE + 4 {
+        let count = u32::from_le_bytes(bytes[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap()) as usize;
+        if count.checked_mul(TRIANGLE_SIZE) == Some(bytes.len() - HEADER_SIZE - 4) {
+            return true;
+        }
+    }
+    !bytes.trim_ascii_start().starts_with(b"solid")
+}
+
+fn read_binary(bytes: &[u8]) -> anyhow::Result<Vec<[Point3<f32>; 3]>> {
+    if bytes.len() < HEADER_SIZE + 4 {
+        bail!("binary STL is truncated");
+    }
+    let count = u32::from_le_bytes(bytes[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap()) as usize;
+    let data = &bytes[HEADER_SIZE + 4..];
+    let size = match count.checked_mul(TRIANGLE_SIZE) {
+        Some(size) if size <= data.len() => size,
+        _ => bail!("binary STL declares {} triangles but only holds {}", count, data.len() / TRIANGLE_SIZE),
+    };
+
+    Ok(data[..size].chunks_exact(TRIANGLE_SIZE).map(|triangle| {
+        let float = |offset: usize| f32::from_le_bytes(triangle[offset..offset + 4].try_into().unwrap());
+        // Skips the 12 byte facet normal, and the 2 attribute bytes after the corners
+        [0, 1, 2].map(|c| {
+            let offset = 12 + c * 12;
+            Point3::new(float(offset), float(offset + 4), float(offset + 8))
+        })
+    }).collect())
+}
+
+fn read_ascii(text: &str) -> anyhow::Result<Vec<[Point3<f32>; 3]>> {
+    let mut triangles = Vec::new();
+    let mut corners = Vec::with_capacity(3);
+
+    for (line_number, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("vertex") => {
+                let coordinates = words.take(3).map(str::parse::<f32>).collect::<Result<Vec<_>, _>>();
+                match coordinates {
+                    Ok(c) if c.len() == 3 => corners.push(Point3::new(c[0], c[1], c[2])),
+                    _ => bail!("bad vertex on line {}", line_number + 1),
+                }
+            }
+            Some("endloop") => {
+                // Facets with more than three corners aren't valid STL, but fanning them costs nothing
+                for i in 1..corners.len().saturating_sub(1) {
+                    triangles.push([corners[0], corners[i], corners[i + 1]]);
+                }
+                corners.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+// Merges corners with exactly the same coordinates, which is how STL writers emit shared vertices
+fn weld(triangles: &[[Point3<f32>; 3]]) -> (Vec<Point3<f32>>, Vec<u32>) {
+    // Adding zero turns -0.0 into 0.0 so both land on the same key
+    let key = |p: Point3<f32>| [(p.x + 0.0).to_bits(), (p.y + 0.0).to_bits(), (p.z + 0.0).to_bits()];
+
+    let mut positions = Vec::new();
+    let mut indices = Vec::with_capacity(triangles.len() * 3);
+    let mut lookup = HashMap::new();
+    for triangle in triangles {
+        for &corner in triangle {
+            let index = *lookup.entry(key(corner)).or_insert_with(|| {
+                positions.push(corner);
+                positions.len() as u32 - 1
+            });
+            indices.push(index);
+        }
+    }
+    (positions, indices)
+}
+
+fn facet_normal(mesh: &SculptMesh, face: usize) -> Vector3<f32> {
+    let [a, b, c] = mesh.face(face).map(|v| mesh.positions[v]);
+    let n = (b - a).cross(c - a);
+    if n.magnitude2() > 0.0 { n.normalize() } else { n }
+}
+
+pub fn write_binary<W: Write>(writer: &mut W, mesh: &SculptMesh) -> io::Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    let name = b"web_sculpt";
+    header[..name.len()].copy_from_slice(name);
+    writer.write_all(&header)?;
+    writer.write_all(&(mesh.num_faces() as u32).to_le_bytes())?;
+
+    for face in 0..mesh.num_faces() {
+        let normal = facet_normal(mesh, face);
+        let corners = mesh.face(face).map(|v| mesh.positions[v]);
+        for value in [normal.x, normal.y, normal.z] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for corner in corners {
+            for value in [corner.x, corner.y, corner.z] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.write_all(&[0, 0])?;
+    }
+    Ok(())
+}
+
+pub fn write_ascii<W: Write>(writer: &mut W, mesh: &SculptMesh, name: &str) -> io::Result<()> {
+    writeln!(writer, "solid {}", name)?;
+    for face in 0..mesh.num_faces() {
+        let n = facet_normal(mesh, face);
+        writeln!(writer, "  facet normal {} {} {}", n.x, n.y, n.z)?;
+        writeln!(writer, "    outer loop")?;
+        for v in mesh.face(face) {
+            let p = mesh.positions[v];
+            writeln!(writer, "      vertex {} {} {}", p.x, p.y, p.z)?;
+        }
+        writeln!(writer, "    endloop")?;
+        writeln!(writer, "  endfacet")?;
+    }
+    writeln!(writer, "endsolid {}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources;
+
+    // Faces come back in order with their corners in order, on vertices welded by position
+    fn assert_same_triangles(before: &SculptMesh, after: &SculptMesh) {
+        assert_eq!(after.num_faces(), before.num_faces());
+        for face in 0..before.num_faces() {
+            assert_eq!(after.face(face).map(|v| after.positions[v]), before.face(face).map(|v| before.positions[v]));
+        }
+        let mut unique = before.positions.iter().map(|p| [p.x, p.y, p.z].map(f32::to_bits)).collect::<Vec<_>>();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(after.num_vertices(), unique.len());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mesh = resources::test_mesh("cube.obj");
+        let mut written = Vec::new();
+        write_binary(&mut written, &mesh).unwrap();
+        assert!(is_binary(&written));
+        assert_same_triangles(&mesh, &read(&written).unwrap());
+    }
+
+    #[test]
+    fn ascii_round_trip() {
+        let mesh = resources::test_mesh("cube.obj");
+        let mut written = Vec::new();
+        write_ascii(&mut written, &mesh, "cube").unwrap();
+        assert!(!is_binary(&written));
+        assert_same_triangles(&mesh, &read(&written).unwrap());
+    }
+
+    #[test]
+    fn binary_header_starting_with_solid_is_still_binary() {
+        let mesh = resources::test_mesh("cube.obj");
+        let mut written = Vec::new();
+        write_binary(&mut written, &mesh).unwrap();
+        written[..5].copy_from_slice(b"solid");
+        assert_same_triangles(&mesh, &read(&written).unwrap());
+    }
+
+    #[test]
+    fn impossible_triangle_counts_are_rejected() {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; TRIANGLE_SIZE]);
+        assert!(read_binary(&bytes).is_err());
+        assert!(read(&bytes).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use cgmath::{InnerSpace, Point3, Vector3};
+
+// Keeps a tiny brush from emitting thousands of dabs per sample
+const MIN_SPACING: f32 = 1e-4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StrokeSample {
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StrokeSettings {
+    // Distance between dabs as a percentage of the brush radius
+    pub spacing: f32,
+    // Length of the lazy mouse string as a fraction of the brush radius, 0 turns it off
+    pub lazy_radius: f32,
+}
+
+impl Default for StrokeSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 10.0,
+            lazy_radius: 0.0,
+        }
+    }
+}
+
+/// Turns irregular pointer samples into evenly spaced dabs.
+/// Everything only depends on the samples fed in, so a stroke can be replayed exactly.
+pub struct Stroke {
+    pub settings: StrokeSettings,
+    pub radius: f32,
+    samples: Vec<StrokeSample>,
+    // Where the lazy mouse string is, the point that actually paints
+    smoothed: Option<StrokeSample>,
+    last_dab: Option<StrokeSample>,
+}
+
+impl Stroke {
+    pub fn new(settings: StrokeSettings, radius: f32) -> Self {
+        Self {
+            settings,
+            radius,
+            samples: Vec::new(),
+            smoothed: None,
+            last_dab: None,
+        }
+    }
+
+    pub fn samples(&self) -> &[StrokeSample] {
+        &self.samples
+    }
+
+    pub fn last_dab(&self) -> Option<StrokeSample> {
+        self.last_dab
+    }
+
+    fn spacing(&self) -> f32 {
+        (self.settings.spacing / 100.0 * self.radius).max(MIN_SPACING)
+    }
+
+    // Drags the smoothed point behind the sample on a string of fixed length
+    fn lazy_follow(&self, sample: StrokeSample) -> StrokeSample {
+        let smoothed = match self.smoothed {
+            Some(smoothed) => smoothed,
+            None => return sample,
+        };
+        let string = self.settings.lazy_radius * self.radius;
+        if string <= 0.0 {
+            return sample;
+        }
+
+        let offset = sample.position - smoothed.position;
+        let distance = offset.magnitude();
+        if distance <= string {
+            return smoothed;
+        }
+        let f = (distance - string) / distance;
+        StrokeSample {
+            position: smoothed.position + offset * f,
+            normal: lerp_normal(smoothed.normal, sample.normal, f),
+        }
+    }
+
+    /// Feeds a pointer sample and returns the dabs it produced, possibly none.
+    /// The first sample of a stroke always dabs where the stroke starts.
+    pub fn add_sample(&mut self, sample: StrokeSample) -> Vec<StrokeSample> {
+        self.samples.push(sample);
+        let target = self.lazy_follow(sample);
+        self.smoothed = Some(target);
+
+        let mut last = match self.last_dab {
+            Some(last) => last,
+            None => {
+                self.last_dab = Some(target);
+                return vec![target];
+            }
+        };
+
+        let spacing = self.spacing();
+        let mut dabs = Vec::new();
+        loop {
+            let offset = target.position - last.position;
+            let distance = offset.magnitude();
+            if distance < spacing {
+                break;
+            }
+            let f = spacing / distance;
+            last = StrokeSample {
+                position: last.position + offset * f,
+                normal: lerp_normal(last.normal, target.normal, f),
+            };
+            dabs.push(last);
+        }
+
+        self.last_dab = Some(last);
+        dabs
+    }
+
+    pub fn replay(settings: StrokeSettings, radius: f32, samples: &[StrokeSample]) -> Vec<StrokeSample> {
+        let mut stroke = Stroke::new(settings, radius);
+        samples.iter().flat_map(|&sample| stroke.add_sample(sample)).collect()
+    }
+}
+
+fn lerp_normal(a: Vector3<f32>, b: Vector3<f32>, f: f32) -> Vector3<f32> {
+    let n = a + (b - a) * f;
+    if n.magnitude2() > 1e-12 { n.normalize() } else { b }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(x: f32) -> StrokeSample {
+        StrokeSample { position: Point3::new(x, 0.0, 0.0), normal: Vector3::unit_y() }
+    }
+
+    fn positions(dabs: &[StrokeSample]) -> Vec<f32> {
+        dabs.iter().map(|dab| dab.position.x).collect()
+    }
+
+    #[test]
+    fn dabs_are_evenly_spaced_along_a_drag() {
+        // 10% of a 0.5 radius, so a dab every 0.05
+        let settings = StrokeSettings { spacing: 10.0, lazy_radius: 0.0 };
+        let dabs = Stroke::replay(settings, 0.5, &[sample(0.0), sample(0.013), sample(0.4), sample(0.41), sample(1.01)]);
+
+        // The start, then 20 whole spacings in 1.01
+        assert_eq!(dabs.len(), 21);
+        for pair in dabs.windows(2) {
+            assert!((pair[1].position.x - pair[0].position.x - 0.05).abs() < 1e-5);
+        }
+
+        // However the pointer samples arrive, the same dabs come out
+        let coarse = Stroke::replay(settings, 0.5, &[sample(0.0), sample(1.01)]);
+        for (a, b) in positions(&dabs).into_iter().zip(positions(&coarse)) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        assert_eq!(coarse.len(), dabs.len());
+    }
+
+    #[test]
+    fn spacing_scales_with_the_radius() {
+        let settings = StrokeSettings { spacing: 25.0, lazy_radius: 0.0 };
+        let small = Stroke::replay(settings, 0.2, &[sample(0.0), sample(1.01)]);
+        let large = Stroke::replay(settings, 0.4, &[sample(0.0), sample(1.01)]);
+        assert_eq!(small.len(), 21);
+        assert_eq!(large.len(), 11);
+    }
+
+    #[test]
+    fn replay_matches_the_live_stroke() {
+        let settings = StrokeSettings { spacing: 7.0, lazy_radius: 0.3 };
+        let samples = [0.0, 0.02, 0.31, 0.33, 0.8, 0.79, 1.2].map(sample);
+        let mut stroke = Stroke::new(settings, 0.5);
+        let live = samples.iter().flat_map(|&s| stroke.add_sample(s)).collect::<Vec<_>>();
+        assert_eq!(Stroke::replay(settings, 0.5, stroke.samples()), live);
+        assert_eq!(Stroke::replay(settings, 0.5, &samples), live);
+    }
+
+    #[test]
+    fn lazy_mouse_lags_behind_the_pointer() {
+        // A string a quarter of a unit long
+        let settings = StrokeSettings { spacing: 10.0, lazy_radius: 0.5 };
+        let mut stroke = Stroke::new(settings, 0.5);
+        assert_eq!(stroke.add_sample(sample(0.0)).len(), 1);
+
+        // Moving less than the string only pulls it taut
+        assert!(stroke.add_sample(sample(0.2)).is_empty());
+        assert!(stroke.add_sample(sample(0.25)).is_empty());
+
+        // Further on the dabs trail a string's length behind
+        let dabs = stroke.add_sample(sample(1.0));
+        let last = dabs.last().unwrap().position.x;
+        assert!(last <= 0.75 + 1e-5 && last > 0.75 - 0.05);
+
+        // Without the lazy mouse the same drag dabs all the way to the pointer
+        let direct = Stroke::replay(StrokeSettings { lazy_radius: 0.0, ..settings }, 0.5, &[0.0, 0.2, 0.25, 1.0].map(sample));
+        assert!(direct.last().unwrap().position.x > 0.95);
+    }
+}
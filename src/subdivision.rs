@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+
+use cgmath::{EuclideanSpace, InnerSpace, Matrix3, Point3, Vector3, Zero};
+
+use crate::sculpt_mesh::SculptMesh;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scheme {
+    // Any polygons in, quads out
+    CatmullClark,
+    // Triangles in, triangles out
+    Loop,
+}
+
+impl Scheme {
+    /// Loop for meshes that are all triangles, Catmull-Clark for anything with quads or larger polygons.
+    pub fn for_mesh(mesh: &PolyMesh) -> Self {
+        if mesh.is_triangles() { Scheme::Loop } else { Scheme::CatmullClark }
+    }
+}
+
+/// A polygon mesh with vertices shared across uv seams, which is what subdivision needs to stay smooth
+/// across them. `SculptMesh` splits vertices at seams and only has triangles, so it's converted both ways.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolyMesh {
+    pub positions: Vec<Point3<f32>>,
+    // RGBA per vertex, empty when there are none
+    pub colors: Vec<[f32; 4]>,
+    // Corners of every face back to back, face f spans face_starts[f]..face_starts[f + 1]
+    pub corners: Vec<usize>,
+    // Texture coordinates per corner, so faces on either side of a seam can disagree
+    pub corner_uvs: Vec<[f32; 2]>,
+    pub face_starts: Vec<usize>,
+}
+
+// Edges with the faces on them, and which edge each corner starts
+struct Edges {
+    ends: Vec<[usize; 2]>,
+    faces: Vec<Vec<usize>>,
+    corner_edges: Vec<usize>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Edges {
+    fn new(mesh: &PolyMesh) -> Self {
+        let mut lookup = HashMap::new();
+        let mut edges = Self {
+            ends: Vec::new(),
+            faces: Vec::new(),
+            corner_edges: Vec::with_capacity(mesh.corners.len()),
+            vertex_edges: vec![Vec::new(); mesh.num_vertices()],
+            vertex_faces: vec![Vec::new(); mesh.num_vertices()],
+        };
+        for f in 0..mesh.num_faces() {
+            let face = mesh.face(f);
+            for (i, &a) in face.iter().enumerate() {
+                let b = face[(i + 1) % face.len()];
+                let e = *lookup.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    edges.ends.push([a, b]);
+                    edges.faces.push(Vec::new());
+                    edges.vertex_edges[a].push(edges.ends.len() - 1);
+                    edges.vertex_edges[b].push(edges.ends.len() - 1);
+                    edges.ends.len() - 1
+                });
+                edges.faces[e].push(f);
+                edges.corner_edges.push(e);
+                edges.vertex_faces[a].push(f);
+            }
+        }
+        edges
+    }
+
+    fn other(&self, e: usize, v: usize) -> usize {
+        let [a, b] = self.ends[e];
+        if a == v { b } else { a }
+    }
+
+    // Edges with one face, or more than two, which the schemes keep sharp
+    fn is_boundary(&self, e: usize) -> bool {
+        self.faces[e].len() != 2
+    }
+
+    // The ends of the boundary edges through `v`, when it sits on a simple boundary curve
+    fn boundary_neighbors(&self, v: usize) -> Option<(usize, usize)> {
+        let mut boundary = self.vertex_edges[v].iter().filter(|&&e| self.is_boundary(e));
+        match (boundary.next(), boundary.next(), boundary.next()) {
+            (Some(&a), Some(&b), None) => Some((self.other(a, v), self.other(b, v))),
+            _ => None,
+        }
+    }
+}
+
+fn average<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Point3<f32> {
+    let (sum, count) = points.into_iter().fold((Vector3::zero(), 0), |(sum, count), p| (sum + p.to_vec(), count + 1));
+    Point3::from_vec(sum / count.max(1) as f32)
+}
+
+fn average_uv<'a, I: IntoIterator<Item = &'a [f32; 2]>>(uvs: I) -> [f32; 2] {
+    let (sum, count) = uvs.into_iter().fold(([0.0, 0.0], 0), |(sum, count), uv| ([sum[0] + uv[0], sum[1] + uv[1]], count + 1));
+    let count = count.max(1) as f32;
+    [sum[0] / count, sum[1] / count]
+}
+
+fn average_color<I: IntoIterator<Item = [f32; 4]>>(colors: I) -> [f32; 4] {
+    let (sum, count) = colors.into_iter().fold(([0.0; 4], 0), |(sum, count), c| ([0, 1, 2, 3].map(|i| sum[i] + c[i]), count + 1));
+    let count = count.max(1) as f32;
+    sum.map(|c| c / count)
+}
+
+impl PolyMesh {
+    /// Builds a polygon mesh from split vertices, welding those at the same position.
+    /// `arities` holds the corner count of every face, empty meaning all triangles.
+    /// Faces with fewer than three corners are dropped.
+    pub fn from_indexed(
+        positions: &[Point3<f32>],
+        uvs: &[[f32; 2]],
+        colors: &[[f32; 4]],
+        indices: &[u32],
+        arities: &[u32],
+    ) -> Self {
+        // Adding zero turns -0.0 into 0.0 so both weld
+        let key = |p: Point3<f32>| [(p.x + 0.0).to_bits(), (p.y + 0.0).to_bits(), (p.z + 0.0).to_bits()];
+        let mut welded = HashMap::new();
+        let mut mesh = Self {
+            positions: Vec::new(),
+            colors: Vec::new(),
+            corners: Vec::with_capacity(indices.len()),
+            corner_uvs: Vec::with_capacity(indices.len()),
+            face_starts: vec![0],
+        };
+        let remap = positions.iter().enumerate().map(|(i, &p)| {
+            *welded.entry(key(p)).or_insert_with(|| {
+                mesh.positions.push(p);
+                if !colors.is_empty() {
+                    mesh.colors.push(colors[i]);
+                }
+                mesh.positions.len() - 1
+            })
+        }).collect::<Vec<_>>();
+
+        let triangles = vec![3; indices.len() / 3];
+        let arities = if arities.is_empty() { &triangles[..] } else { arities };
+        let mut start = 0;
+        for &arity in arities {
+            let end = start + arity as usize;
+            if end > indices.len() {
+                break;
+            }
+            let face = &indices[start..end];
+            if face.len() >= 3 {
+                mesh.corners.extend(face.iter().map(|&i| remap[i as usize]));
+                mesh.corner_uvs.extend(face.iter().map(|&i| uvs.get(i as usize).copied().unwrap_or([0.0, 0.0])));
+                mesh.face_starts.push(mesh.corners.len());
+            }
+            start = end;
+        }
+        mesh
+    }
+
+    pub fn from_sculpt(mesh: &SculptMesh) -> Self {
+        Self::from_indexed(&mesh.positions, &mesh.uvs, &mesh.colors, &mesh.indices, &[])
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn num_faces(&self) -> usize {
+        self.face_starts.len() - 1
+    }
+
+    pub fn face(&self, f: usize) -> &[usize] {
+        &self.corners[self.face_starts[f]..self.face_starts[f + 1]]
+    }
+
+    pub fn face_uvs(&self, f: usize) -> &[[f32; 2]] {
+        &self.corner_uvs[self.face_starts[f]..self.face_starts[f + 1]]
+    }
+
+    pub fn is_triangles(&self) -> bool {
+        (0..self.num_faces()).all(|f| self.face(f).len() == 3)
+    }
+
+    pub fn has_colors(&self) -> bool {
+        !self.colors.is_empty()
+    }
+
+    /// Splits vertices again where uvs differ, fans polygons into triangles and recomputes the normals.
+    /// Also returns which of this mesh's vertices every vertex of the result came from.
+    pub fn to_sculpt(&self) -> (SculptMesh, Vec<usize>) {
+        let mut split = HashMap::new();
+        let mut source = Vec::new();
+        let mut uvs = Vec::new();
+        let mut face_vertices = Vec::with_capacity(self.corners.len());
+        for (&v, uv) in self.corners.iter().zip(&self.corner_uvs) {
+            let vertex = *split.entry((v, uv[0].to_bits(), uv[1].to_bits())).or_insert_with(|| {
+                source.push(v);
+                uvs.push(*uv);
+                source.len() - 1
+            });
+            face_vertices.push(vertex as u32);
+        }
+
+        let mut indices = Vec::new();
+        for f in 0..self.num_faces() {
+            let face = &face_vertices[self.face_starts[f]..self.face_starts[f + 1]];
+            for i in 1..face.len() - 1 {
+                indices.extend([face[0], face[i], face[i + 1]]);
+            }
+        }
+
+        let positions = source.iter().map(|&v| self.positions[v]).collect::<Vec<_>>();
+        let normals = vec![Vector3::zero(); positions.len()];
+        let mut sculpt = SculptMesh::new(positions, normals, uvs, indices);
+        if self.has_colors() {
+            sculpt.colors = source.iter().map(|&v| self.colors[v]).collect();
+        }
+        sculpt.recompute_normals();
+        (sculpt, source)
+    }
+
+    /// An orthonormal frame per vertex with the normal in the last column. The tangent points along
+    /// the vertex's first edge, so it follows the surface through sculpting without any uvs.
+    pub fn frames(&self) -> Vec<Matrix3<f32>> {
+        let mut normals = vec![Vector3::zero(); self.num_vertices()];
+        let mut towards = vec![None; self.num_vertices()];
+        for f in 0..self.num_faces() {
+            let face = self.face(f);
+            // Newell's method, which also holds up for polygons that aren't flat
+            let normal = face.iter().enumerate().fold(Vector3::zero(), |n, (i, &a)| {
+                let (p, q) = (self.positions[a], self.positions[face[(i + 1) % face.len()]]);
+                n + Vector3::new((p.y - q.y) * (p.z + q.z), (p.z - q.z) * (p.x + q.x), (p.x - q.x) * (p.y + q.y))
+            });
+            for (i, &v) in face.iter().enumerate() {
+                normals[v] += normal;
+                towards[v].get_or_insert(face[(i + 1) % face.len()]);
+            }
+        }
+
+        normals.into_iter().zip(towards).enumerate().map(|(v, (normal, towards))| {
+            let normal = if normal.magnitude2() > 1e-20 { normal.normalize() } else { Vector3::unit_z() };
+            let edge = towards.map_or(Vector3::zero(), |t: usize| self.positions[t] - self.positions[v]);
+            let tangent = edge - normal * normal.dot(edge);
+            let tangent = if tangent.magnitude2() > 1e-20 {
+                tangent.normalize()
+            } else {
+                let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+                (axis - normal * normal.dot(axis)).normalize()
+            };
+            Matrix3::from_cols(tangent, normal.cross(tangent), normal)
+        }).collect()
+    }
+
+    /// One level of subdivision. The vertices of this mesh keep their indices in the result,
+    /// followed by one vertex per edge and, for Catmull-Clark, one per face.
+    /// Loop only works on triangles, anything else gets Catmull-Clark.
+    pub fn subdivide(&self, scheme: Scheme) -> Self {
+        match scheme {
+            Scheme::Loop if self.is_triangles() => self.loop_subdivide(),
+            _ => self.catmull_clark(),
+        }
+    }
+
+    fn catmull_clark(&self) -> Self {
+        let edges = Edges::new(self);
+        let (vertices, edge_count) = (self.num_vertices(), edges.ends.len());
+
+        let face_points = (0..self.num_faces())
+            .map(|f| average(self.face(f).iter().map(|&v| self.positions[v])))
+            .collect::<Vec<_>>();
+        let midpoint = |e: usize| {
+            let [a, b] = edges.ends[e];
+            self.positions[a].midpoint(self.positions[b])
+        };
+
+        let mut positions = Vec::with_capacity(vertices + edge_count + self.num_faces());
+        for v in 0..vertices {
+            let p = self.positions[v];
+            let (vertex_edges, vertex_faces) = (&edges.vertex_edges[v], &edges.vertex_faces[v]);
+            let interior = !vertex_edges.is_empty()
+                && vertex_edges.iter().all(|&e| !edges.is_boundary(e))
+                && vertex_edges.len() == vertex_faces.len();
+            positions.push(if interior {
+                let n = vertex_edges.len() as f32;
+                let q = average(vertex_faces.iter().map(|&f| face_points[f]));
+                let r = average(vertex_edges.iter().map(|&e| midpoint(e)));
+                Point3::from_vec((q.to_vec() + r.to_vec() * 2.0 + p.to_vec() * (n - 3.0)) / n)
+            } else if let Some((a, b)) = edges.boundary_neighbors(v) {
+                Point3::from_vec(p.to_vec() * 0.75 + (self.positions[a].to_vec() + self.positions[b].to_vec()) * 0.125)
+            } else {
+                // Corners and non-manifold vertices stay put
+                p
+            });
+        }
+        for e in 0..edge_count {
+            positions.push(match edges.faces[e][..] {
+                [f, g] => {
+                    let [a, b] = edges.ends[e];
+                    average([self.positions[a], self.positions[b], face_points[f], face_points[g]])
+                },
+                _ => midpoint(e),
+            });
+        }
+        positions.extend_from_slice(&face_points);
+
+        let colors = if self.has_colors() {
+            let mut colors = self.colors.clone();
+            colors.extend(edges.ends.iter().map(|&[a, b]| average_color([self.colors[a], self.colors[b]])));
+            colors.extend((0..self.num_faces()).map(|f| average_color(self.face(f).iter().map(|&v| self.colors[v]))));
+            colors
+        } else {
+            Vec::new()
+        };
+
+        let mut mesh = Self {
+            positions,
+            colors,
+            corners: Vec::with_capacity(self.corners.len() * 4),
+            corner_uvs: Vec::with_capacity(self.corners.len() * 4),
+            face_starts: vec![0],
+        };
+        for f in 0..self.num_faces() {
+            let (start, face, uvs) = (self.face_starts[f], self.face(f), self.face_uvs(f));
+            let k = face.len();
+            let center_uv = average_uv(uvs);
+            for i in 0..k {
+                let previous = (i + k - 1) % k;
+                let (next_edge, previous_edge) = (edges.corner_edges[start + i], edges.corner_edges[start + previous]);
+                // Same winding as the face it came from
+                mesh.corners.extend([face[i], vertices + next_edge, vertices + edge_count + f, vertices + previous_edge]);
+                mesh.corner_uvs.extend([
+                    uvs[i],
+                    average_uv([&uvs[i], &uvs[(i + 1) % k]]),
+                    center_uv,
+                    average_uv([&uvs[previous], &uvs[i]]),
+                ]);
+                mesh.face_starts.push(mesh.corners.len());
+            }
+        }
+        mesh
+    }
+
+    fn loop_subdivide(&self) -> Self {
+        let edges = Edges::new(self);
+        let (vertices, edge_count) = (self.num_vertices(), edges.ends.len());
+        let opposite = |f: usize, e: usize| {
+            let [a, b] = edges.ends[e];
+            self.face(f).iter().copied().find(|&c| c != a && c != b).unwrap_or(a)
+        };
+
+        let mut positions = Vec::with_capacity(vertices + edge_count);
+        for v in 0..vertices {
+            let p = self.positions[v];
+            let vertex_edges = &edges.vertex_edges[v];
+            let interior = !vertex_edges.is_empty() && vertex_edges.iter().all(|&e| !edges.is_boundary(e));
+            positions.push(if interior {
+                let n = vertex_edges.len();
+                // Warren's weights, simpler than Loop's original ones and just as smooth
+                let beta = if n == 3 { 3.0 / 16.0 } else { 3.0 / (8.0 * n as f32) };
+                let sum = vertex_edges.iter().fold(Vector3::zero(), |sum, &e| sum + self.positions[edges.other(e, v)].to_vec());
+                Point3::from_vec(p.to_vec() * (1.0 - n as f32 * beta) + sum * beta)
+            } else if let Some((a, b)) = edges.boundary_neighbors(v) {
+                Point3::from_vec(p.to_vec() * 0.75 + (self.positions[a].to_vec() + self.positions[b].to_vec()) * 0.125)
+            } else {
+                p
+            });
+        }
+        for e in 0..edge_count {
+            let [a, b] = edges.ends[e];
+            let (pa, pb) = (self.positions[a].to_vec(), self.positions[b].to_vec());
+            positions.push(match edges.faces[e][..] {
+                [f, g] => {
+                    let (c, d) = (self.positions[opposite(f, e)].to_vec(), self.positions[opposite(g, e)].to_vec());
+                    Point3::from_vec((pa + pb) * 0.375 + (c + d) * 0.125)
+                },
+                _ => Point3::from_vec((pa + pb) * 0.5),
+            });
+        }
+
+        let colors = if self.has_colors() {
+            let mut colors = self.colors.clone();
+            colors.extend(edges.ends.iter().map(|&[a, b]| average_color([self.colors[a], self.colors[b]])));
+            colors
+        } else {
+            Vec::new()
+        };
+
+        let mut mesh = Self {
+            positions,
+            colors,
+            corners: Vec::with_capacity(self.corners.len() * 4),
+            corner_uvs: Vec::with_capacity(self.corners.len() * 4),
+            face_starts: vec![0],
+        };
+        for f in 0..self.num_faces() {
+            let (start, face, uvs) = (self.face_starts[f], self.face(f), self.face_uvs(f));
+            let [e01, e12, e20] = [0, 1, 2].map(|i| vertices + edges.corner_edges[start + i]);
+            let [uv01, uv12, uv20] = [0, 1, 2].map(|i| average_uv([&uvs[i], &uvs[(i + 1) % 3]]));
+            for (corners, corner_uvs) in [
+                ([face[0], e01, e20], [uvs[0], uv01, uv20]),
+                ([face[1], e12, e01], [uvs[1], uv12, uv01]),
+                ([face[2], e20, e12], [uvs[2], uv20, uv12]),
+                ([e01, e12, e20], [uv01, uv12, uv20]),
+            ] {
+                mesh.corners.extend(corners);
+                mesh.corner_uvs.extend(corner_uvs);
+                mesh.face_starts.push(mesh.corners.len());
+            }
+        }
+        mesh
+    }
+}
@@ -1,6 +1,33 @@
 use image::GenericImageView;
 use anyhow::*;
 
+/// A texture as it was stored in its file, kept so exports can embed it again without re-encoding.
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    pub bytes: Vec<u8>,
+    pub mime_type: String,
+}
+
+impl EncodedImage {
+    /// Labels the bytes with the format their header says they're in.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        let mime_type = match image::guess_format(&bytes) {
+            Result::Ok(format) => format.to_mime_type(),
+            Err(_) => "application/octet-stream",
+        };
+        Self { bytes, mime_type: mime_type.to_string() }
+    }
+
+    /// The MIME type to embed the image in a glTF with, None unless it's a PNG or JPEG, the only formats glTF allows.
+    /// Sniffed again rather than trusting `mime_type`, which older projects saved as PNG for anything.
+    pub fn gltf_mime_type(&self) -> Option<&'static str> {
+        match image::guess_format(&self.bytes) {
+            Result::Ok(format @ (image::ImageFormat::Png | image::ImageFormat::Jpeg)) => Some(format.to_mime_type()),
+            _ => None,
+        }
+    }
+}
+
 pub struct Texture {
     #[allow(unused)]
     pub texture: wgpu::Texture,
@@ -60,18 +87,26 @@ impl Texture {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8], 
-        label: &str
+        label: &str,
+        is_normal_map: bool
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
 
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
-        label: Option<&str>
+        label: Option<&str>,
+        is_normal_map: bool
     ) -> Result<Self> {
+        // Normal maps store directions, not colors, so they must not be gamma decoded
+        let format = if is_normal_map {
+            wgpu::TextureFormat::Rgba8Unorm
+        } else {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        };
 
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
@@ -84,13 +119,13 @@ impl Texture {
 
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
-                size: size,
+                size,
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                format,
                 usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-                label: label,
+                label,
                 view_formats: &[]
             }
         );
@@ -7,7 +7,10 @@ pub trait Vertex {
 pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
-    pub normal: [f32; 3]
+    pub normal: [f32; 3],
+    // Texture space basis for the normal map
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
 }
 
 impl Vertex for ModelVertex {
@@ -30,23 +33,18 @@ impl Vertex for ModelVertex {
                     offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x3
                 }
             ]
         }
     }
 }
-
-pub const VERTICES: &[ModelVertex] = &[
-    ModelVertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.99240386], normal: [0.0, 0.0, 0.0] }, // A
-    ModelVertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.56958647],normal: [0.0, 0.0, 0.0] }, // A
-    ModelVertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords: [0.28081453, 0.05060294], normal: [0.0, 0.0, 0.0] }, // A
-    ModelVertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.1526709], normal: [0.0, 0.0, 0.0] }, // A
-    ModelVertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.7347359], normal: [0.0, 0.0, 0.0] }, // A
-];
- 
-
-pub const INDICES: &[u16] = &[
-    0, 1, 4,
-    1, 2, 4,
-    2, 3, 4,
-];
\ No newline at end of file